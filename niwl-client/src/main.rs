@@ -1,5 +1,11 @@
+use chrono::Duration;
 use clap::Clap;
-use niwl::Profile;
+use niwl::client::{NiwlClient, RetryPolicy};
+use niwl::storage::PASSPHRASE_ENV_VAR;
+use niwl::{NiwlError, Payload, Profile};
+
+/// How long to wait for the missing fragments of a message before giving up on it
+const FRAGMENT_TIMEOUT_HOURS: i64 = 24;
 
 #[derive(Clap)]
 #[clap(version = "1.0", author = "Sarah Jamie Lewis <sarah@openprivacy.ca>")]
@@ -10,6 +16,18 @@ struct Opts {
     #[clap(default_value = "http://localhost:8000")]
     niwl_server: String,
 
+    /// send requests to the server through this proxy e.g. "http://localhost:8118"
+    #[clap(long)]
+    proxy: Option<String>,
+
+    /// how many seconds to wait for each request to the server
+    #[clap(long, default_value = "30")]
+    timeout: u64,
+
+    /// how many times to retry requests that fail temporarily
+    #[clap(long, default_value = "3")]
+    retries: u32,
+
     #[clap(subcommand)]
     subcmd: SubCommand,
 }
@@ -18,9 +36,21 @@ struct Opts {
 enum SubCommand {
     Generate(Generate),
     ImportTaggingKey(ImportTaggingKey),
+    ListContacts(ListContacts),
+    ShowContact(ShowContact),
+    RenameContact(RenameContact),
+    RemoveContact(RemoveContact),
     TagAndSend(TagAndSend),
     TagAndMix(TagAndMix),
+    TagAndRoute(TagAndRoute),
+    Reply(Reply),
     Detect(Detect),
+    SetPassphrase(SetPassphrase),
+    RemovePassphrase(RemovePassphrase),
+    Backup(Backup),
+    Restore(Restore),
+    RotateKeys(RotateKeys),
+    VerifyContact(VerifyContact),
 }
 
 /// Generate a new niwl.profile file
@@ -35,11 +65,90 @@ struct Generate {
 #[derive(Clap)]
 struct ImportTaggingKey {
     key: String,
+    /// the name to know this friend by, instead of the name they chose for themselves
+    #[clap(long)]
+    petname: Option<String>,
+}
+
+/// List the friends you have imported
+#[derive(Clap)]
+struct ListContacts {}
+
+/// Show the keys you hold for a friend
+#[derive(Clap)]
+struct ShowContact {
+    /// the petname or key id of the friend e.g. "alice"
+    id: String,
+}
+
+/// Change the name you know a friend by
+#[derive(Clap)]
+struct RenameContact {
+    /// the petname or key id of the friend e.g. "alice"
+    id: String,
+    petname: String,
+}
+
+/// Forget a friend and their keys
+#[derive(Clap)]
+struct RemoveContact {
+    /// the petname or key id of the friend e.g. "alice"
+    id: String,
+}
+
+/// Encrypt this profile with a new passphrase, or change its passphrase
+#[derive(Clap)]
+struct SetPassphrase {}
+
+/// Store this profile unencrypted
+#[derive(Clap)]
+struct RemovePassphrase {}
+
+/// Show the seed phrase that recovers this profile
+#[derive(Clap)]
+struct Backup {
+    /// also export your contacts, encrypted under your seed, to this file
+    #[clap(long)]
+    contacts: Option<String>,
+}
+
+/// Recreate a profile from its seed phrase
+#[derive(Clap)]
+struct Restore {
+    name: String,
+    #[clap(default_value = "2")]
+    length: usize,
+    /// a contacts export created by `backup --contacts`
+    #[clap(long)]
+    contacts: Option<String>,
+}
+
+/// Replace the keys of this profile and tell every contact about the new keys
+#[derive(Clap)]
+struct RotateKeys {
+    /// how many days to keep checking for messages sent to the old keys
+    #[clap(long, default_value = "7")]
+    grace_days: i64,
+}
+
+/// Compare safety numbers with a friend to check that nobody has swapped their keys
+#[derive(Clap)]
+struct VerifyContact {
+    /// the id of the friend e.g. "alice"
+    id: String,
+    /// mark the friend as unverified again
+    #[clap(long)]
+    reset: bool,
 }
 
 /// Connect to a server and check for new notifications
 #[derive(Clap)]
-struct Detect {}
+struct Detect {
+    /// check every message the server still has, after the server has pruned messages we had
+    /// not yet synced
+    #[clap(long)]
+    reset_cursor: bool,
+}
 
 /// Send a message to a friend tagged with their niwl key
 #[derive(Clap)]
@@ -48,6 +157,16 @@ struct TagAndSend {
     id: String,
     /// the message you want to send.
     message: String,
+    /// authenticate the message with your identity key so your friend knows it came from you
+    #[clap(long)]
+    authenticated: bool,
+    /// encrypt the message within a forward secret session with your friend (implies
+    /// --authenticated)
+    #[clap(long)]
+    ratchet: bool,
+    /// include a single-use reply block routed back to you through these mixes, in order
+    #[clap(long)]
+    reply_via: Vec<String>,
 }
 
 /// Send a message to a friend tagged with their niwl key
@@ -59,22 +178,201 @@ struct TagAndMix {
     id: String,
     /// the message you want to send.
     message: String,
+    /// authenticate the message with your identity key so your friend knows it came from you
+    #[clap(long)]
+    authenticated: bool,
+    /// encrypt the message within a forward secret session with your friend (implies
+    /// --authenticated)
+    #[clap(long)]
+    ratchet: bool,
+    /// include a single-use reply block routed back to you through these mixes, in order
+    #[clap(long)]
+    reply_via: Vec<String>,
+}
+
+/// Send a message to a friend through a chain of mixes, each mix forwarding to the next
+#[derive(Clap)]
+struct TagAndRoute {
+    /// the id of the friend e.g. "alice"
+    id: String,
+    /// the message you want to send.
+    message: String,
+    /// the ids of the mixes to route the message through, in order
+    #[clap(required = true)]
+    route: Vec<String>,
+    /// authenticate the message with your identity key so your friend knows it came from you
+    #[clap(long)]
+    authenticated: bool,
+    /// encrypt the message within a forward secret session with your friend (implies
+    /// --authenticated)
+    #[clap(long)]
+    ratchet: bool,
+    /// include a single-use reply block routed back to you through these mixes, in order
+    #[clap(long)]
+    reply_via: Vec<String>,
+    /// send the message as a sphinx packet, which looks the same at every hop
+    #[clap(long)]
+    sphinx: bool,
+}
+
+/// Reply to a message using the reply block that came with it
+#[derive(Clap)]
+struct Reply {
+    /// the id of the reply block, shown when the message was received
+    id: String,
+    /// the message you want to send.
+    message: String,
+}
+
+/// Load the profile, asking for its passphrase if it is encrypted and the passphrase is not
+/// set in the environment
+fn load_profile(profile_filename: &String) -> Profile {
+    let passphrase = match Profile::is_encrypted(profile_filename) {
+        Ok(true) => match std::env::var(PASSPHRASE_ENV_VAR) {
+            Ok(passphrase) => Some(passphrase),
+            Err(_) => Some(
+                rpassword::read_password_from_tty(Some("Passphrase: "))
+                    .expect("couldn't read passphrase"),
+            ),
+        },
+        Ok(false) => None,
+        Err(err) => {
+            println!("[ERROR] {:?}", err);
+            std::process::exit(1);
+        }
+    };
+    match Profile::open(profile_filename, passphrase.as_ref()) {
+        Ok(profile) => profile,
+        Err(err) => {
+            println!("[ERROR] {:?}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Connect to the server given on the command line, exiting if the options are invalid
+fn connect(opts: &Opts) -> NiwlClient {
+    let mut builder = NiwlClient::builder(&opts.niwl_server)
+        .timeout(std::time::Duration::from_secs(opts.timeout))
+        .retry_policy(RetryPolicy {
+            max_retries: opts.retries,
+            ..Default::default()
+        });
+    if let Some(proxy) = &opts.proxy {
+        builder = builder.proxy(proxy);
+    }
+    match builder.build() {
+        Ok(client) => client,
+        Err(err) => {
+            println!("[ERROR] {:?}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Warn about routing through contacts that did not share their keys as a mix
+fn warn_unless_mixes(profile: &Profile, mixes: &[String]) {
+    for mix in mixes.iter().filter(|mix| !profile.is_mix(mix)) {
+        println!(
+            "[WARNING] {} is not known to be a mix, its key was not shared with the mix option",
+            mix
+        );
+    }
+}
+
+/// Build the payloads for a message, with a reply block if the sender asked for one and
+/// encrypted within our session with the contact if `ratchet` is set. Messages too large to send
+/// through `hops` mixes in a single packet are split into fragments.
+fn payloads(
+    profile: &mut Profile,
+    contact: &String,
+    message: &String,
+    reply_via: &[String],
+    ratchet: bool,
+    hops: usize,
+    sphinx: bool,
+) -> Result<Vec<Payload>, NiwlError> {
+    if !profile.is_verified(contact) {
+        println!(
+            "[WARNING] {} is not verified, compare safety numbers with `verify-contact {}`",
+            contact, contact
+        );
+    }
+    warn_unless_mixes(profile, reply_via);
+    let message = message.as_bytes().to_vec();
+    let mut payload = match reply_via.is_empty() {
+        true => Payload::Message(message),
+        false => Payload::Replyable(profile.create_surb(reply_via, contact)?, message),
+    };
+    if ratchet {
+        payload = profile.ratchet_encrypt(contact, &payload)?;
+    }
+    profile.fragment(payload, hops, sphinx)
+}
+
+/// Print a message we have received, keeping the reply block if it came with one and collecting
+/// fragments until the whole message has arrived
+fn show(profile: &mut Profile, kind: &str, sender: Option<String>, payload: Payload) {
+    let name = sender.clone().unwrap_or(String::from("unknown"));
+    match payload {
+        Payload::Message(message) => {
+            println!(
+                "{} from {}: {}",
+                kind,
+                name,
+                String::from_utf8_lossy(&message)
+            )
+        }
+        Payload::Replyable(surb, message) => {
+            let id = profile.store_reply_block(sender, surb);
+            println!(
+                "{} from {}: {} (reply with `reply {}`)",
+                kind,
+                name,
+                String::from_utf8_lossy(&message),
+                id
+            )
+        }
+        Payload::Fragment(fragment) => {
+            let (id, index, count) = (fragment.id(), fragment.index(), fragment.count());
+            match profile.reassemble(sender, fragment) {
+                Ok(Some((sender, payload))) => show(profile, kind, sender, payload),
+                Ok(None) => println!(
+                    "received fragment {} of {} of message {} from {}",
+                    index + 1,
+                    count,
+                    id,
+                    name
+                ),
+                Err(err) => println!("[ERROR] {:?}", err),
+            }
+        }
+        Payload::Ratchet(message) => match &sender {
+            Some(contact) => match profile.ratchet_decrypt(contact, &message) {
+                Ok(payload) => show(profile, kind, sender, payload),
+                Err(err) => println!("[ERROR] {:?}", err),
+            },
+            None => println!("[ERROR] received a session message from an unknown sender"),
+        },
+        Payload::KeyRotation(keyset) => match &sender {
+            Some(contact) => match profile.apply_key_rotation(contact, keyset) {
+                Ok(()) => println!("{} has rotated their keys", contact),
+                Err(err) => println!("[ERROR] {:?}", err),
+            },
+            None => println!("[ERROR] ignored a key rotation notice from an unknown sender"),
+        },
+    }
 }
 
 fn main() {
     let opts: Opts = Opts::parse();
-    match opts.subcmd {
+    match &opts.subcmd {
         SubCommand::Generate(g) => {
-            let profile = Profile::new(g.name.clone(), g.length);
-            let hotk = profile.keyset();
-            println!(
-                "Tagging Key: {}",
-                base32::encode(
-                    base32::Alphabet::RFC4648 { padding: false },
-                    bincode::serialize(&hotk).unwrap().as_slice()
-                )
-                .to_ascii_lowercase()
-            );
+            let mut profile = Profile::new(g.name.clone(), g.length);
+            match profile.export_keyset(false) {
+                Ok(key) => println!("Tagging Key: {}", key),
+                Err(err) => println!("[ERROR] {:?}", err),
+            }
             match profile.save(&opts.profile) {
                 Err(e) => {
                     println!("[ERROR] {}", e)
@@ -83,8 +381,98 @@ fn main() {
             }
         }
         SubCommand::ImportTaggingKey(cmd) => {
-            let mut profile = Profile::get_profile(&opts.profile);
-            profile.import_tagging_key(&cmd.key);
+            let mut profile = load_profile(&opts.profile);
+            match profile.import_tagging_key(&cmd.key, cmd.petname.as_ref()) {
+                Ok(petname) => {
+                    let contact = profile.contact(&petname).unwrap();
+                    println!("Got: {}: {}", petname, contact.id());
+                    if contact.identity_key().is_none() {
+                        println!(
+                            "[WARN] {} shared a key from an older version of niwl without an identity key. They can't be verified and their messages will show as anonymous until they share a new key.",
+                            petname
+                        );
+                    }
+                }
+                Err(err) => {
+                    println!("[ERROR] {:?}", err);
+                    return;
+                }
+            }
+            match profile.save(&opts.profile) {
+                Err(e) => {
+                    println!("[ERROR] {}", e)
+                }
+                _ => {}
+            }
+        }
+        SubCommand::ListContacts(_cmd) => {
+            let profile = load_profile(&opts.profile);
+            let contacts = match profile.contacts() {
+                Ok(contacts) => contacts,
+                Err(err) => {
+                    println!("[ERROR] {:?}", err);
+                    return;
+                }
+            };
+            for contact in contacts {
+                let mut flags = vec![];
+                if contact.is_verified() {
+                    flags.push("verified");
+                }
+                if contact.is_mix() {
+                    flags.push("mix");
+                }
+                println!(
+                    "{}: {} {}",
+                    contact.petname(),
+                    contact.id(),
+                    flags.join(" ")
+                );
+            }
+        }
+        SubCommand::ShowContact(cmd) => {
+            let profile = load_profile(&opts.profile);
+            let contact = match profile.contact(&cmd.id) {
+                Ok(contact) => contact,
+                Err(err) => {
+                    println!("[ERROR] {:?}", err);
+                    return;
+                }
+            };
+            println!("Petname: {}", contact.petname());
+            println!("Key Id: {}", contact.id());
+            match contact.identity_key() {
+                Some(identity_key) => println!("Identity Key: {}", identity_key),
+                None => println!("Identity Key: none, ask them for a new key"),
+            }
+            println!("Verified: {}", contact.is_verified());
+            println!("Mix: {}", contact.is_mix());
+            if let Ok(number) = profile.safety_number(&cmd.id) {
+                println!("Safety Number: {}", number);
+            }
+        }
+        SubCommand::RenameContact(cmd) => {
+            let mut profile = load_profile(&opts.profile);
+            if let Err(err) = profile.rename_contact(&cmd.id, &cmd.petname) {
+                println!("[ERROR] {:?}", err);
+                return;
+            }
+            match profile.save(&opts.profile) {
+                Err(e) => {
+                    println!("[ERROR] {}", e)
+                }
+                _ => {}
+            }
+        }
+        SubCommand::RemoveContact(cmd) => {
+            let mut profile = load_profile(&opts.profile);
+            match profile.remove_contact(&cmd.id) {
+                Ok(contact) => println!("Removed {}: {}", contact.petname(), contact.id()),
+                Err(err) => {
+                    println!("[ERROR] {:?}", err);
+                    return;
+                }
+            }
             match profile.save(&opts.profile) {
                 Err(e) => {
                     println!("[ERROR] {}", e)
@@ -93,69 +481,251 @@ fn main() {
             }
         }
         SubCommand::TagAndSend(cmd) => {
-            let profile = Profile::get_profile(&opts.profile);
-            let server = opts.niwl_server.clone();
+            let mut profile = load_profile(&opts.profile);
+            let client = connect(&opts);
             let contact = cmd.id.clone();
             tokio::runtime::Builder::new_current_thread()
                 .enable_all()
                 .build()
                 .unwrap()
                 .block_on(async {
-                    let result = profile.tag_and_send(&server, contact, &cmd.message).await;
-                    println!("{}", result.unwrap().text().await.unwrap());
+                    let payloads = payloads(
+                        &mut profile,
+                        &contact,
+                        &cmd.message,
+                        &cmd.reply_via,
+                        cmd.ratchet,
+                        0,
+                        false,
+                    );
+                    match payloads {
+                        Ok(payloads) => {
+                            for payload in payloads.iter() {
+                                let result = client
+                                    .tag_and_send(
+                                        &profile,
+                                        contact.clone(),
+                                        &payload,
+                                        cmd.authenticated || cmd.ratchet,
+                                    )
+                                    .await;
+                                match result {
+                                    Ok(()) => println!("Sent"),
+                                    Err(err) => println!("[ERROR] {:?}", err),
+                                }
+                            }
+                        }
+                        Err(err) => println!("[ERROR] {:?}", err),
+                    }
                 });
+            match profile.save(&opts.profile) {
+                Err(e) => {
+                    println!("[ERROR] {}", e)
+                }
+                _ => {}
+            }
         }
         SubCommand::TagAndMix(cmd) => {
-            let profile = Profile::get_profile(&opts.profile);
-            let server = opts.niwl_server.clone();
+            let mut profile = load_profile(&opts.profile);
+            let client = connect(&opts);
             let contact = cmd.id.clone();
             let mix = cmd.mix.clone();
+            warn_unless_mixes(&profile, &[mix.clone()]);
             tokio::runtime::Builder::new_current_thread()
                 .enable_all()
                 .build()
                 .unwrap()
                 .block_on(async {
-                    let result = profile
-                        .tag_and_mix(server, mix, contact, &cmd.message)
-                        .await;
-                    println!("{}", result.unwrap().text().await.unwrap());
+                    let payloads = payloads(
+                        &mut profile,
+                        &contact,
+                        &cmd.message,
+                        &cmd.reply_via,
+                        cmd.ratchet,
+                        1,
+                        false,
+                    );
+                    match payloads {
+                        Ok(payloads) => {
+                            for payload in payloads.iter() {
+                                let result = client
+                                    .tag_and_mix(
+                                        &profile,
+                                        mix.clone(),
+                                        contact.clone(),
+                                        &payload,
+                                        cmd.authenticated || cmd.ratchet,
+                                    )
+                                    .await;
+                                match result {
+                                    Ok(()) => println!("Sent"),
+                                    Err(err) => println!("[ERROR] {:?}", err),
+                                }
+                            }
+                        }
+                        Err(err) => println!("[ERROR] {:?}", err),
+                    }
                 });
+            match profile.save(&opts.profile) {
+                Err(e) => {
+                    println!("[ERROR] {}", e)
+                }
+                _ => {}
+            }
         }
-        SubCommand::Detect(_cmd) => {
-            let mut profile = Profile::get_profile(&opts.profile);
-            let server = opts.niwl_server.clone();
+        SubCommand::TagAndRoute(cmd) => {
+            let mut profile = load_profile(&opts.profile);
+            let client = connect(&opts);
+            let contact = cmd.id.clone();
+            warn_unless_mixes(&profile, &cmd.route);
             tokio::runtime::Builder::new_current_thread()
                 .enable_all()
                 .build()
                 .unwrap()
                 .block_on(async {
-                    match profile.detect_tags(&server).await {
-                        Ok(detected_tags) => {
-                            let mut count = 0;
-                            let mut to_me_count = 0;
-                            for (tag, ciphertext) in detected_tags.detected_tags.iter() {
-                                count += 1;
-                                match profile.private_key.decrypt(ciphertext) {
-                                    Some(message) => {
+                    let payloads = match payloads(
+                        &mut profile,
+                        &contact,
+                        &cmd.message,
+                        &cmd.reply_via,
+                        cmd.ratchet,
+                        cmd.route.len(),
+                        cmd.sphinx,
+                    ) {
+                        Ok(payloads) => payloads,
+                        Err(err) => {
+                            println!("[ERROR] {:?}", err);
+                            return;
+                        }
+                    };
+                    for payload in payloads.iter() {
+                        let result = if cmd.sphinx {
+                            client
+                                .tag_and_route_sphinx(
+                                    &profile,
+                                    &cmd.route,
+                                    contact.clone(),
+                                    payload,
+                                    cmd.authenticated || cmd.ratchet,
+                                )
+                                .await
+                        } else {
+                            client
+                                .tag_and_route(
+                                    &profile,
+                                    &cmd.route,
+                                    contact.clone(),
+                                    payload,
+                                    cmd.authenticated || cmd.ratchet,
+                                )
+                                .await
+                        };
+                        match result {
+                            Ok(()) => println!("Sent"),
+                            Err(err) => println!("[ERROR] {:?}", err),
+                        }
+                    }
+                });
+            match profile.save(&opts.profile) {
+                Err(e) => {
+                    println!("[ERROR] {}", e)
+                }
+                _ => {}
+            }
+        }
+        SubCommand::Reply(cmd) => {
+            let mut profile = load_profile(&opts.profile);
+            let client = connect(&opts);
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async {
+                    let payload = Payload::Message(cmd.message.as_bytes().to_vec());
+                    let result = client.send_reply(&mut profile, &cmd.id, &payload).await;
+                    match result {
+                        Ok(()) => println!("Sent"),
+                        Err(err) => println!("[ERROR] {:?}", err),
+                    }
+                });
+            match profile.save(&opts.profile) {
+                Err(e) => {
+                    println!("[ERROR] {}", e)
+                }
+                _ => {}
+            }
+        }
+        SubCommand::Detect(cmd) => {
+            let mut profile = load_profile(&opts.profile);
+            let client = connect(&opts);
+            if cmd.reset_cursor {
+                profile.reset_cursor(client.server());
+            }
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async {
+                    let mut count = 0;
+                    let mut to_me_count = 0;
+                    // Messages are fetched a page at a time, saving our progress after each page
+                    loop {
+                        match client.detect_tags(&profile).await {
+                            Ok(detected_tags) => {
+                                for (_, ciphertext) in detected_tags.detected_tags.iter() {
+                                    count += 1;
+                                    if let Ok((contact, payload)) =
+                                        profile.decrypt_reply(ciphertext)
+                                    {
+                                        to_me_count += 1;
+                                        show(&mut profile, "reply", Some(contact), payload);
+                                    } else if let Ok((sender, payload)) =
+                                        profile.decrypt(ciphertext)
+                                    {
                                         to_me_count += 1;
-                                        println!("message: {}", message)
+                                        show(&mut profile, "message", sender, payload);
                                     }
-                                    _ => {}
                                 }
-                                profile.update_previously_seen_tag(tag);
+                                profile.update_cursor(client.server(), &detected_tags);
+                                if !detected_tags.more {
+                                    break;
+                                }
+                                if let Err(e) = profile.save(&opts.profile) {
+                                    println!("[ERROR] {}", e);
+                                    return;
+                                }
                             }
-                            if count > 0 {
+                            Err(NiwlError::CursorExpiredError(reason)) => {
                                 println!(
-                                    "Received {} Messages from server. {} were true positives.",
-                                    count, to_me_count
+                                    "[ERROR] {}. Some messages may have been missed, run detect with --reset-cursor to check every message the server still has",
+                                    reason
                                 );
-                            } else {
-                                println!("Received no messages.");
+                                return;
+                            }
+                            Err(err) => {
+                                println!("Error: {:?}", err);
+                                return;
                             }
                         }
-                        Err(err) => {
-                            println!("Error: {}", err)
-                        }
+                    }
+                    if count > 0 {
+                        println!(
+                            "Received {} Messages from server. {} were true positives.",
+                            count, to_me_count
+                        );
+                    } else {
+                        println!("Received no messages.");
+                    }
+                    let expired = profile.expire_retired_keys();
+                    if expired > 0 {
+                        println!("Stopped checking for messages to {} retired keys", expired);
+                    }
+                    let timeout = Duration::hours(FRAGMENT_TIMEOUT_HOURS);
+                    for (id, received, count) in profile.expire_fragments(timeout) {
+                        println!(
+                            "[ERROR] gave up on message {} after receiving {} of {} fragments",
+                            id, received, count
+                        );
                     }
                 });
 
@@ -166,5 +736,158 @@ fn main() {
                 _ => {}
             }
         }
+        SubCommand::SetPassphrase(_cmd) => {
+            let mut profile = load_profile(&opts.profile);
+            let passphrase = rpassword::read_password_from_tty(Some("New passphrase: "))
+                .expect("couldn't read passphrase");
+            let confirmation = rpassword::read_password_from_tty(Some("Confirm passphrase: "))
+                .expect("couldn't read passphrase");
+            if passphrase.is_empty() || passphrase != confirmation {
+                println!("[ERROR] passphrases were empty or did not match");
+                return;
+            }
+            match profile.set_passphrase(Some(&passphrase)) {
+                Ok(()) => match profile.save(&opts.profile) {
+                    Err(e) => {
+                        println!("[ERROR] {}", e)
+                    }
+                    _ => println!("{} is now encrypted", opts.profile),
+                },
+                Err(err) => println!("[ERROR] {:?}", err),
+            }
+        }
+        SubCommand::RemovePassphrase(_cmd) => {
+            let mut profile = load_profile(&opts.profile);
+            match profile.set_passphrase(None) {
+                Ok(()) => match profile.save(&opts.profile) {
+                    Err(e) => {
+                        println!("[ERROR] {}", e)
+                    }
+                    _ => println!("{} is no longer encrypted", opts.profile),
+                },
+                Err(err) => println!("[ERROR] {:?}", err),
+            }
+        }
+        SubCommand::Backup(cmd) => {
+            let profile = load_profile(&opts.profile);
+            match profile.seed_phrase() {
+                Ok(phrase) => println!("Seed Phrase: {}", phrase.as_str()),
+                Err(err) => {
+                    println!("[ERROR] {:?}", err);
+                    return;
+                }
+            }
+            if let Some(filename) = &cmd.contacts {
+                match profile.export_contacts() {
+                    Ok(export) => match std::fs::write(&filename, export) {
+                        Ok(()) => println!("Exported contacts to {}", filename),
+                        Err(e) => println!("[ERROR] {}", e),
+                    },
+                    Err(err) => println!("[ERROR] {:?}", err),
+                }
+            }
+        }
+        SubCommand::Restore(cmd) => {
+            if std::path::Path::new(&opts.profile).exists() {
+                println!(
+                    "[ERROR] {} already exists, refusing to overwrite it",
+                    opts.profile
+                );
+                return;
+            }
+            let phrase = rpassword::read_password_from_tty(Some("Seed phrase: "))
+                .expect("couldn't read seed phrase");
+            let mut profile = match Profile::restore(cmd.name.clone(), cmd.length, &phrase) {
+                Ok(profile) => profile,
+                Err(err) => {
+                    println!("[ERROR] {:?}", err);
+                    return;
+                }
+            };
+            if let Some(filename) = &cmd.contacts {
+                let imported = std::fs::read(&filename)
+                    .map_err(|e| NiwlError::BackupError(e.to_string()))
+                    .and_then(|export| profile.import_contacts(&export));
+                match imported {
+                    Ok(contacts) => println!("Restored contacts: {}", contacts.join(", ")),
+                    Err(err) => println!("[ERROR] {:?}", err),
+                }
+            }
+            match profile.save(&opts.profile) {
+                Err(e) => {
+                    println!("[ERROR] {}", e)
+                }
+                _ => {}
+            }
+        }
+        SubCommand::RotateKeys(cmd) => {
+            let mut profile = load_profile(&opts.profile);
+            let client = connect(&opts);
+            let notices = match profile.rotate_keys(Duration::days(cmd.grace_days)) {
+                Ok(notices) => notices,
+                Err(err) => {
+                    println!("[ERROR] {:?}", err);
+                    return;
+                }
+            };
+            // Keep the new keys before telling anyone about them
+            match profile.save(&opts.profile) {
+                Err(e) => {
+                    println!("[ERROR] {}", e);
+                    return;
+                }
+                _ => {}
+            }
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async {
+                    for (contact, notice) in notices.iter() {
+                        match client.forward(notice).await {
+                            Ok(_) => println!("Sent new keys to {}", contact),
+                            Err(err) => println!("[ERROR] couldn't notify {}: {:?}", contact, err),
+                        }
+                    }
+                });
+            match profile.seed_phrase() {
+                Ok(phrase) => println!("New Seed Phrase: {}", phrase.as_str()),
+                Err(err) => println!("[ERROR] {:?}", err),
+            }
+        }
+        SubCommand::VerifyContact(cmd) => {
+            let mut profile = load_profile(&opts.profile);
+            let result = match cmd.reset {
+                true => profile.unverify_contact(&cmd.id),
+                false => profile.safety_number(&cmd.id).and_then(|number| {
+                    println!("Your safety number with {} is:\n\n    {}\n", cmd.id, number);
+                    println!(
+                        "Enter the safety number {} sees, over a channel you trust:",
+                        cmd.id
+                    );
+                    let mut theirs = String::new();
+                    std::io::stdin()
+                        .read_line(&mut theirs)
+                        .expect("couldn't read safety number");
+                    profile.verify_contact(&cmd.id, &theirs)
+                }),
+            };
+            match result {
+                Ok(()) => match cmd.reset {
+                    true => println!("{} is no longer verified", cmd.id),
+                    false => println!("{} is verified", cmd.id),
+                },
+                Err(err) => {
+                    println!("[ERROR] {:?}", err);
+                    return;
+                }
+            }
+            match profile.save(&opts.profile) {
+                Err(e) => {
+                    println!("[ERROR] {}", e)
+                }
+                _ => {}
+            }
+        }
     }
 }
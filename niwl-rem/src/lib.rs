@@ -2,11 +2,19 @@ use crate::MixMessage::{Forward, Heartbeat};
 use chrono::{DateTime, Duration, Local, NaiveDateTime};
 use fuzzytags::{RootSecret, Tag, TaggingKey};
 use niwl::encrypt::{PrivateKey, TaggedCiphertext};
-use niwl::Profile;
+use niwl::sphinx::{self, ProcessedPacket, ReplayTag};
+use niwl::{NiwlError, Profile};
 use rand::rngs::OsRng;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryInto;
 use std::fmt::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+
+// The file holds a record for every tag seen: the epoch of the packet followed by its tag
+const REPLAY_RECORD_SIZE: usize = 8 + 32;
 
 #[derive(Serialize, Deserialize)]
 pub enum MixMessage {
@@ -14,14 +22,113 @@ pub enum MixMessage {
     Forward(TaggedCiphertext),
 }
 
+/// The replay tags of the sphinx packets a mix has processed, by the epoch each packet was
+/// created for. Each tag is appended to a file as it is seen, so packets cannot be replayed once
+/// the mix restarts. Packets are only processed close to the epoch they were created for, so
+/// the tags of older epochs can be pruned - keeping the cache bounded without the mix having to
+/// rotate its keys.
+pub struct ReplayCache {
+    filename: String,
+    tags: BTreeMap<u64, HashSet<[u8; 32]>>,
+}
+
+impl ReplayCache {
+    /// Open the replay tags kept in `filename`, starting with none if there is no file
+    pub fn open(filename: &str) -> Result<ReplayCache, NiwlError> {
+        let data = match fs::read(filename) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err.into()),
+        };
+        // A record cut short by a crash was never recorded, so neither was its packet forwarded.
+        // It is dropped so that the records appended after it line up.
+        let complete = data.len() - data.len() % REPLAY_RECORD_SIZE;
+        if complete != data.len() {
+            OpenOptions::new()
+                .write(true)
+                .open(filename)?
+                .set_len(complete as u64)?;
+        }
+        let mut tags: BTreeMap<u64, HashSet<[u8; 32]>> = BTreeMap::new();
+        for record in data[..complete].chunks_exact(REPLAY_RECORD_SIZE) {
+            let epoch = u64::from_le_bytes(record[0..8].try_into().unwrap());
+            tags.entry(epoch)
+                .or_default()
+                .insert(record[8..].try_into().unwrap());
+        }
+        Ok(ReplayCache {
+            filename: String::from(filename),
+            tags,
+        })
+    }
+
+    /// Record a replay tag, returning false if it has been seen before. The tag is written out
+    /// before this returns, so the packet it belongs to can safely be forwarded.
+    pub fn insert(&mut self, replay_tag: &ReplayTag) -> Result<bool, NiwlError> {
+        let seen = self.tags.get(&replay_tag.epoch);
+        if seen.map_or(false, |tags| tags.contains(&replay_tag.tag)) {
+            return Ok(false);
+        }
+        let mut record = replay_tag.epoch.to_le_bytes().to_vec();
+        record.extend_from_slice(&replay_tag.tag);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.filename)?;
+        file.write_all(&record)?;
+        file.sync_data()?;
+        self.tags
+            .entry(replay_tag.epoch)
+            .or_default()
+            .insert(replay_tag.tag);
+        Ok(true)
+    }
+
+    /// Forget the tags of epochs before `epoch`, returning how many were removed. The file is
+    /// replaced atomically, so a crash leaves either every tag or only those that are kept.
+    pub fn prune(&mut self, epoch: u64) -> Result<usize, NiwlError> {
+        let kept = self.tags.split_off(&epoch);
+        let removed = std::mem::replace(&mut self.tags, kept);
+        if removed.is_empty() {
+            return Ok(0);
+        }
+        let mut data = Vec::with_capacity(self.len() * REPLAY_RECORD_SIZE);
+        for (epoch, tags) in self.tags.iter() {
+            for tag in tags.iter() {
+                data.extend_from_slice(&epoch.to_le_bytes());
+                data.extend_from_slice(tag);
+            }
+        }
+        let temporary = format!("{}.tmp", self.filename);
+        let result =
+            fs::write(&temporary, &data).and_then(|_| fs::rename(&temporary, &self.filename));
+        if let Err(err) = result {
+            // Keep refusing the removed tags until they are gone from the file too
+            self.tags.extend(removed);
+            return Err(err.into());
+        }
+        Ok(removed.values().map(|tags| tags.len()).sum())
+    }
+
+    pub fn len(&self) -> usize {
+        self.tags.values().map(|tags| tags.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+}
+
 pub struct RandomEjectionMix {
     heartbeat_id: Tag<24>,
     last_heartbeat: DateTime<Local>,
     store: Vec<TaggedCiphertext>,
+    // Replay tags of the sphinx packets this mix has processed in recent epochs
+    replay_cache: ReplayCache,
 }
 
 impl RandomEjectionMix {
-    pub fn init(tag: Tag<24>) -> RandomEjectionMix {
+    pub fn init(tag: Tag<24>, replay_cache: ReplayCache) -> RandomEjectionMix {
         let mut store = vec![];
         for i in 0..10 {
             store.push(RandomEjectionMix::get_random());
@@ -31,28 +138,52 @@ impl RandomEjectionMix {
             heartbeat_id: tag,
             last_heartbeat: Local::now(),
             store,
+            replay_cache,
         }
     }
 
+    /// Forget the replay tags of epochs the mix no longer processes packets for, returning how
+    /// many were removed
+    pub fn prune_replay_tags(&mut self) -> Result<usize, NiwlError> {
+        self.replay_cache
+            .prune(sphinx::current_epoch().saturating_sub(1))
+    }
+
     pub fn get_random() -> TaggedCiphertext {
-        let random_tag = RootSecret::<24>::generate(&mut OsRng).tagging_key().generate_tag(&mut OsRng);
+        let mut random_encryption = RandomEjectionMix::get_random_compact();
+        random_encryption.pad();
+        random_encryption
+    }
+
+    /// A random packet in the compact form expected inside a mix message, suitable for injecting
+    /// into our own pool.
+    pub fn get_random_compact() -> TaggedCiphertext {
+        let random_tag = RootSecret::<24>::generate(&mut OsRng)
+            .tagging_key()
+            .generate_tag(&mut OsRng);
         let random_secret = PrivateKey::generate();
-        let random_encryption = random_secret
+        random_secret
             .public_key()
-            .encrypt(&random_tag, &String::new());
-        random_encryption
+            .encrypt_bytes_compact(&random_tag, &[], None)
+            .unwrap()
     }
 
-    pub fn push(&mut self, tag: &Tag<24>, plaintext: &String) -> Option<MixMessage> {
-        // The plaintext can either be a TaggedCiphertext OR a HeartBeat
-        let message: serde_json::Result<TaggedCiphertext> =
-            serde_json::from_str(plaintext.as_str());
-        match &message {
-            Ok(ciphertext) => return Some(Forward(self.random_ejection_mix(ciphertext))),
-            Err(_) => {
-                // Assume this is a Mix Message
-                let message: serde_json::Result<MixMessage> =
-                    serde_json::from_str(plaintext.as_str());
+    /// Process a message decrypted by this mix. If the message wraps another packet then that
+    /// packet is the next layer of a route: it is padded and added to the store, and a random
+    /// packet from the store is returned to be forwarded. The next hop (another mix, or the
+    /// final recipient) will detect the forwarded packet by its tag.
+    pub fn push(&mut self, tag: &Tag<24>, plaintext: &[u8]) -> Option<MixMessage> {
+        // The plaintext can either be a (compact) TaggedCiphertext OR a HeartBeat
+        match TaggedCiphertext::from_bytes(plaintext) {
+            Some(mut ciphertext) => {
+                // Pad the inner packet so it is indistinguishable in size from any other
+                ciphertext.pad();
+                return Some(Forward(self.random_ejection_mix(&ciphertext)));
+            }
+            None => {
+                // Assume this is a Mix Message. These are JSON, so they start with `{` which no
+                // ciphertext version uses.
+                let message: serde_json::Result<MixMessage> = serde_json::from_slice(plaintext);
                 match &message {
                     Ok(mixMessage) => match mixMessage {
                         Heartbeat(id, time) => self.process_heartbeat(id, time),
@@ -64,6 +195,49 @@ impl RandomEjectionMix {
         }
     }
 
+    /// Process a Sphinx packet addressed to this mix, under whichever of its private keys
+    /// (current or retired) the packet was made for. Whether the processed packet is destined
+    /// for another mix or for the final recipient it is added to the store, and a random packet
+    /// from the store is returned to be forwarded. Replayed packets are dropped.
+    pub fn push_sphinx(
+        &mut self,
+        private_keys: &[&PrivateKey],
+        packet: &TaggedCiphertext,
+    ) -> Option<MixMessage> {
+        let mut processed = Err(NiwlError::CryptoError(String::from("the mix has no keys")));
+        for private_key in private_keys.iter() {
+            processed = sphinx::process_packet(private_key, packet);
+            if processed.is_ok() {
+                break;
+            }
+        }
+        match processed {
+            Ok((replay_tag, processed)) => {
+                match self.replay_cache.insert(&replay_tag) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        println!("[DEBUG] Dropping replayed sphinx packet");
+                        return None;
+                    }
+                    // Forwarding a packet we could not record would allow it to be replayed
+                    Err(err) => {
+                        println!("[ERROR] Dropping sphinx packet: {:?}", err);
+                        return None;
+                    }
+                }
+                let ciphertext = match processed {
+                    ProcessedPacket::Relay(ciphertext) => ciphertext,
+                    ProcessedPacket::Deliver(ciphertext) => ciphertext,
+                };
+                Some(Forward(self.random_ejection_mix(&ciphertext)))
+            }
+            Err(err) => {
+                println!("[DEBUG] Dropping sphinx packet: {:?}", err);
+                None
+            }
+        }
+    }
+
     fn process_heartbeat(
         &mut self,
         tag: &Tag<24>,
@@ -101,3 +275,102 @@ impl RandomEjectionMix {
         ejection
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{MixMessage, RandomEjectionMix, ReplayCache};
+    use chrono::Local;
+    use niwl::sphinx::{self, ReplayTag};
+    use niwl::Profile;
+    use rand::rngs::OsRng;
+
+    fn replay_filename(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("niwl-test-{}-{}.replay", name, std::process::id()))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    #[test]
+    fn test_heartbeats_are_not_mistaken_for_ciphertexts() {
+        let filename = replay_filename("heartbeat");
+        let mix = Profile::new(String::from("mix"), 0);
+        let heartbeat = mix.root_secret.tagging_key().generate_tag(&mut OsRng);
+        let cache = ReplayCache::open(&filename).unwrap();
+        let mut rem = RandomEjectionMix::init(heartbeat.clone(), cache);
+
+        let message = serde_json::to_vec(&MixMessage::Heartbeat(heartbeat.clone(), Local::now()));
+        assert!(matches!(
+            rem.push(&heartbeat, &message.unwrap()),
+            Some(MixMessage::Heartbeat(_, _))
+        ));
+        assert!(!std::path::Path::new(&filename).exists());
+    }
+
+    #[test]
+    fn test_replayed_packets_are_dropped() {
+        let filename = replay_filename("replay");
+        let mut mix = Profile::new(String::from("mix"), 0);
+        let key = mix.private_key.public_key();
+        let tag = mix.root_secret.tagging_key().generate_tag(&mut OsRng);
+        let payload = RandomEjectionMix::get_random_compact();
+        let packet = sphinx::create_packet(&[(&key, tag)], &payload).unwrap();
+        let heartbeat = mix.root_secret.tagging_key().generate_tag(&mut OsRng);
+
+        let cache = ReplayCache::open(&filename).unwrap();
+        let mut rem = RandomEjectionMix::init(heartbeat.clone(), cache);
+        assert!(matches!(
+            rem.push_sphinx(&mix.private_keys(), &packet),
+            Some(MixMessage::Forward(_))
+        ));
+        assert!(rem.push_sphinx(&mix.private_keys(), &packet).is_none());
+
+        // The packet is still refused once the mix restarts, and after it rotates its keys
+        let cache = ReplayCache::open(&filename).unwrap();
+        assert_eq!(cache.len(), 1);
+        let mut rem = RandomEjectionMix::init(heartbeat.clone(), cache);
+        assert!(rem.push_sphinx(&mix.private_keys(), &packet).is_none());
+        mix.rotate_keys(chrono::Duration::days(7)).unwrap();
+        assert!(rem.push_sphinx(&mix.private_keys(), &packet).is_none());
+
+        // Packets for the retired key are still processed during its grace period
+        let tag = mix.root_secret.tagging_key().generate_tag(&mut OsRng);
+        let other = sphinx::create_packet(&[(&key, tag)], &payload).unwrap();
+        assert!(rem.push_sphinx(&mix.private_keys(), &other).is_some());
+        assert_eq!(rem.prune_replay_tags().unwrap(), 0);
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn test_replay_tags_of_old_epochs_are_pruned() {
+        let filename = replay_filename("prune");
+        let mut cache = ReplayCache::open(&filename).unwrap();
+        let replay_tag = |epoch: u64, byte: u8| ReplayTag {
+            epoch,
+            tag: [byte; 32],
+        };
+        for epoch in 10..13 {
+            assert!(cache.insert(&replay_tag(epoch, 1)).unwrap());
+            assert!(cache.insert(&replay_tag(epoch, 2)).unwrap());
+            assert!(!cache.insert(&replay_tag(epoch, 1)).unwrap());
+        }
+        assert_eq!(cache.len(), 6);
+
+        assert_eq!(cache.prune(12).unwrap(), 4);
+        assert_eq!(cache.prune(12).unwrap(), 0);
+        assert!(!cache.insert(&replay_tag(12, 2)).unwrap());
+        assert!(cache.insert(&replay_tag(13, 2)).unwrap());
+
+        // A record cut short by a crash is dropped, and the records after it still line up
+        let mut data = std::fs::read(&filename).unwrap();
+        data.extend_from_slice(&[0u8; 17]);
+        std::fs::write(&filename, &data).unwrap();
+        let mut cache = ReplayCache::open(&filename).unwrap();
+        assert_eq!(cache.len(), 3);
+        assert!(cache.insert(&replay_tag(13, 3)).unwrap());
+        let cache = ReplayCache::open(&filename).unwrap();
+        assert_eq!(cache.len(), 4);
+        assert!(!std::path::Path::new(&format!("{}.tmp", filename)).exists());
+        std::fs::remove_file(&filename).unwrap();
+    }
+}
@@ -1,8 +1,11 @@
 use chrono::Local;
 use clap::Clap;
-use niwl::Profile;
+use niwl::client::NiwlClient;
+use niwl::encrypt::SPHINX_VERSION;
+use niwl::storage::PASSPHRASE_ENV_VAR;
+use niwl::{NiwlError, Profile};
 use niwl_rem::MixMessage::Heartbeat;
-use niwl_rem::{MixMessage, RandomEjectionMix};
+use niwl_rem::{MixMessage, RandomEjectionMix, ReplayCache};
 use rand::{thread_rng, Rng, rngs::OsRng};
 use std::time::Duration;
 
@@ -33,126 +36,233 @@ struct Generate {
 
 /// Run a Random Ejection Mix
 #[derive(Clap)]
-struct Run {}
+struct Run {
+    /// a file containing the passphrase of the profile, if it is encrypted. Otherwise the
+    /// passphrase is read from the NIWL_PASSPHRASE environment variable
+    #[clap(long)]
+    passphrase_file: Option<String>,
+    /// rotate the keys of the mix every this many days. The old keys keep working for as long
+    /// again, during which clients should import the new key the mix prints
+    #[clap(long)]
+    rotate_days: Option<i64>,
+}
+
+// Mixes run unattended, so the passphrase must come from a file or the environment
+fn passphrase(passphrase_file: &Option<String>) -> Option<String> {
+    match passphrase_file {
+        Some(filename) => match std::fs::read_to_string(filename) {
+            Ok(passphrase) => Some(
+                passphrase
+                    .trim_end_matches(&['\r', '\n'][..])
+                    .to_string(),
+            ),
+            Err(err) => {
+                println!("Error: couldn't read {} : {}", filename, err);
+                None
+            }
+        },
+        None => std::env::var(PASSPHRASE_ENV_VAR).ok(),
+    }
+}
 
 fn main() {
     let opts: Opts = Opts::parse();
     match opts.subcmd {
         SubCommand::Generate(g) => {
-            let profile = Profile::new(g.name.clone(), 0);
-            let hotk = profile.keyset();
-            println!(
-                "Tagging Key: {}",
-                base32::encode(
-                    base32::Alphabet::RFC4648 { padding: false },
-                    bincode::serialize(&hotk).unwrap().as_slice()
-                )
-                .to_ascii_lowercase()
-            );
-            profile.save(&opts.profile_filename);
+            let mut profile = Profile::new(g.name.clone(), 0);
+            match profile.export_keyset(true) {
+                Ok(key) => println!("Tagging Key: {}", key),
+                Err(err) => {
+                    println!("Error: {:?}", err);
+                    std::process::exit(1);
+                }
+            }
+            if let Err(err) = profile.save(&opts.profile_filename) {
+                println!("Error: {:?}", err);
+                std::process::exit(1);
+            }
         }
-        SubCommand::Run(_cmd) => {
-            let mut profile = Profile::get_profile(&opts.profile_filename);
+        SubCommand::Run(cmd) => {
+            let passphrase = passphrase(&cmd.passphrase_file);
+            let mut profile = match Profile::open(&opts.profile_filename, passphrase.as_ref()) {
+                Ok(profile) => profile,
+                Err(err) => {
+                    println!("Error: {:?}", err);
+                    std::process::exit(1);
+                }
+            };
             let filename = opts.profile_filename.clone();
-            let server = opts.niwl_server.clone();
+            // The replay tags of the packets processed so far are kept next to the profile
+            let replay_filename = format!("{}.replay", filename);
+            let replay_cache = match ReplayCache::open(&replay_filename) {
+                Ok(replay_cache) => replay_cache,
+                Err(err) => {
+                    println!("Error: {:?}", err);
+                    std::process::exit(1);
+                }
+            };
+            let client = match NiwlClient::new(&opts.niwl_server) {
+                Ok(client) => client,
+                Err(err) => {
+                    println!("Error: {:?}", err);
+                    std::process::exit(1);
+                }
+            };
             tokio::runtime::Builder::new_current_thread()
                 .enable_all()
                 .build()
                 .unwrap()
                 .block_on(async {
                     let random_tag = profile.root_secret.tagging_key().generate_tag(&mut OsRng);
-                    let mut rem = RandomEjectionMix::init(random_tag.clone());
+                    let mut rem = RandomEjectionMix::init(random_tag.clone(), replay_cache);
                     println!("[DEBUG] kicking off initial heartbeat...");
-                    profile
-                        .send_to_self(
-                            &server,
-                            &serde_json::to_string(&Heartbeat(random_tag.clone(), Local::now()))
-                                .unwrap(),
-                        )
-                        .await;
+                    let heartbeat =
+                        serde_json::to_vec(&Heartbeat(random_tag.clone(), Local::now())).unwrap();
+                    if let Err(err) = client.send_to_self(&profile, &heartbeat).await {
+                        println!("[ERROR] {:?}", err);
+                    }
                     println!("[DEBUG] starting mixing loop");
-                    let detection_key = profile.root_secret.extract_detection_key(24);
 
                     loop {
-
+                        let due = cmd.rotate_days.map(chrono::Duration::days).filter(|period| {
+                            Local::now() - profile.keys_created() >= *period
+                        });
+                        if let Some(period) = due {
+                            // Packets for the old keys are still processed for another period,
+                            // giving clients time to import the new key
+                            let notices = match profile.rotate_keys(period) {
+                                Ok(notices) => notices,
+                                Err(err) => {
+                                    println!("Error: {:?}", err);
+                                    std::process::exit(1);
+                                }
+                            };
+                            if let Err(err) = profile.save(&filename) {
+                                println!("Error: {:?}", err);
+                                std::process::exit(1);
+                            }
+                            println!("[INFO] Rotated keys, publish the new key:");
+                            match profile.export_keyset(true) {
+                                Ok(key) => println!("Tagging Key: {}", key),
+                                Err(err) => println!("[ERROR] {:?}", err),
+                            }
+                            for (contact, notice) in notices.iter() {
+                                if let Err(err) = client.forward(notice).await {
+                                    println!("[ERROR] couldn't notify {}: {:?}", contact, err);
+                                }
+                            }
+                            // Our last heartbeat was sent to the old keys
+                            let heartbeat =
+                                serde_json::to_vec(&Heartbeat(random_tag.clone(), Local::now()))
+                                    .unwrap();
+                            if let Err(err) = client.send_to_self(&profile, &heartbeat).await {
+                                println!("[ERROR] {:?}", err);
+                            }
+                        }
+                        if profile.expire_retired_keys() > 0 {
+                            println!("[INFO] The grace period of our old keys has passed");
+                            if let Err(err) = profile.save(&filename) {
+                                println!("[ERROR] could not save the profile: {:?}", err);
+                            }
+                        }
+                        match rem.prune_replay_tags() {
+                            Ok(0) => {}
+                            Ok(pruned) => println!("[DEBUG] Pruned {} expired replay tags", pruned),
+                            Err(err) => println!("[ERROR] could not prune replay tags: {:?}", err),
+                        }
 
                         if rem.check_heartbeat() == false {
                             println!("[ERROR] Niwl Server is Delaying Messages for more than 2 Minutes...Possible Attack...");
-                            let num_messages : i32 = thread_rng().gen_range(0, 100);
+                            let num_messages: i32 = thread_rng().gen_range(0, 100);
                             // Kick out a random number of messages...
-                            for i in 0..num_messages {
-                                random_delay();
-                                profile.send_to_self(&server, &serde_json::to_string(
-                                    &RandomEjectionMix::get_random(),
-                                ).unwrap()).await;
+                            for _ in 0..num_messages {
+                                random_delay().await;
+                                let random = RandomEjectionMix::get_random_compact().to_bytes();
+                                if let Err(err) = client.send_to_self(&profile, &random).await {
+                                    println!("[ERROR] {:?}", err);
+                                }
                             }
                         } else {
                             // After every heart beat kick out a random
                             // message so we wil eventually clear the pool
-                            random_delay();
-                            profile.send_to_self(&server,&serde_json::to_string(
-                                &RandomEjectionMix::get_random(),
-                            ).unwrap()).await;
-
+                            random_delay().await;
+                            let random = RandomEjectionMix::get_random_compact().to_bytes();
+                            if let Err(err) = client.send_to_self(&profile, &random).await {
+                                println!("[ERROR] {:?}", err);
+                            }
                         }
 
-
-                        match profile.detect_tags(&server).await {
+                        // Keep fetching without waiting while the server has more pages for us
+                        let mut more = false;
+                        match client.detect_tags(&profile).await {
                             Ok(detected_tags) => {
-                                let mut latest_tag = None;
                                 for (tag, ciphertext) in detected_tags.detected_tags.iter() {
-                                    if detection_key.test_tag(&tag) {
-                                        let plaintext = profile.private_key.decrypt(ciphertext);
-                                        match plaintext {
-                                            Some(plaintext) => match rem.push(tag, &plaintext) {
-                                                None => {}
-                                                Some(message) => {
-                                                    let response = match &message {
-                                                        MixMessage::Heartbeat(_, _) => {
-                                                            profile
-                                                                .send_to_self(
-                                                                    &server,
-                                                                    &serde_json::to_string(
-                                                                        &message,
-                                                                    )
+                                    if profile.owns_tag(&tag) {
+                                        let private_keys = profile.private_keys();
+                                        let mixed = if ciphertext.version() == SPHINX_VERSION {
+                                            rem.push_sphinx(&private_keys, ciphertext)
+                                        } else {
+                                            match private_keys
+                                                .iter()
+                                                .find_map(|key| key.decrypt_bytes(ciphertext))
+                                            {
+                                                Some(plaintext) => rem.push(tag, &plaintext),
+                                                None => None,
+                                            }
+                                        };
+                                        match mixed {
+                                            None => {}
+                                            Some(message) => {
+                                                let response = match &message {
+                                                    MixMessage::Heartbeat(_, _) => {
+                                                        client
+                                                            .send_to_self(
+                                                                &profile,
+                                                                &serde_json::to_vec(&message)
                                                                     .unwrap(),
-                                                                )
-                                                                .await
-                                                        }
-                                                        MixMessage::Forward(ciphertext) => {
-                                                            profile
-                                                                .forward(&server, ciphertext)
-                                                                .await
-                                                        }
-                                                    };
+                                                            )
+                                                            .await
+                                                    }
+                                                    MixMessage::Forward(ciphertext) => {
+                                                        client.forward(ciphertext).await
+                                                    }
+                                                };
 
-                                                    match response {
-                                                        Err(err) => {
-                                                            println!("[ERROR] {:?}", err);
-                                                        }
-                                                        _ => {}
+                                                match response {
+                                                    Err(err) => {
+                                                        println!("[ERROR] {:?}", err);
                                                     }
+                                                    _ => {}
                                                 }
-                                            },
-                                            _ => {}
+                                            }
                                         }
                                     }
-                                    latest_tag = Some(tag.clone());
                                 }
-                                match &latest_tag {
-                                    Some(tag) => {
-                                        profile.update_previously_seen_tag(tag);
-                                        profile.save(&filename);
+                                if profile.cursor(client.server()) != detected_tags.cursor {
+                                    profile.update_cursor(client.server(), &detected_tags);
+                                    if let Err(err) = profile.save(&filename) {
+                                        println!("[ERROR] could not save the cursor: {:?}", err);
                                     }
-                                    _ => {}
                                 }
+                                more = detected_tags.more;
+                            }
+                            Err(NiwlError::CursorExpiredError(reason)) => {
+                                // We cannot get back what the server pruned, so start over with
+                                // what it still has
+                                println!(
+                                    "[ERROR] {}, checking every message the server still has",
+                                    reason
+                                );
+                                profile.reset_cursor(client.server());
                             }
                             Err(err) => {
-                                println!("Error: {}", err)
+                                println!("Error: {:?}", err)
                             }
                         }
 
-                        random_delay().await;
+                        if !more {
+                            random_delay().await;
+                        }
                     }
                 });
         }
@@ -0,0 +1,75 @@
+//! Throughput of detecting the messages sent to a client among 100k and 1M stored tags.
+//!
+//! `serial` decompresses and tests every tag in turn, as the server did before detection was
+//! spread across a pool. `parallel` does the same on a `Detector`, as for messages older than the
+//! tags it keeps, and `kept` tests the decompressed tags in its window, as when clients check
+//! recent messages.
+//! Run with `cargo bench -p niwl-server`.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use fuzzytags::{DetectionKey, RootSecret, Tag};
+use niwl_server::detect::Detector;
+use rand::rngs::OsRng;
+
+// Testing a tag costs the same whichever tag it is, so a smaller set of distinct tags is
+// repeated to fill the table rather than generating every one
+const DISTINCT_TAGS: usize = 1000;
+
+// The detection key length `niwl-client generate` uses by default
+const DETECTION_KEY_LENGTH: usize = 2;
+
+fn stored_tags(count: usize) -> (DetectionKey<24>, Vec<(i64, Vec<u8>)>) {
+    let secrets: Vec<RootSecret<24>> = (0..10)
+        .map(|_| RootSecret::<24>::generate(&mut OsRng))
+        .collect();
+    let distinct: Vec<Vec<u8>> = (0..DISTINCT_TAGS)
+        .map(|i| {
+            let tagging_key = secrets[i % secrets.len()].tagging_key();
+            tagging_key.generate_tag(&mut OsRng).compress()
+        })
+        .collect();
+    let tags = (0..count)
+        .map(|i| (i as i64 + 1, distinct[i % DISTINCT_TAGS].clone()))
+        .collect();
+    (secrets[0].extract_detection_key(DETECTION_KEY_LENGTH), tags)
+}
+
+fn bench_detection(c: &mut Criterion) {
+    let mut group = c.benchmark_group("detection");
+    group.sample_size(10);
+    for count in [100_000, 1_000_000].iter() {
+        let (detection_key, tags) = stored_tags(*count);
+        group.throughput(Throughput::Elements(*count as u64));
+
+        group.bench_with_input(BenchmarkId::new("serial", count), &tags, |b, tags| {
+            b.iter(|| {
+                tags.iter()
+                    .filter(|(_, compressed)| {
+                        Tag::<24>::decompress(compressed)
+                            .map_or(false, |tag| detection_key.test_tag(&tag))
+                    })
+                    .count()
+            })
+        });
+
+        let detector = Detector::new(0, 0).unwrap();
+        group.bench_with_input(BenchmarkId::new("parallel", count), &tags, |b, tags| {
+            b.iter(|| detector.detect(&detection_key, tags).len())
+        });
+
+        let detector = Detector::new(0, *count).unwrap();
+        for (id, compressed) in tags.iter() {
+            let tag = Tag::<24>::decompress(compressed).unwrap();
+            detector.insert(tag, || Ok::<i64, ()>(*id)).unwrap();
+        }
+        group.bench_with_input(BenchmarkId::new("kept", count), count, |b, count| {
+            b.iter(|| {
+                let checked = detector.detect_kept(&detection_key, 0, i64::MAX, *count);
+                checked.ok().unwrap().matches.len()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_detection);
+criterion_main!(benches);
@@ -1,5 +1,5 @@
 CREATE TABLE IF NOT EXISTS tags (
               id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
               tag BLOB NOT NULL UNIQUE,
-              message TEXT NOT NULL
+              message BLOB NOT NULL
 )
\ No newline at end of file
@@ -0,0 +1,312 @@
+//! Detection of the tags matching a detection key, spread across a pool of worker threads.
+//!
+//! Decompressing a tag and testing it against a detection key both cost group operations, so a
+//! request checking many messages could otherwise keep a single core busy for seconds. A
+//! `Detector` splits the tags of a request across its pool.
+//!
+//! Most requests are from clients that sync regularly, and only check the newest messages. The
+//! detector keeps a window of the decompressed tags of the newest messages, appended as messages
+//! are stored, so that these requests neither read the tags from the database nor decompress
+//! them. The window is bounded, dropping the oldest tags as new ones are appended, and covers
+//! every message from its first id on: messages older than that are read from the database.
+use fuzzytags::{DetectionKey, Tag};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
+use std::collections::VecDeque;
+use std::sync::{Mutex, RwLock};
+
+/// How many decompressed tags the server keeps
+pub const TAG_WINDOW_SIZE: usize = 100_000;
+
+/// The result of checking a batch of messages
+pub struct Checked {
+    /// The id and tag of the messages that matched, in order
+    pub matches: Vec<(i64, Tag<24>)>,
+    /// How many messages were checked
+    pub count: usize,
+    /// The id of the last message checked
+    pub last: Option<i64>,
+    /// Whether there may be messages left to check
+    pub more: bool,
+}
+
+struct TagWindow {
+    // The tags of the newest messages, by id in ascending order
+    tags: VecDeque<(i64, Tag<24>)>,
+    // Every message from this id on has its tag in the window, None until a tag is appended
+    from: Option<i64>,
+    capacity: usize,
+}
+
+impl TagWindow {
+    fn push(&mut self, id: i64, tag: Tag<24>) {
+        // Ids are appended in order, an older id can only be a message we already dropped
+        if self.from.map_or(false, |from| id < from)
+            || self.tags.back().map_or(false, |(last, _)| id <= *last)
+        {
+            return;
+        }
+        self.from.get_or_insert(id);
+        self.tags.push_back((id, tag));
+        while self.tags.len() > self.capacity {
+            if let Some((dropped, _)) = self.tags.pop_front() {
+                self.from = Some(dropped + 1);
+            }
+        }
+    }
+
+    fn prune(&mut self, before: i64) {
+        while self.tags.front().map_or(false, |(id, _)| *id < before) {
+            self.tags.pop_front();
+        }
+        if let Some(from) = self.from.as_mut() {
+            *from = (*from).max(before);
+        }
+    }
+
+    // The position of the first tag with an id after `after`
+    fn position_after(&self, after: i64) -> usize {
+        let (mut low, mut high) = (0, self.tags.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            match self.tags[middle].0 <= after {
+                true => low = middle + 1,
+                false => high = middle,
+            }
+        }
+        low
+    }
+}
+
+pub struct Detector {
+    pool: ThreadPool,
+    window: RwLock<TagWindow>,
+    // Held while a message is stored and its tag appended, so that tags are appended in order
+    inserting: Mutex<()>,
+}
+
+impl Detector {
+    /// A detector with `threads` worker threads (0 for one per CPU), keeping the tags of up to
+    /// `capacity` of the newest messages
+    pub fn new(threads: usize, capacity: usize) -> Result<Detector, ThreadPoolBuildError> {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("niwl-detect-{}", i))
+            .build()?;
+        Ok(Detector {
+            pool,
+            window: RwLock::new(TagWindow {
+                tags: VecDeque::new(),
+                from: None,
+                capacity,
+            }),
+            inserting: Mutex::new(()),
+        })
+    }
+
+    /// Store a message with `store`, which returns its id, and keep its tag. Ids must increase
+    /// with every message stored, so messages are stored one at a time.
+    ///
+    /// Requests are only blocked while the tag is appended, not while the message is stored. A
+    /// request in between does not check the new message: the tags kept end before it, and so
+    /// does the cursor of the request.
+    pub fn insert<E>(
+        &self,
+        tag: Tag<24>,
+        store: impl FnOnce() -> Result<i64, E>,
+    ) -> Result<i64, E> {
+        let _inserting = self.inserting.lock().unwrap();
+        let id = store()?;
+        self.window.write().unwrap().push(id, tag);
+        Ok(id)
+    }
+
+    /// Drop the tags of the messages before `before`, which are no longer stored
+    pub fn prune(&self, before: i64) {
+        self.window.write().unwrap().prune(before);
+    }
+
+    /// Check up to `limit` of the messages with an id after `after`, and up to `until`, from the
+    /// tags kept. If the tags of the messages right after `after` are not kept, returns the id up
+    /// to which the messages must be read from the database instead.
+    pub fn detect_kept(
+        &self,
+        detection_key: &DetectionKey<24>,
+        after: i64,
+        until: i64,
+        limit: usize,
+    ) -> Result<Checked, i64> {
+        let window = self.window.read().unwrap();
+        match window.from {
+            Some(from) if from <= after + 1 => {}
+            Some(from) => return Err(from - 1),
+            None => return Err(until),
+        }
+        let mut kept: Vec<&(i64, Tag<24>)> = window
+            .tags
+            .range(window.position_after(after)..)
+            .take_while(|(id, _)| *id <= until)
+            .take(limit + 1)
+            .collect();
+        let more = kept.len() > limit;
+        kept.truncate(limit);
+        let matches = self.pool.install(|| {
+            kept.par_iter()
+                .filter(|(_, tag)| detection_key.test_tag(tag))
+                .map(|(id, tag)| (*id, tag.clone()))
+                .collect()
+        });
+        Ok(Checked {
+            matches,
+            count: kept.len(),
+            last: kept.last().map(|(id, _)| *id),
+            more,
+        })
+    }
+
+    /// The messages whose tags match the detection key, as their id and decompressed tag, given
+    /// the id and compressed tag of each message to check. Matches are returned in the order the
+    /// messages were given, and tags that cannot be decompressed never match.
+    pub fn detect(
+        &self,
+        detection_key: &DetectionKey<24>,
+        messages: &[(i64, Vec<u8>)],
+    ) -> Vec<(i64, Tag<24>)> {
+        self.pool.install(|| {
+            messages
+                .par_iter()
+                .filter_map(|(id, compressed)| {
+                    let tag = Tag::<24>::decompress(compressed)?;
+                    match detection_key.test_tag(&tag) {
+                        true => Some((*id, tag)),
+                        false => None,
+                    }
+                })
+                .collect()
+        })
+    }
+
+    /// How many tags are kept
+    pub fn len(&self) -> usize {
+        self.window.read().unwrap().tags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detect::Detector;
+    use fuzzytags::RootSecret;
+    use rand::rngs::OsRng;
+
+    fn ids<T>(matches: Vec<(i64, T)>) -> Vec<i64> {
+        matches.into_iter().map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn test_detect() {
+        let ours = RootSecret::<24>::generate(&mut OsRng);
+        let theirs = RootSecret::<24>::generate(&mut OsRng);
+        let detection_key = ours.extract_detection_key(24);
+        let mut messages = vec![];
+        for id in 1..=100 {
+            let secret = if id % 10 == 0 { &ours } else { &theirs };
+            let tag = secret.tagging_key().generate_tag(&mut OsRng);
+            messages.push((id, tag.compress()));
+        }
+        // A corrupted tag never matches
+        messages.push((101, vec![0u8; 3]));
+
+        let detector = Detector::new(4, 50).unwrap();
+        let expected: Vec<i64> = (1..=10).map(|i| i * 10).collect();
+        assert_eq!(ids(detector.detect(&detection_key, &messages)), expected);
+        assert!(detector.is_empty());
+    }
+
+    #[test]
+    fn test_tag_window() {
+        let ours = RootSecret::<24>::generate(&mut OsRng);
+        let theirs = RootSecret::<24>::generate(&mut OsRng);
+        let detection_key = ours.extract_detection_key(24);
+        let detector = Detector::new(4, 50).unwrap();
+
+        // Nothing is kept until a message is stored
+        assert_eq!(
+            detector.detect_kept(&detection_key, 0, i64::MAX, 10).err(),
+            Some(i64::MAX)
+        );
+        for id in 1..=100 {
+            let secret = if id % 10 == 0 { &ours } else { &theirs };
+            let tag = secret.tagging_key().generate_tag(&mut OsRng);
+            detector.insert(tag, || Ok::<i64, ()>(id)).unwrap();
+        }
+
+        // Only the newest tags are kept, older messages must be read from the database
+        assert_eq!(detector.len(), 50);
+        assert_eq!(
+            detector.detect_kept(&detection_key, 0, i64::MAX, 10).err(),
+            Some(50)
+        );
+        let checked = detector
+            .detect_kept(&detection_key, 50, i64::MAX, 30)
+            .ok()
+            .unwrap();
+        assert_eq!(ids(checked.matches), vec![60, 70, 80]);
+        assert_eq!(
+            (checked.count, checked.last, checked.more),
+            (30, Some(80), true)
+        );
+        let checked = detector
+            .detect_kept(&detection_key, 80, 95, 30)
+            .ok()
+            .unwrap();
+        assert_eq!(ids(checked.matches), vec![90]);
+        assert_eq!(
+            (checked.count, checked.last, checked.more),
+            (15, Some(95), false)
+        );
+
+        // Tags of pruned messages are dropped, and the window only covers what is left
+        detector.prune(75);
+        assert_eq!(detector.len(), 26);
+        assert_eq!(
+            detector.detect_kept(&detection_key, 50, i64::MAX, 10).err(),
+            Some(74)
+        );
+        let checked = detector
+            .detect_kept(&detection_key, 74, i64::MAX, 100)
+            .ok()
+            .unwrap();
+        assert_eq!(ids(checked.matches), vec![80, 90, 100]);
+        assert!(!checked.more);
+    }
+
+    #[test]
+    fn test_requests_are_answered_while_storing() {
+        let ours = RootSecret::<24>::generate(&mut OsRng);
+        let detection_key = ours.extract_detection_key(24);
+        let detector = Detector::new(1, 50).unwrap();
+        let tag = ours.tagging_key().generate_tag(&mut OsRng);
+        detector.insert(tag, || Ok::<i64, ()>(1)).unwrap();
+
+        let tag = ours.tagging_key().generate_tag(&mut OsRng);
+        detector
+            .insert(tag, || {
+                // The message is stored but its tag is not kept yet, so it is left for the next
+                // request rather than skipped
+                let checked = detector.detect_kept(&detection_key, 0, 2, 10).ok().unwrap();
+                assert_eq!(ids(checked.matches), vec![1]);
+                assert_eq!(
+                    (checked.count, checked.last, checked.more),
+                    (1, Some(1), false)
+                );
+                Ok::<i64, ()>(2)
+            })
+            .unwrap();
+        let checked = detector.detect_kept(&detection_key, 1, 2, 10).ok().unwrap();
+        assert_eq!(ids(checked.matches), vec![2]);
+    }
+}
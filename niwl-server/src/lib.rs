@@ -0,0 +1,3 @@
+//! The parts of the niwl server that do not depend on its routes or database, so that they can
+//! be tested and benchmarked on their own.
+pub mod detect;
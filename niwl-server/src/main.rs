@@ -7,125 +7,364 @@ extern crate rocket_contrib;
 
 use fuzzytags::{DetectionKey, Tag};
 use niwl::encrypt::TaggedCiphertext;
-use niwl::{FetchMessagesRequest, PostMessageRequest};
+use niwl::wire::{self, Wire};
+use niwl::{DetectedTags, FetchMessagesRequest, PostMessageRequest, MAX_PAGE_SIZE};
+use niwl_server::detect::{Checked, Detector, TAG_WINDOW_SIZE};
+use rocket::data::Data;
+use rocket::http::{ContentType, Status};
+use rocket::response::{content, status};
+use rocket::State;
 use rocket_contrib::databases::rusqlite;
-use rocket_contrib::databases::rusqlite::types::ToSql;
+use rocket_contrib::databases::rusqlite::types::{ToSql, Value};
 use rocket_contrib::json;
 use rocket_contrib::json::{Json, JsonValue};
+use std::convert::TryFrom;
+use std::io::Read;
+
+/// The largest request body accepted in the wire encoding
+const MAX_WIRE_REQUEST_SIZE: u64 = 64 * 1024;
+
+/// How many messages are read from the database before their tags are checked on the detection
+/// pool. Requests that are not paged are checked a batch at a time.
+const DETECTION_BATCH_SIZE: usize = MAX_PAGE_SIZE as usize;
 
 #[database("tags")]
 struct TagsDbConn(rusqlite::Connection);
 
-#[post("/new", format = "application/json", data = "<post_message_request>")]
-fn new(conn: TagsDbConn, post_message_request: Json<PostMessageRequest>) -> JsonValue {
-    match serde_json::to_string(&post_message_request.ciphertext) {
-        Ok(ciphertext) => {
-            match conn.0.execute(
+fn wire_content_type() -> ContentType {
+    ContentType::parse_flexible(wire::CONTENT_TYPE).unwrap()
+}
+
+// Read the body of a request in the wire encoding
+fn read_wire<T: Wire>(content_type: &ContentType, data: Data) -> Option<T> {
+    if content_type != &wire_content_type() {
+        return None;
+    }
+    let mut bytes = vec![];
+    data.open()
+        .take(MAX_WIRE_REQUEST_SIZE)
+        .read_to_end(&mut bytes)
+        .ok()?;
+    T::from_wire(&bytes)
+}
+
+// Messages are stored in the wire encoding, older databases may still contain JSON messages
+fn decode_message(message: Value) -> Option<TaggedCiphertext> {
+    match message {
+        Value::Blob(bytes) => TaggedCiphertext::from_bytes(&bytes),
+        Value::Text(json) => serde_json::from_str(json.as_str()).ok(),
+        _ => None,
+    }
+}
+
+fn store(
+    conn: &TagsDbConn,
+    detector: &Detector,
+    post_message_request: &PostMessageRequest,
+) -> Result<JsonValue, status::BadRequest<JsonValue>> {
+    // Every packet must be exactly the same size, otherwise the size of a packet would leak
+    // information about its contents (e.g. whether it is destined for a mix)
+    if post_message_request.ciphertext.is_fixed_size() == false {
+        return Err(status::BadRequest(Some(
+            json!({"tag" : "error", "reason" : "ciphertext is not a fixed size packet"}),
+        )));
+    }
+    // The detector keeps the tag of every message it stores, for detecting new messages
+    let stored = detector.insert(post_message_request.tag.clone(), || {
+        conn.0
+            .execute(
                 "INSERT INTO tags (tag, message) VALUES (?1, ?2);",
                 &[
                     &post_message_request.tag.compress() as &dyn ToSql,
-                    &ciphertext,
+                    &post_message_request.ciphertext.to_bytes(),
                 ],
-            ) {
-                Ok(_) => {
-                    json!({"tag" : post_message_request.tag.to_string()})
-                }
-                Err(_) => {
-                    json!({"tag" : "error"})
-                }
-            }
+            )
+            .map(|_| conn.0.last_insert_rowid())
+    });
+    Ok(match stored {
+        Ok(_) => {
+            json!({"tag" : post_message_request.tag.to_string()})
         }
-        _ => {
+        Err(_) => {
             json!({"tag" : "error"})
         }
-    }
+    })
 }
 
-#[post("/tags", format = "application/json", data = "<fetch_message_request>")]
-fn tags(conn: TagsDbConn, fetch_message_request: Json<FetchMessagesRequest>) -> JsonValue {
-    let mut detected_tags: Vec<(Tag<24>, TaggedCiphertext)> = vec![];
+// Messages are numbered by their id. AUTOINCREMENT never reuses an id, even once the message
+// with the highest id has been pruned, so ids can be used as a cursor by clients.
+fn cursor_expired(reason: &str) -> status::Custom<JsonValue> {
+    status::Custom(Status::Gone, json!({"tag" : "error", "reason" : reason}))
+}
 
+// The id of the newest message ever stored, even if it has since been pruned
+fn last_id(conn: &TagsDbConn) -> i64 {
+    conn.0
+        .query_row(
+            "SELECT seq FROM sqlite_sequence WHERE name='tags';",
+            &[],
+            |row| row.get(0),
+        )
+        .unwrap_or(0)
+}
+
+// The id of the oldest message that has not been pruned, or of the next message if every message
+// has been pruned
+fn first_id(conn: &TagsDbConn, last_id: i64) -> i64 {
+    let first_id: Option<i64> = conn
+        .0
+        .query_row("SELECT MIN(id) FROM tags;", &[], |row| row.get(0))
+        .unwrap_or(None);
+    first_id.unwrap_or(last_id + 1)
+}
+
+// Check up to `limit` of the messages with an id after `after`, and up to `until`, reading their
+// tags from the database
+fn detect_stored(
+    conn: &TagsDbConn,
+    detector: &Detector,
+    detection_key: &DetectionKey<24>,
+    after: i64,
+    until: i64,
+    limit: usize,
+) -> Checked {
     let mut select = conn
         .0
-        .prepare("SELECT tag,message FROM tags WHERE id>(SELECT id FROM tags WHERE tag=(?));")
+        .prepare("SELECT id,tag FROM tags WHERE id>(?) AND id<=(?) ORDER BY id LIMIT (?);")
         .unwrap();
+    // One more message than the limit is selected to tell if there are more
+    let selected = limit as i64 + 1;
+    let mut messages: Vec<(i64, Vec<u8>)> = select
+        .query_map(&[&after as &dyn ToSql, &until, &selected], |row| {
+            (row.get(0), row.get(1))
+        })
+        .unwrap()
+        .filter_map(|result| result.ok())
+        .collect();
+    let more = messages.len() > limit;
+    messages.truncate(limit);
+    Checked {
+        matches: detector.detect(detection_key, &messages),
+        count: messages.len(),
+        // Every message up to `until` has been checked unless we stopped at the limit
+        last: match more {
+            true => messages.last().map(|(id, _)| *id),
+            false => Some(until),
+        },
+        more,
+    }
+}
 
-    let mut select_all = conn.0.prepare("SELECT tag,message FROM tags;").unwrap();
-
-    let all = match &fetch_message_request.reference_tag {
-        Some(tag) => {
-            let mut stmt = conn
-                .0
-                .prepare("SELECT COUNT(*) FROM tags WHERE tag=(?);")
-                .unwrap();
-            let count = stmt.query_row(&[&tag.compress() as &dyn ToSql], |row| {
-                let count: i32 = row.get(0);
-                return count;
-            });
-            match count {
-                Ok(count) => count == 0,
-                _ => true,
-            }
-        }
-        None => true,
+fn detect(
+    conn: &TagsDbConn,
+    detector: &Detector,
+    fetch_message_request: &FetchMessagesRequest,
+) -> Result<DetectedTags, status::Custom<JsonValue>> {
+    let mut detected_tags: Vec<(Tag<24>, TaggedCiphertext)> = vec![];
+
+    let last_id = last_id(conn);
+    let first_id = first_id(conn, last_id);
+    // Tags are only kept for the messages that are still stored
+    detector.prune(first_id);
+
+    let reference_id = match &fetch_message_request.reference_tag {
+        Some(tag) => conn
+            .0
+            .query_row(
+                "SELECT id FROM tags WHERE tag=(?);",
+                &[&tag.compress() as &dyn ToSql],
+                |row| {
+                    let id: i64 = row.get(0);
+                    id
+                },
+            )
+            .ok(),
+        None => None,
     };
 
-    match all {
-        false => {
-            let ref_tag = fetch_message_request.reference_tag.clone().unwrap();
-            let selected_tags = select
-                .query_map(&[&ref_tag.compress() as &dyn ToSql], |row| {
-                    let tag_bytes: Vec<u8> = row.get(0);
-                    let tag = Tag::<24>::decompress(tag_bytes.as_slice()).unwrap();
-
-                    let ciphertext_json: String = row.get(1);
-                    let message: TaggedCiphertext =
-                        serde_json::from_str(ciphertext_json.as_str()).unwrap();
-                    (tag, message)
-                })
-                .unwrap();
-            for result in selected_tags {
-                match result {
-                    Ok((tag, ciphertext)) => {
-                        if fetch_message_request.detection_key.test_tag(&tag) {
-                            detected_tags.push((tag, ciphertext));
-                        }
-                    }
-                    _ => {}
-                }
-            }
+    let after = match (
+        fetch_message_request.after_sequence,
+        &fetch_message_request.reference_tag,
+        reference_id,
+    ) {
+        // Older clients without a cursor get every message if the reference tag is unknown
+        (None, _, reference_id) => reference_id.unwrap_or(0),
+        // Clients migrating to a cursor are told when the reference tag has been pruned
+        (Some(_), Some(_), None) => {
+            return Err(cursor_expired("the reference tag is no longer kept"))
         }
-        true => {
-            let selected_tags = select_all
-                .query_map(&[], |row| {
-                    let tag_bytes: Vec<u8> = row.get(0);
-                    let tag = Tag::<24>::decompress(tag_bytes.as_slice()).unwrap();
-
-                    let ciphertext_json: String = row.get(1);
-                    let message: TaggedCiphertext =
-                        serde_json::from_str(ciphertext_json.as_str()).unwrap();
-                    (tag, message)
-                })
-                .unwrap();
-            for result in selected_tags {
-                match result {
-                    Ok((tag, ciphertext)) => {
-                        if fetch_message_request.detection_key.test_tag(&tag) {
-                            detected_tags.push((tag, ciphertext));
-                        }
-                    }
-                    _ => {}
+        (Some(_), Some(_), Some(reference_id)) => reference_id,
+        (Some(after), None, _) => {
+            // A cursor of 0 asks for every message still kept, any other cursor expires once
+            // a message after it has been pruned
+            match i64::try_from(after) {
+                Ok(after) if after <= last_id && (after == 0 || first_id <= after + 1) => after,
+                _ => {
+                    return Err(cursor_expired(
+                        "messages after the cursor are no longer kept",
+                    ))
                 }
             }
         }
     };
 
-    json!({ "detected_tags": detected_tags })
+    // Only requests that can resume from a cursor are paged, the matches for a page are all that
+    // is held in memory
+    let limit = match fetch_message_request.after_sequence {
+        Some(_) => Some(
+            fetch_message_request
+                .limit
+                .filter(|limit| *limit != 0)
+                .map_or(MAX_PAGE_SIZE, |limit| limit.min(MAX_PAGE_SIZE)) as usize,
+        ),
+        None => None,
+    };
+    let until = fetch_message_request
+        .until_sequence
+        .and_then(|until| i64::try_from(until).ok())
+        .unwrap_or(i64::MAX)
+        .min(last_id);
+
+    // Messages are checked a batch at a time, from the tags the detector keeps where it can and
+    // from the database otherwise. The cursor is the last message checked, whether or not it
+    // matched.
+    let mut cursor = after;
+    let mut checked = 0;
+    let mut more = false;
+    let mut matches = vec![];
+    let mut read_stored = false;
+    while cursor < until {
+        let batch = limit.map_or(DETECTION_BATCH_SIZE, |limit| {
+            (limit - checked).min(DETECTION_BATCH_SIZE)
+        });
+        let detection_key = &fetch_message_request.detection_key;
+        let (page, kept) = match detector.detect_kept(detection_key, cursor, until, batch) {
+            Ok(page) => (page, true),
+            Err(stored_until) => {
+                read_stored = true;
+                let stored_until = stored_until.min(until);
+                (
+                    detect_stored(conn, detector, detection_key, cursor, stored_until, batch),
+                    false,
+                )
+            }
+        };
+        checked += page.count;
+        cursor = page.last.unwrap_or(cursor);
+        matches.extend(page.matches);
+        // Requests without a cursor are not paged, but are still sent at most a page of
+        // messages. They resume after the last one, as their reference tag.
+        if limit.is_none() && matches.len() > MAX_PAGE_SIZE as usize {
+            matches.truncate(MAX_PAGE_SIZE as usize);
+            more = true;
+            break;
+        }
+        if page.more && batch == 0 {
+            more = true;
+            break;
+        }
+        // Every message up to `until` has been checked, the window keeps all the newest tags
+        if kept && !page.more {
+            break;
+        }
+    }
+
+    // Only the messages of matching tags are read
+    let mut select_message = conn
+        .0
+        .prepare("SELECT message FROM tags WHERE id=(?);")
+        .unwrap();
+    let mut pruned = false;
+    for (id, tag) in matches {
+        match select_message.query_row(&[&id as &dyn ToSql], |row| decode_message(row.get(0))) {
+            Ok(Some(ciphertext)) => detected_tags.push((tag, ciphertext)),
+            Ok(None) => {}
+            Err(_) => pruned = true,
+        }
+    }
+
+    // The client would miss messages pruned while they were being checked: a matching message
+    // that could no longer be read, or any message read from the database after the cursor. The
+    // window keeps the tags of pruned messages until the next request, so those were checked.
+    if fetch_message_request.after_sequence.is_some()
+        && (pruned || (read_stored && after > 0 && first_id(conn, last_id) > after + 1))
+    {
+        return Err(cursor_expired(
+            "messages after the cursor are no longer kept",
+        ));
+    }
+
+    Ok(DetectedTags {
+        detected_tags,
+        cursor: fetch_message_request.after_sequence.map(|_| cursor as u64),
+        more,
+    })
+}
+
+#[post("/new", format = "application/json", data = "<post_message_request>")]
+fn new(
+    conn: TagsDbConn,
+    detector: State<Detector>,
+    post_message_request: Json<PostMessageRequest>,
+) -> Result<JsonValue, status::BadRequest<JsonValue>> {
+    store(&conn, &detector, &post_message_request)
+}
+
+#[post("/new", data = "<data>", rank = 2)]
+fn new_wire(
+    conn: TagsDbConn,
+    detector: State<Detector>,
+    content_type: &ContentType,
+    data: Data,
+) -> Result<JsonValue, status::BadRequest<JsonValue>> {
+    match read_wire::<PostMessageRequest>(content_type, data) {
+        Some(post_message_request) => store(&conn, &detector, &post_message_request),
+        None => Err(status::BadRequest(Some(
+            json!({"tag" : "error", "reason" : "malformed request"}),
+        ))),
+    }
+}
+
+#[post("/tags", format = "application/json", data = "<fetch_message_request>")]
+fn tags(
+    conn: TagsDbConn,
+    detector: State<Detector>,
+    fetch_message_request: Json<FetchMessagesRequest>,
+) -> Result<JsonValue, status::Custom<JsonValue>> {
+    let detected = detect(&conn, &detector, &fetch_message_request)?;
+    Ok(json!({
+        "detected_tags": detected.detected_tags,
+        "cursor": detected.cursor,
+        "more": detected.more,
+    }))
+}
+
+#[post("/tags", data = "<data>", rank = 2)]
+fn tags_wire(
+    conn: TagsDbConn,
+    detector: State<Detector>,
+    content_type: &ContentType,
+    data: Data,
+) -> Result<content::Content<Vec<u8>>, status::Custom<JsonValue>> {
+    match read_wire::<FetchMessagesRequest>(content_type, data) {
+        Some(fetch_message_request) => Ok(content::Content(
+            wire_content_type(),
+            detect(&conn, &detector, &fetch_message_request)?.to_wire(),
+        )),
+        None => Err(status::Custom(
+            Status::BadRequest,
+            json!({"tag" : "error", "reason" : "malformed request"}),
+        )),
+    }
 }
 
 fn main() {
+    // One detection thread per CPU
+    let detector =
+        Detector::new(0, TAG_WINDOW_SIZE).expect("could not start the detection threads");
     rocket::ignite()
         .attach(TagsDbConn::fairing())
-        .mount("/", routes![tags, new])
+        .manage(detector)
+        .mount("/", routes![tags, tags_wire, new, new_wire])
         .launch();
 }
@@ -0,0 +1,195 @@
+//! Seed phrases for backing up and recovering a profile.
+//!
+//! Every secret of a profile (the fuzzytags root secret, the private key and the identity key)
+//! is drawn from a ChaCha20 CSPRNG seeded from a BIP-39 mnemonic, so writing down the mnemonic
+//! is enough to recover the identity of a profile. Contacts are not derived from the seed, so
+//! they can be exported separately, encrypted under a key that is also derived from the seed.
+use crate::encrypt::PublicKey;
+use crate::{Contact, NiwlError};
+use bip39::{Language, Mnemonic, MnemonicType};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use fuzzytags::TaggingKey;
+use rand::rngs::OsRng;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::collections::HashMap;
+use std::fmt;
+use zeroize::{Zeroize, Zeroizing};
+
+const MAGIC: &[u8; 4] = b"NIWC";
+const EXPORT_VERSION: u8 = 1;
+const NONCE_SIZE: usize = 24;
+const HEADER_SIZE: usize = MAGIC.len() + 1 + NONCE_SIZE;
+
+/// The entropy of a BIP-39 mnemonic, from which every secret of a profile is derived
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Seed {
+    entropy: Vec<u8>,
+}
+
+impl Drop for Seed {
+    fn drop(&mut self) {
+        self.entropy.zeroize();
+    }
+}
+
+impl fmt::Debug for Seed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Seed([REDACTED])")
+    }
+}
+
+// The keys of a contact, without any session state - restoring an old session would reuse
+// message keys
+#[derive(Serialize)]
+struct ContactKeys<'a> {
+    petname: &'a String,
+    tagging_key: &'a TaggingKey<24>,
+    public_key: &'a PublicKey,
+    identity_key: &'a Option<PublicKey>,
+    verified: bool,
+    mix: bool,
+}
+
+fn backup_error(reason: &str) -> NiwlError {
+    NiwlError::BackupError(String::from(reason))
+}
+
+impl Seed {
+    /// Generate a new seed for a 24 word mnemonic
+    pub fn generate() -> Seed {
+        let mut entropy = vec![0u8; MnemonicType::Words24.entropy_bits() / 8];
+        OsRng.fill_bytes(&mut entropy);
+        Seed { entropy }
+    }
+
+    /// Recover a seed from its mnemonic, checking the checksum
+    pub fn from_phrase(phrase: &str) -> Result<Seed, NiwlError> {
+        let normalized = Zeroizing::new(phrase.split_whitespace().collect::<Vec<_>>().join(" "));
+        let mnemonic = Mnemonic::from_phrase(&normalized.to_lowercase(), Language::English)
+            .map_err(|err| NiwlError::BackupError(format!("invalid seed phrase : {}", err)))?;
+        Ok(Seed {
+            entropy: mnemonic.entropy().to_vec(),
+        })
+    }
+
+    /// The mnemonic for this seed
+    pub fn phrase(&self) -> Zeroizing<String> {
+        Zeroizing::new(self.mnemonic().phrase().to_string())
+    }
+
+    fn mnemonic(&self) -> Mnemonic {
+        Mnemonic::from_entropy(&self.entropy, Language::English).unwrap()
+    }
+
+    fn derive(&self, label: &[u8]) -> [u8; 32] {
+        let seed = bip39::Seed::new(&self.mnemonic(), "");
+        let mut hash = Sha3_256::new();
+        hash.update(label);
+        hash.update(seed.as_bytes());
+        hash.finalize().into()
+    }
+
+    /// The CSPRNG that the secrets of a profile are drawn from. Secrets must always be drawn
+    /// in the same order for a seed to recover the same profile.
+    pub(crate) fn rng(&self) -> ChaCha20Rng {
+        let mut seed = self.derive(b"niwl-profile-seed");
+        let rng = ChaCha20Rng::from_seed(seed);
+        seed.zeroize();
+        rng
+    }
+
+    fn contacts_cipher(&self) -> XChaCha20Poly1305 {
+        let mut key = self.derive(b"niwl-contacts-export");
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(&key));
+        key.zeroize();
+        cipher
+    }
+
+    /// Encrypt the keys of our contacts so they can be restored along with this seed
+    pub(crate) fn export_contacts(&self, contacts: &HashMap<String, Contact>) -> Vec<u8> {
+        let keys: HashMap<&String, ContactKeys> = contacts
+            .iter()
+            .map(|(id, contact)| {
+                (
+                    id,
+                    ContactKeys {
+                        petname: &contact.petname,
+                        tagging_key: &contact.tagging_key,
+                        public_key: &contact.public_key,
+                        identity_key: &contact.identity_key,
+                        verified: contact.verified,
+                        mix: contact.mix,
+                    },
+                )
+            })
+            .collect();
+        let json = Zeroizing::new(serde_json::to_vec(&keys).unwrap());
+
+        let mut export = MAGIC.to_vec();
+        export.push(EXPORT_VERSION);
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        export.extend_from_slice(&nonce);
+        let ciphertext = self
+            .contacts_cipher()
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &json,
+                    aad: &export,
+                },
+            )
+            .unwrap();
+        export.extend_from_slice(&ciphertext);
+        export
+    }
+
+    /// Decrypt contacts exported with this seed
+    pub(crate) fn import_contacts(
+        &self,
+        export: &[u8],
+    ) -> Result<HashMap<String, Contact>, NiwlError> {
+        if export.len() < HEADER_SIZE || !export.starts_with(MAGIC) {
+            return Err(backup_error("not a contacts export"));
+        }
+        let (header, ciphertext) = export.split_at(HEADER_SIZE);
+        if header[MAGIC.len()] != EXPORT_VERSION {
+            return Err(backup_error("unsupported contacts export version"));
+        }
+        let json = Zeroizing::new(
+            self.contacts_cipher()
+                .decrypt(
+                    XNonce::from_slice(&header[MAGIC.len() + 1..]),
+                    Payload {
+                        msg: ciphertext,
+                        aad: header,
+                    },
+                )
+                .map_err(|_| backup_error("contacts were exported from a different seed"))?,
+        );
+        serde_json::from_slice(&json).map_err(|_| backup_error("malformed contacts export"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backup::Seed;
+
+    #[test]
+    fn test_seed_phrase_round_trip() {
+        let seed = Seed::generate();
+        let phrase = seed.phrase();
+        assert_eq!(phrase.split(' ').count(), 24);
+
+        // Extra whitespace and capitals are forgiven, words outside the wordlist are not
+        let sloppy = format!("  {}\n", phrase.replace(' ', "   ").to_uppercase());
+        assert_eq!(Seed::from_phrase(&sloppy).unwrap().entropy, seed.entropy);
+        let misspelled = phrase.replacen(" ", "zzz ", 1);
+        assert!(Seed::from_phrase(&misspelled).is_err());
+        assert!(Seed::from_phrase("not a seed phrase").is_err());
+    }
+}
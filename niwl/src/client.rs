@@ -0,0 +1,517 @@
+//! A reusable connection to a niwl server.
+//!
+//! `NiwlClient` posts messages and detects the messages sent to a profile over a `Transport`,
+//! which is usually an `HttpTransport` talking to a niwl server. `HttpTransport` owns the HTTP
+//! client used to talk to the server, so connections are reused between requests. Requests time
+//! out rather than hanging forever, and requests that fail in a way that may be temporary (the
+//! server could not be reached, timed out or was overloaded) are retried with exponential
+//! backoff. The backoff is jittered, so that clients which lost their connection at the same
+//! time don't all retry at the same moment. Posting a message is only retried if the server
+//! could not be reached: a request that timed out or failed may still have been stored, and
+//! posting it again would leave a duplicate on the server.
+use crate::encrypt::TaggedCiphertext;
+use crate::transport::{Transport, TransportFuture};
+use crate::wire::{self, Wire};
+use crate::{DetectedTags, FetchMessagesRequest, NiwlError, Payload, PostMessageRequest, Profile};
+use rand::rngs::OsRng;
+use rand::Rng;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Proxy, Response, StatusCode};
+use std::time::Duration;
+
+/// How many times, and how quickly, requests that fail temporarily are retried
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// How many times to retry a request after it first fails
+    pub max_retries: u32,
+    /// The longest to wait before the first retry. The wait doubles with every retry.
+    pub initial_backoff: Duration,
+    /// The longest to wait before any retry
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// How long to wait before retry number `attempt` (counting from 0), chosen uniformly at
+    /// random up to the exponential backoff for that attempt
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .initial_backoff
+            .checked_mul(1 << attempt.min(31))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+        Duration::from_millis(OsRng.gen_range(0, ceiling.as_millis() as u64 + 1))
+    }
+}
+
+/// Builds an `HttpTransport`, or a `NiwlClient` using one, see `NiwlClient::builder`
+pub struct NiwlClientBuilder {
+    server: String,
+    connect_timeout: Duration,
+    timeout: Duration,
+    retry_policy: RetryPolicy,
+    proxy: Option<String>,
+}
+
+impl NiwlClientBuilder {
+    /// How long to wait for a connection to the server, 10 seconds by default
+    pub fn connect_timeout(mut self, timeout: Duration) -> NiwlClientBuilder {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// How long to wait for each attempt at a request to complete, 30 seconds by default
+    pub fn timeout(mut self, timeout: Duration) -> NiwlClientBuilder {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> NiwlClientBuilder {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Send every request through a proxy, e.g. "http://localhost:8118"
+    pub fn proxy(mut self, proxy: &str) -> NiwlClientBuilder {
+        self.proxy = Some(String::from(proxy));
+        self
+    }
+
+    pub fn build(self) -> Result<NiwlClient, NiwlError> {
+        Ok(NiwlClient::with_transport(self.build_transport()?))
+    }
+
+    pub fn build_transport(self) -> Result<HttpTransport, NiwlError> {
+        let mut http = reqwest::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.timeout);
+        if let Some(proxy) = &self.proxy {
+            http = http.proxy(Proxy::all(proxy.as_str()).map_err(|err| {
+                NiwlError::RemoteServerError(format!("invalid proxy {} : {}", proxy, err))
+            })?);
+        }
+        Ok(HttpTransport {
+            http: http.build()?,
+            server: self.server.trim_end_matches('/').to_string(),
+            retry_policy: self.retry_policy,
+        })
+    }
+}
+
+/// The transport to a niwl server over HTTP, in the wire encoding
+pub struct HttpTransport {
+    http: reqwest::Client,
+    server: String,
+    retry_policy: RetryPolicy,
+}
+
+impl HttpTransport {
+    // Post a request, retrying it if it fails in a way that may be temporary. Unless the request
+    // is `idempotent` it is only retried when it cannot have reached the server.
+    async fn send(
+        &self,
+        path: &str,
+        body: Vec<u8>,
+        idempotent: bool,
+    ) -> Result<Response, NiwlError> {
+        let url = format!("{}/{}", self.server, path);
+        let mut attempt = 0;
+        loop {
+            let result = self
+                .http
+                .post(&url)
+                .header(CONTENT_TYPE, wire::CONTENT_TYPE)
+                .body(body.clone())
+                .send()
+                .await;
+            let temporary = match &result {
+                Ok(response) => {
+                    idempotent
+                        && (response.status().is_server_error()
+                            || response.status() == StatusCode::TOO_MANY_REQUESTS)
+                }
+                Err(err) => err.is_connect() || (idempotent && err.is_timeout()),
+            };
+            if !temporary || attempt >= self.retry_policy.max_retries {
+                return check_status(result?).await;
+            }
+            tokio::time::sleep(self.retry_policy.backoff(attempt)).await;
+            attempt += 1;
+        }
+    }
+}
+
+impl Transport for HttpTransport {
+    fn server(&self) -> &str {
+        &self.server
+    }
+
+    fn post(&self, request: PostMessageRequest) -> TransportFuture<'_, ()> {
+        Box::pin(async move {
+            let bytes = self
+                .send("new", request.to_wire(), false)
+                .await?
+                .bytes()
+                .await?;
+            // Servers acknowledge a message with its tag, or "error" if it could not be stored
+            let acknowledgement: serde_json::Value =
+                serde_json::from_slice(&bytes).map_err(|_| {
+                    NiwlError::ProtocolError(String::from("could not decode the acknowledgement"))
+                })?;
+            match acknowledgement["tag"].as_str() {
+                Some("error") | None => Err(NiwlError::RemoteServerError(String::from(
+                    "the server could not store the message",
+                ))),
+                Some(_) => Ok(()),
+            }
+        })
+    }
+
+    fn fetch(&self, request: FetchMessagesRequest) -> TransportFuture<'_, DetectedTags> {
+        Box::pin(async move {
+            // Servers answer 410 Gone once they have pruned messages after our cursor
+            let response =
+                self.send("tags", request.to_wire(), true)
+                    .await
+                    .map_err(|err| match err {
+                        NiwlError::HttpStatusError(410, reason) => {
+                            NiwlError::CursorExpiredError(reason)
+                        }
+                        err => err,
+                    })?;
+            let bytes = response.bytes().await?;
+            DetectedTags::from_wire(&bytes).ok_or(NiwlError::ProtocolError(String::from(
+                "could not decode the detected tags",
+            )))
+        })
+    }
+}
+
+/// Posts messages and detects the messages sent to a profile, over any `Transport`
+pub struct NiwlClient {
+    transport: Box<dyn Transport>,
+}
+
+impl NiwlClient {
+    /// A client for the server at `server` e.g. "http://localhost:8000", with the default
+    /// timeouts and retry policy
+    pub fn new(server: &str) -> Result<NiwlClient, NiwlError> {
+        NiwlClient::builder(server).build()
+    }
+
+    pub fn builder(server: &str) -> NiwlClientBuilder {
+        NiwlClientBuilder {
+            server: String::from(server),
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            retry_policy: RetryPolicy::default(),
+            proxy: None,
+        }
+    }
+
+    /// A client using a transport other than HTTP, e.g. an in-memory `BulletinBoard`
+    pub fn with_transport<T: Transport + 'static>(transport: T) -> NiwlClient {
+        NiwlClient {
+            transport: Box::new(transport),
+        }
+    }
+
+    /// Identifies the server we talk to, for `Profile::update_cursor`
+    pub fn server(&self) -> &str {
+        self.transport.server()
+    }
+
+    /// Post a message we have already encrypted
+    pub async fn forward(&self, message: &TaggedCiphertext) -> Result<(), NiwlError> {
+        let request = PostMessageRequest {
+            tag: message.tag.clone(),
+            ciphertext: message.clone(),
+        };
+        self.transport.post(request).await
+    }
+
+    pub async fn send_to_self(&self, profile: &Profile, message: &[u8]) -> Result<(), NiwlError> {
+        let ciphertext = profile.encrypt_to_self(message)?;
+        self.forward(&ciphertext).await
+    }
+
+    pub async fn tag_and_send(
+        &self,
+        profile: &Profile,
+        contact: String,
+        payload: &Payload,
+        authenticated: bool,
+    ) -> Result<(), NiwlError> {
+        self.tag_and_route(profile, &[], contact, payload, authenticated)
+            .await
+    }
+
+    pub async fn tag_and_mix(
+        &self,
+        profile: &Profile,
+        mix: String,
+        contact: String,
+        payload: &Payload,
+        authenticated: bool,
+    ) -> Result<(), NiwlError> {
+        self.tag_and_route(profile, &[mix], contact, payload, authenticated)
+            .await
+    }
+
+    pub async fn tag_and_route(
+        &self,
+        profile: &Profile,
+        route: &[String],
+        contact: String,
+        payload: &Payload,
+        authenticated: bool,
+    ) -> Result<(), NiwlError> {
+        let ciphertext = profile.wrap_route(route, &contact, payload, authenticated)?;
+        self.forward(&ciphertext).await
+    }
+
+    pub async fn tag_and_route_sphinx(
+        &self,
+        profile: &Profile,
+        route: &[String],
+        contact: String,
+        payload: &Payload,
+        authenticated: bool,
+    ) -> Result<(), NiwlError> {
+        let ciphertext = profile.sphinx_route(route, &contact, payload, authenticated)?;
+        self.forward(&ciphertext).await
+    }
+
+    /// Reply using a SURB we have received. The SURB is forgotten once used, even if posting
+    /// the reply fails.
+    pub async fn send_reply(
+        &self,
+        profile: &mut Profile,
+        id: &String,
+        payload: &Payload,
+    ) -> Result<(), NiwlError> {
+        let packet = profile.reply(id, payload)?;
+        self.forward(&packet).await
+    }
+
+    /// Fetch a page of the tags matching each of our detection keys, including those of retired
+    /// keys, posted since the cursor the profile holds for this server. Tags for retired keys
+    /// come first, so the last tag is the newest for our current key. Once the messages have been
+    /// handled, the returned cursor should be stored with `Profile::update_cursor`, and if
+    /// `more` is set, the next page fetched.
+    pub async fn detect_tags(&self, profile: &Profile) -> Result<DetectedTags, NiwlError> {
+        let mut detected_tags = vec![];
+        // The cursor and `more` of the page fetched for the first key
+        let mut page: Option<(u64, bool)> = None;
+        for detection_key in profile.detection_keys() {
+            let mut request = profile.fetch_request(self.server(), detection_key);
+            match page {
+                // There were no messages to check, for any key
+                Some((0, _)) => break,
+                // Messages may arrive between fetches, so the page of the first key bounds the
+                // others, and every key checks the same messages
+                Some((cursor, _)) => request.until_sequence = Some(cursor),
+                None => {}
+            }
+            let detected = self.transport.fetch(request).await?;
+            let cursor = detected
+                .cursor
+                .ok_or(NiwlError::ProtocolError(String::from(
+                    "the server did not return a cursor",
+                )))?;
+            page.get_or_insert((cursor, detected.more));
+            detected_tags.extend(detected.detected_tags);
+        }
+        Ok(DetectedTags {
+            detected_tags,
+            cursor: page.map(|(cursor, _)| cursor),
+            more: page.map_or(false, |(_, more)| more),
+        })
+    }
+}
+
+/// Turn an unsuccessful response from a server into an error
+async fn check_status(response: Response) -> Result<Response, NiwlError> {
+    let status = response.status();
+    match status.is_success() {
+        true => Ok(response),
+        false => Err(NiwlError::HttpStatusError(
+            status.as_u16(),
+            response.text().await.unwrap_or_default(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::{HttpTransport, NiwlClient, RetryPolicy};
+    use crate::NiwlError;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // Answer one request per status, in order, and return the url of the server
+    fn serve(statuses: Vec<u16>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0u8; 4096];
+                let _ = stream.read(&mut request);
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                    status
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        url
+    }
+
+    // Accept every request and answer each one after `delay`, counting the requests received
+    fn serve_slowly(delay: Duration) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                std::thread::spawn(move || {
+                    let mut request = [0u8; 4096];
+                    let _ = stream.read(&mut request);
+                    std::thread::sleep(delay);
+                    let response =
+                        "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+                    let _ = stream.write_all(response.as_bytes());
+                });
+            }
+        });
+        (url, received)
+    }
+
+    fn send(transport: &HttpTransport, path: &str, idempotent: bool) -> Result<u16, NiwlError> {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let response = transport.send(path, vec![], idempotent).await?;
+                Ok(response.status().as_u16())
+            })
+    }
+
+    fn fetch(transport: &HttpTransport) -> Result<u16, NiwlError> {
+        send(transport, "tags", true)
+    }
+
+    fn post(transport: &HttpTransport) -> Result<u16, NiwlError> {
+        send(transport, "new", false)
+    }
+
+    #[test]
+    fn test_backoff_is_bounded() {
+        let policy = RetryPolicy {
+            max_retries: 40,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        };
+        for attempt in 0..40 {
+            assert!(policy.backoff(attempt) <= Duration::from_secs(1));
+        }
+        assert!(policy.backoff(0) <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_temporary_failures_are_retried() {
+        let policy = RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+        };
+        let transport = NiwlClient::builder(&serve(vec![503, 429, 200]))
+            .retry_policy(policy.clone())
+            .build_transport()
+            .unwrap();
+        assert_eq!(fetch(&transport).unwrap(), 200);
+
+        // Once retries run out the last failure is returned
+        let transport = NiwlClient::builder(&serve(vec![503, 503, 503]))
+            .retry_policy(policy.clone())
+            .build_transport()
+            .unwrap();
+        assert!(matches!(
+            fetch(&transport),
+            Err(NiwlError::HttpStatusError(503, _))
+        ));
+
+        // Requests the server rejected are not retried
+        let transport = NiwlClient::builder(&serve(vec![400, 200]))
+            .retry_policy(policy.clone())
+            .build_transport()
+            .unwrap();
+        assert!(matches!(
+            fetch(&transport),
+            Err(NiwlError::HttpStatusError(400, _))
+        ));
+
+        // A message the server failed to handle may have been stored, so it is not posted again
+        let transport = NiwlClient::builder(&serve(vec![503, 200]))
+            .retry_policy(policy)
+            .build_transport()
+            .unwrap();
+        assert!(matches!(
+            post(&transport),
+            Err(NiwlError::HttpStatusError(503, _))
+        ));
+    }
+
+    #[test]
+    fn test_timed_out_posts_are_not_retried() {
+        let policy = RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+        };
+        // The server receives the message, but answers after the client has given up
+        let (url, received) = serve_slowly(Duration::from_millis(500));
+        let transport = NiwlClient::builder(&url)
+            .timeout(Duration::from_millis(100))
+            .retry_policy(policy.clone())
+            .build_transport()
+            .unwrap();
+        assert!(post(&transport).is_err());
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+
+        // Fetching has no side effects, so it is retried
+        let (url, received) = serve_slowly(Duration::from_millis(500));
+        let transport = NiwlClient::builder(&url)
+            .timeout(Duration::from_millis(100))
+            .retry_policy(policy)
+            .build_transport()
+            .unwrap();
+        assert!(fetch(&transport).is_err());
+        assert_eq!(received.load(Ordering::SeqCst), 3);
+    }
+}
@@ -1,77 +1,358 @@
+use crate::NiwlError;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
 use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
 use curve25519_dalek::digest::Digest;
-use curve25519_dalek::ristretto::RistrettoPoint;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
 use curve25519_dalek::scalar::Scalar;
 use fuzzytags::Tag;
 use rand::rngs::OsRng;
+use rand::{CryptoRng, RngCore};
 use secretbox::CipherType::Salsa20;
 use secretbox::SecretBox;
 use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fmt;
 use std::ops::Mul;
+use zeroize::{Zeroize, Zeroizing};
+
+/// The size of a compressed Tag<24>: two 32 byte group elements plus 24 bits of ciphertext
+pub const TAG_SIZE: usize = 64 + 24 / 8;
+
+/// The largest message that can be encrypted into a single TaggedCiphertext
+pub const MAX_MESSAGE_LENGTH: usize = 1024;
+
+/// The original ciphertext format: a message padded with spaces and sealed with xsalsa20
+/// secretbox, without a header. Still accepted when decrypting, as an anonymous message.
+pub const LEGACY_VERSION: u8 = 0;
+
+/// The current ciphertext format, sealed with XChaCha20-Poly1305 using the version and the
+/// compressed tag as associated data.
+pub const CURRENT_VERSION: u8 = 1;
+
+/// A Sphinx mix packet (see `niwl::sphinx`). These are not decrypted directly, they are
+/// processed by a mix into the packet for the next hop.
+pub const SPHINX_VERSION: u8 = 2;
+
+/// Size of the authenticator appended to every sealed box (the same for both versions)
+const MAC_SIZE: usize = 16;
+
+/// Size of the little-endian length prefix sealed into the header of every ciphertext
+const LENGTH_PREFIX_SIZE: usize = 4;
+
+/// Size of the sender field sealed into the header: a flag byte followed by a compressed identity key
+const SENDER_SIZE: usize = 1 + 32;
+
+/// The sealed header holds the length of the sealed message that follows it, and the identity
+/// key of the sender if the message is authenticated.
+const HEADER_SIZE: usize = LENGTH_PREFIX_SIZE + SENDER_SIZE + MAC_SIZE;
+
+/// Every TaggedCiphertext posted to a niwl server carries a ciphertext of exactly this size:
+/// a sealed header, a sealed message and (for messages shorter than MAX_MESSAGE_LENGTH) random filler.
+pub const CIPHERTEXT_SIZE: usize = HEADER_SIZE + MAX_MESSAGE_LENGTH + MAC_SIZE;
+
+/// The number of bytes a compact TaggedCiphertext adds on top of its message when it is
+/// itself used as the message of another TaggedCiphertext (e.g. when wrapping for a mix).
+pub const PACKET_OVERHEAD: usize = 1 + TAG_SIZE + 32 + HEADER_SIZE + MAC_SIZE;
 
 /// TaggedCiphertext is a wrapper around a Tag and an encrypted payload (in addition to a
 /// nonce value).
 #[derive(Serialize, Deserialize, Clone)]
 pub struct TaggedCiphertext {
+    // Ciphertexts serialized before versioning was introduced are LEGACY_VERSION
+    #[serde(default)]
+    pub(crate) version: u8,
     pub tag: Tag<24>,
-    nonce: RistrettoPoint,
-    ciphertext: Vec<u8>,
+    pub(crate) nonce: RistrettoPoint,
+    pub(crate) ciphertext: Vec<u8>,
 }
 
-/// A Private Key used when encrypting to a niwl client
+/// A Private Key used when encrypting to a niwl client. The key is wiped from memory when it is
+/// dropped, and is never shown by Debug.
 #[derive(Serialize, Deserialize)]
-pub struct PrivateKey(Scalar);
+pub struct PrivateKey(pub(crate) Scalar);
+
+impl Drop for PrivateKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PrivateKey([REDACTED])")
+    }
+}
 
 /// A Public Key derived from a niwl PrivateKey
-#[derive(Serialize, Deserialize)]
-pub struct PublicKey(RistrettoPoint);
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct PublicKey(pub(crate) RistrettoPoint);
+
+/// A Schnorr signature made with a PrivateKey
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Signature {
+    r: RistrettoPoint,
+    s: Scalar,
+}
+
+/// The origin of a decrypted message as asserted by its ciphertext
+pub enum Sender {
+    /// The message was encrypted with only an ephemeral key, anyone could have sent it
+    Anonymous,
+    /// The message could only have been encrypted by the holder of this identity key
+    Authenticated(PublicKey),
+}
+
+// Derive a (public) nonce by hashing the public z parameter with the tag. The label separates
+// the nonce used for the header from the nonce used for the message.
+fn derive_nonce(z: &RistrettoPoint, tag: &Tag<24>, label: &[u8]) -> [u8; 24] {
+    let mut nonce_hash = sha3::Sha3_256::new();
+    nonce_hash.update(label);
+    nonce_hash.update(z.compress().as_bytes());
+    nonce_hash.update(tag.compress());
+    let mut nonce = [0u8; 24];
+    nonce[..].copy_from_slice(&nonce_hash.finalize()[0..24]);
+    nonce
+}
+
+/// The symmetric construction used to seal the header and message of a particular
+/// ciphertext version.
+enum MessageKey {
+    Legacy(SecretBox),
+    Aead(XChaCha20Poly1305, Vec<u8>),
+}
+
+impl MessageKey {
+    // Derive the key from one or more shared diffie-hellman points and the tag
+    fn derive(version: u8, shared: &[RistrettoPoint], tag: &Tag<24>) -> Option<MessageKey> {
+        let mut hash = sha3::Sha3_256::new();
+        for point in shared {
+            let mut compressed = point.compress();
+            hash.update(compressed.as_bytes());
+            compressed.zeroize();
+        }
+        hash.update(tag.compress());
+        let key = Zeroizing::new(hash.finalize().to_vec());
+        match version {
+            LEGACY_VERSION => Some(MessageKey::Legacy(
+                SecretBox::new(key.to_vec(), Salsa20).unwrap(),
+            )),
+            CURRENT_VERSION => {
+                // Bind both the version and the tag to every sealed box
+                let mut associated_data = vec![version];
+                associated_data.extend_from_slice(&tag.compress());
+                Some(MessageKey::Aead(
+                    XChaCha20Poly1305::new(Key::from_slice(&key)),
+                    associated_data,
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    fn seal(&self, plaintext: &[u8], nonce: [u8; 24]) -> Vec<u8> {
+        match self {
+            MessageKey::Legacy(secret_box) => secret_box.seal(plaintext, nonce),
+            MessageKey::Aead(cipher, associated_data) => cipher
+                .encrypt(
+                    XNonce::from_slice(&nonce),
+                    Payload {
+                        msg: plaintext,
+                        aad: associated_data,
+                    },
+                )
+                .unwrap(),
+        }
+    }
+
+    fn open(&self, ciphertext: &[u8], nonce: [u8; 24]) -> Option<Vec<u8>> {
+        match self {
+            MessageKey::Legacy(secret_box) => secret_box.unseal(ciphertext, nonce),
+            MessageKey::Aead(cipher, associated_data) => cipher
+                .decrypt(
+                    XNonce::from_slice(&nonce),
+                    Payload {
+                        msg: ciphertext,
+                        aad: associated_data,
+                    },
+                )
+                .ok(),
+        }
+    }
+}
+
+impl TaggedCiphertext {
+    /// Returns true if this ciphertext is exactly the size of a niwl packet, i.e. it is
+    /// acceptable to post to a niwl server.
+    pub fn is_fixed_size(&self) -> bool {
+        self.ciphertext.len() == CIPHERTEXT_SIZE
+    }
+
+    /// Extend a compact ciphertext with random filler so that it is exactly CIPHERTEXT_SIZE
+    /// bytes. Mixes call this on the inner packet before forwarding it.
+    pub fn pad(&mut self) {
+        if self.ciphertext.len() < CIPHERTEXT_SIZE {
+            let mut filler = vec![0u8; CIPHERTEXT_SIZE - self.ciphertext.len()];
+            OsRng::default().fill_bytes(&mut filler);
+            self.ciphertext.extend_from_slice(&filler);
+        }
+    }
+
+    /// The format version of this ciphertext
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Serialize to version || tag || nonce || ciphertext
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(1 + TAG_SIZE + 32 + self.ciphertext.len());
+        bytes.push(self.version);
+        bytes.extend_from_slice(&self.tag.compress());
+        bytes.extend_from_slice(self.nonce.compress().as_bytes());
+        bytes.extend_from_slice(&self.ciphertext);
+        bytes
+    }
+
+    /// Deserialize a (compact or padded) ciphertext produced by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Option<TaggedCiphertext> {
+        if bytes.len() < PACKET_OVERHEAD || bytes.len() > 1 + TAG_SIZE + 32 + CIPHERTEXT_SIZE {
+            return None;
+        }
+        let version = bytes[0];
+        if version > SPHINX_VERSION {
+            return None;
+        }
+        let tag = Tag::<24>::decompress(&bytes[1..1 + TAG_SIZE])?;
+        let nonce = CompressedRistretto::from_slice(&bytes[1 + TAG_SIZE..1 + TAG_SIZE + 32])
+            .decompress()?;
+        Some(TaggedCiphertext {
+            version,
+            tag,
+            nonce,
+            ciphertext: bytes[1 + TAG_SIZE + 32..].to_vec(),
+        })
+    }
+}
+
+fn signature_challenge(r: &RistrettoPoint, public_key: &PublicKey, message: &[u8]) -> Scalar {
+    let mut hash = sha3::Sha3_512::new();
+    hash.update(b"niwl-signature");
+    hash.update(r.compress().as_bytes());
+    hash.update(public_key.0.compress().as_bytes());
+    hash.update(message);
+    Scalar::from_hash(hash)
+}
 
 impl PublicKey {
+    /// Check a signature made with the private key for this public key
+    pub fn verify(&self, message: &[u8], signature: &Signature) -> bool {
+        let c = signature_challenge(&signature.r, self, message);
+        RISTRETTO_BASEPOINT_POINT.mul(signature.s) == signature.r + self.0.mul(c)
+    }
+
     /// Encrypt to Tag provides uni-directional encrypted
-    pub fn encrypt(&self, tag: &Tag<24>, message: &String) -> TaggedCiphertext {
-        let mut padded_message = message.clone();
-        if message.len() < 1024 {
-            for _i in message.len()..1024 {
-                padded_message += " "
-            }
+    pub fn encrypt(&self, tag: &Tag<24>, message: &String) -> Result<TaggedCiphertext, NiwlError> {
+        self.encrypt_bytes(tag, message.as_bytes())
+    }
+
+    /// Encrypt an arbitrary binary payload to a Tag. The resulting ciphertext is always exactly
+    /// CIPHERTEXT_SIZE bytes, regardless of the length of the message.
+    pub fn encrypt_bytes(
+        &self,
+        tag: &Tag<24>,
+        message: &[u8],
+    ) -> Result<TaggedCiphertext, NiwlError> {
+        let mut ciphertext = self.encrypt_bytes_compact(tag, message, None)?;
+        ciphertext.pad();
+        Ok(ciphertext)
+    }
+
+    /// Encrypt an arbitrary binary payload to a Tag such that the recipient can verify it was
+    /// sent by the holder of `identity`.
+    pub fn encrypt_authenticated(
+        &self,
+        tag: &Tag<24>,
+        message: &[u8],
+        identity: &PrivateKey,
+    ) -> Result<TaggedCiphertext, NiwlError> {
+        let mut ciphertext = self.encrypt_bytes_compact(tag, message, Some(identity))?;
+        ciphertext.pad();
+        Ok(ciphertext)
+    }
+
+    /// Encrypt an arbitrary binary payload to a Tag without appending filler. Compact ciphertexts
+    /// are only suitable for embedding in another message and must be padded before being posted.
+    ///
+    /// If an identity is given then the message key is additionally derived from a static-static
+    /// diffie-hellman between the identity and this public key, and the identity public key is
+    /// sealed into the header so the recipient can authenticate the sender.
+    pub fn encrypt_bytes_compact(
+        &self,
+        tag: &Tag<24>,
+        message: &[u8],
+        identity: Option<&PrivateKey>,
+    ) -> Result<TaggedCiphertext, NiwlError> {
+        if message.len() > MAX_MESSAGE_LENGTH {
+            return Err(NiwlError::MessageTooLongError(format!(
+                "message is {} bytes, the maximum is {} bytes",
+                message.len(),
+                MAX_MESSAGE_LENGTH
+            )));
         }
 
         // Generate a random point. We will use the public part as a nonce
         // And the private part to generate a key.
         let mut rng = OsRng::default();
-        let r = Scalar::random(&mut rng);
+        let mut r = Scalar::random(&mut rng);
         let z = RISTRETTO_BASEPOINT_POINT.mul(r);
 
-        // Compile our (public) nonce...we derive a new random nonce by hashing
-        // the public z parameter with the tag.
-        let mut nonce_hash = sha3::Sha3_256::new();
-        nonce_hash.update(z.compress().as_bytes());
-        nonce_hash.update(tag.compress());
-        let mut nonce = [0u8; 24];
-        nonce[..].copy_from_slice(&nonce_hash.finalize().as_slice()[0..24]);
+        // Calculate the key by multiplying part of the tagging key by our private 'r'. The shared
+        // points are wiped on drop, with room reserved for both so no copy is left by a realloc.
+        let mut shared = Zeroizing::new(Vec::with_capacity(2));
+        shared.push(self.0.mul(r));
+        r.zeroize();
+        let header_key = MessageKey::derive(CURRENT_VERSION, &shared, tag).unwrap();
 
-        // Calculate the key by multiplying part of the tagging key by our private 'r'
-        let mut hash = sha3::Sha3_256::new();
-        hash.update(self.0.mul(r).compress().as_bytes());
-        hash.update(tag.compress());
-        let key = hash.finalize().to_vec();
-        let secret_box = SecretBox::new(key, Salsa20).unwrap();
+        // The header tells the recipient where the sealed message ends and the filler begins,
+        // and who (if anyone) is claiming to have sent the message.
+        let mut header = [0u8; LENGTH_PREFIX_SIZE + SENDER_SIZE];
+        header[0..LENGTH_PREFIX_SIZE].copy_from_slice(&(message.len() as u32).to_le_bytes());
+        if let Some(identity) = identity {
+            header[LENGTH_PREFIX_SIZE] = 1;
+            header[LENGTH_PREFIX_SIZE + 1..]
+                .copy_from_slice(identity.public_key().0.compress().as_bytes());
+            shared.push(self.0.mul(identity.0));
+        }
+        let message_key = MessageKey::derive(CURRENT_VERSION, &shared, tag).unwrap();
 
-        // TODO: Fixed Size Packets
-        let ciphertext = secret_box.seal(padded_message.as_bytes(), nonce);
-        TaggedCiphertext {
+        let mut ciphertext = header_key.seal(&header, derive_nonce(&z, tag, b"header"));
+        ciphertext.extend_from_slice(&message_key.seal(message, derive_nonce(&z, tag, b"message")));
+        Ok(TaggedCiphertext {
+            version: CURRENT_VERSION,
             tag: tag.clone(),
             nonce: z,
             ciphertext,
-        }
+        })
     }
 }
 
 impl PrivateKey {
     pub fn generate() -> PrivateKey {
-        let mut rng = OsRng::default();
-        let r = Scalar::random(&mut rng);
-        PrivateKey { 0: r }
+        PrivateKey::from_rng(&mut OsRng::default())
+    }
+
+    /// Draw a private key from `rng`, e.g. one seeded for deterministic recovery
+    pub fn from_rng<R: RngCore + CryptoRng>(rng: &mut R) -> PrivateKey {
+        PrivateKey(Scalar::random(rng))
+    }
+
+    /// Derive another private key from this one, so that it is recovered along with this key.
+    /// Different labels give unrelated keys.
+    pub(crate) fn derive(&self, label: &[u8]) -> PrivateKey {
+        let mut hash = sha3::Sha3_512::new();
+        hash.update(label);
+        hash.update(self.0.as_bytes());
+        PrivateKey(Scalar::from_hash(hash))
     }
 
     pub fn public_key(&self) -> PublicKey {
@@ -80,37 +361,150 @@ impl PrivateKey {
         }
     }
 
-    /// Decrypt a tagged ciphertext
+    /// Sign a message. The nonce is derived from the key, the message and fresh randomness, so
+    /// a weak random number generator alone cannot leak the key.
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        let mut randomness = [0u8; 32];
+        OsRng::default().fill_bytes(&mut randomness);
+        let mut nonce_hash = sha3::Sha3_512::new();
+        nonce_hash.update(b"niwl-signature-nonce");
+        nonce_hash.update(self.0.as_bytes());
+        nonce_hash.update(&randomness);
+        nonce_hash.update(message);
+        let mut k = Scalar::from_hash(nonce_hash);
+        let r = RISTRETTO_BASEPOINT_POINT.mul(k);
+        let c = signature_challenge(&r, &self.public_key(), message);
+        let signature = Signature {
+            r,
+            s: k + c * self.0,
+        };
+        k.zeroize();
+        signature
+    }
+
+    /// Decrypt a tagged ciphertext containing a utf-8 message
     pub fn decrypt(&self, ciphertext: &TaggedCiphertext) -> Option<String> {
-        // Derive the public nonce...
-        let mut nonce_hash = sha3::Sha3_256::new();
-        nonce_hash.update(ciphertext.nonce.compress().as_bytes());
-        nonce_hash.update(ciphertext.tag.compress());
-        let mut nonce = [0u8; 24];
-        nonce[..].copy_from_slice(&nonce_hash.finalize().as_slice()[0..24]);
+        match self.decrypt_bytes(ciphertext) {
+            Some(plaintext) => String::from_utf8(plaintext).ok(),
+            None => None,
+        }
+    }
 
-        // Calculate the key by multiplying the public point with our private 'x'
-        let mut hash = sha3::Sha3_256::new();
-        hash.update(ciphertext.nonce.mul(self.0).compress().as_bytes());
-        hash.update(ciphertext.tag.compress());
-        let key = hash.finalize().to_vec();
-
-        let secret_box = SecretBox::new(key, Salsa20).unwrap();
-        match secret_box.unseal(ciphertext.ciphertext.as_slice(), nonce) {
-            Some(plaintext) => match String::from_utf8(plaintext) {
-                Ok(plaintext) => Some(String::from(plaintext.trim_end())),
-                Err(_) => None,
-            },
+    /// Decrypt a tagged ciphertext and return the exact binary payload it was created with
+    pub fn decrypt_bytes(&self, ciphertext: &TaggedCiphertext) -> Option<Vec<u8>> {
+        match self.decrypt_with_sender(ciphertext) {
+            Some((_, plaintext)) => Some(plaintext),
             None => None,
         }
     }
+
+    /// Decrypt a tagged ciphertext, returning the binary payload along with the sender of the
+    /// message if it was authenticated.
+    pub fn decrypt_with_sender(&self, ciphertext: &TaggedCiphertext) -> Option<(Sender, Vec<u8>)> {
+        if ciphertext.version == LEGACY_VERSION {
+            return self.decrypt_legacy(ciphertext);
+        }
+        if ciphertext.ciphertext.len() < HEADER_SIZE {
+            return None;
+        }
+
+        // Calculate the key by multiplying the public point with our private 'x'
+        let ephemeral = ciphertext.nonce.mul(self.0);
+        let header_key = MessageKey::derive(ciphertext.version, &[ephemeral], &ciphertext.tag)?;
+
+        let header = header_key.open(
+            &ciphertext.ciphertext[0..HEADER_SIZE],
+            derive_nonce(&ciphertext.nonce, &ciphertext.tag, b"header"),
+        )?;
+        if header.len() != LENGTH_PREFIX_SIZE + SENDER_SIZE {
+            return None;
+        }
+        let length = u32::from_le_bytes(header[0..LENGTH_PREFIX_SIZE].try_into().unwrap()) as usize;
+        if length > MAX_MESSAGE_LENGTH
+            || HEADER_SIZE + length + MAC_SIZE > ciphertext.ciphertext.len()
+        {
+            return None;
+        }
+
+        let (sender, shared) = match header[LENGTH_PREFIX_SIZE] {
+            0 => (Sender::Anonymous, vec![ephemeral]),
+            1 => {
+                let identity = CompressedRistretto::from_slice(&header[LENGTH_PREFIX_SIZE + 1..])
+                    .decompress()?;
+                (
+                    Sender::Authenticated(PublicKey(identity)),
+                    vec![ephemeral, identity.mul(self.0)],
+                )
+            }
+            _ => return None,
+        };
+        let message_key = MessageKey::derive(ciphertext.version, &shared, &ciphertext.tag)?;
+
+        // Anything after the sealed message is filler
+        let message = message_key.open(
+            &ciphertext.ciphertext[HEADER_SIZE..HEADER_SIZE + length + MAC_SIZE],
+            derive_nonce(&ciphertext.nonce, &ciphertext.tag, b"message"),
+        )?;
+        Some((sender, message))
+    }
+
+    // Ciphertexts from before versioning seal the whole padded message in one box, with a nonce
+    // derived without a label
+    fn decrypt_legacy(&self, ciphertext: &TaggedCiphertext) -> Option<(Sender, Vec<u8>)> {
+        let ephemeral = ciphertext.nonce.mul(self.0);
+        let message_key = MessageKey::derive(LEGACY_VERSION, &[ephemeral], &ciphertext.tag)?;
+        let mut message = message_key.open(
+            &ciphertext.ciphertext,
+            derive_nonce(&ciphertext.nonce, &ciphertext.tag, b""),
+        )?;
+        while message
+            .last()
+            .map_or(false, |byte| byte.is_ascii_whitespace())
+        {
+            message.pop();
+        }
+        Some((Sender::Anonymous, message))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::encrypt::PrivateKey;
-    use fuzzytags::RootSecret;
+    use crate::encrypt::{
+        PrivateKey, PublicKey, Sender, TaggedCiphertext, CIPHERTEXT_SIZE, CURRENT_VERSION,
+        LEGACY_VERSION, MAX_MESSAGE_LENGTH,
+    };
+    use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+    use curve25519_dalek::digest::Digest;
+    use curve25519_dalek::scalar::Scalar;
+    use fuzzytags::{RootSecret, Tag};
     use rand::rngs::OsRng;
+    use secretbox::CipherType::Salsa20;
+    use secretbox::SecretBox;
+    use std::ops::Mul;
+
+    // Encrypt a message the way niwl did before ciphertexts were versioned
+    fn encrypt_legacy(public_key: &PublicKey, tag: &Tag<24>, message: &str) -> TaggedCiphertext {
+        let padded_message = format!("{:<1024}", message);
+        let r = Scalar::random(&mut OsRng);
+        let z = RISTRETTO_BASEPOINT_POINT.mul(r);
+
+        let mut nonce_hash = sha3::Sha3_256::new();
+        nonce_hash.update(z.compress().as_bytes());
+        nonce_hash.update(tag.compress());
+        let mut nonce = [0u8; 24];
+        nonce[..].copy_from_slice(&nonce_hash.finalize().as_slice()[0..24]);
+
+        let mut hash = sha3::Sha3_256::new();
+        hash.update(public_key.0.mul(r).compress().as_bytes());
+        hash.update(tag.compress());
+        let secret_box = SecretBox::new(hash.finalize().to_vec(), Salsa20).unwrap();
+        TaggedCiphertext {
+            version: LEGACY_VERSION,
+            tag: tag.clone(),
+            nonce: z,
+            ciphertext: secret_box.seal(padded_message.as_bytes(), nonce),
+        }
+    }
 
     #[test]
     fn test_encrypt_to_tag() {
@@ -120,10 +514,157 @@ mod tests {
         let root_secret = RootSecret::<24>::generate(&mut OsRng);
         let tagging_key = root_secret.tagging_key();
 
-        let ciphertext =
-            public_key.encrypt(&tagging_key.generate_tag(&mut OsRng), &String::from("Hello World"));
+        let ciphertext = public_key
+            .encrypt(
+                &tagging_key.generate_tag(&mut OsRng),
+                &String::from("Hello World"),
+            )
+            .unwrap();
 
         let plaintext = secret.decrypt(&ciphertext);
         assert_eq!(plaintext.unwrap(), String::from("Hello World"))
     }
+
+    #[test]
+    fn test_encrypt_bytes_to_tag() {
+        let secret = PrivateKey::generate();
+        let public_key = secret.public_key();
+
+        let root_secret = RootSecret::<24>::generate(&mut OsRng);
+        let tagging_key = root_secret.tagging_key();
+
+        // trailing whitespace, nul bytes and invalid utf-8 must all survive the round trip
+        let message = vec![0xff, 0x00, 0xfe, b' ', b'\n', 0x00];
+        let ciphertext = public_key
+            .encrypt_bytes(&tagging_key.generate_tag(&mut OsRng), &message)
+            .unwrap();
+
+        let plaintext = secret.decrypt_bytes(&ciphertext);
+        assert_eq!(plaintext.unwrap(), message);
+        assert!(secret.decrypt(&ciphertext).is_none());
+    }
+
+    #[test]
+    fn test_fixed_size_packets() {
+        let secret = PrivateKey::generate();
+        let public_key = secret.public_key();
+
+        let root_secret = RootSecret::<24>::generate(&mut OsRng);
+        let tagging_key = root_secret.tagging_key();
+
+        let short = public_key
+            .encrypt_bytes(&tagging_key.generate_tag(&mut OsRng), b"short")
+            .unwrap();
+        let long = public_key
+            .encrypt_bytes(
+                &tagging_key.generate_tag(&mut OsRng),
+                &[7u8; MAX_MESSAGE_LENGTH],
+            )
+            .unwrap();
+        assert!(short.is_fixed_size() && long.is_fixed_size());
+        assert_eq!(short.to_bytes().len(), long.to_bytes().len());
+        assert_eq!(
+            secret.decrypt_bytes(&long).unwrap(),
+            vec![7u8; MAX_MESSAGE_LENGTH]
+        );
+
+        let too_long = vec![0u8; MAX_MESSAGE_LENGTH + 1];
+        assert!(public_key
+            .encrypt_bytes(&tagging_key.generate_tag(&mut OsRng), &too_long)
+            .is_err());
+
+        // A compact ciphertext survives being embedded, padded by a mix, and posted
+        let compact = public_key
+            .encrypt_bytes_compact(&tagging_key.generate_tag(&mut OsRng), b"mixed", None)
+            .unwrap();
+        assert!(compact.to_bytes().len() < CIPHERTEXT_SIZE);
+        let mut forwarded = TaggedCiphertext::from_bytes(&compact.to_bytes()).unwrap();
+        forwarded.pad();
+        assert!(forwarded.is_fixed_size());
+        assert_eq!(secret.decrypt_bytes(&forwarded).unwrap(), b"mixed".to_vec());
+    }
+
+    #[test]
+    fn test_authenticated_encryption() {
+        let secret = PrivateKey::generate();
+        let public_key = secret.public_key();
+        let identity = PrivateKey::generate();
+
+        let root_secret = RootSecret::<24>::generate(&mut OsRng);
+        let tagging_key = root_secret.tagging_key();
+
+        let ciphertext = public_key
+            .encrypt_authenticated(
+                &tagging_key.generate_tag(&mut OsRng),
+                b"from alice",
+                &identity,
+            )
+            .unwrap();
+        assert!(ciphertext.is_fixed_size());
+        match secret.decrypt_with_sender(&ciphertext) {
+            Some((Sender::Authenticated(sender), message)) => {
+                assert!(sender == identity.public_key());
+                assert_eq!(message, b"from alice".to_vec());
+            }
+            _ => panic!("expected an authenticated message"),
+        }
+
+        let anonymous = public_key
+            .encrypt_bytes(&tagging_key.generate_tag(&mut OsRng), b"from someone")
+            .unwrap();
+        match secret.decrypt_with_sender(&anonymous) {
+            Some((Sender::Anonymous, _)) => {}
+            _ => panic!("expected an anonymous message"),
+        }
+    }
+
+    #[test]
+    fn test_ciphertext_binds_tag_and_version() {
+        let secret = PrivateKey::generate();
+        let public_key = secret.public_key();
+
+        let root_secret = RootSecret::<24>::generate(&mut OsRng);
+        let tagging_key = root_secret.tagging_key();
+
+        let ciphertext = public_key
+            .encrypt_bytes(&tagging_key.generate_tag(&mut OsRng), b"bound")
+            .unwrap();
+        assert_eq!(ciphertext.version(), CURRENT_VERSION);
+
+        let mut retagged = ciphertext.clone();
+        retagged.tag = tagging_key.generate_tag(&mut OsRng);
+        assert!(secret.decrypt_bytes(&retagged).is_none());
+
+        let mut downgraded = ciphertext.clone();
+        downgraded.version = LEGACY_VERSION;
+        assert!(secret.decrypt_bytes(&downgraded).is_none());
+
+        // Ciphertexts from before versioning are still decrypted, as anonymous messages
+        let legacy = encrypt_legacy(
+            &public_key,
+            &tagging_key.generate_tag(&mut OsRng),
+            "Hello World",
+        );
+        let mut json = serde_json::to_value(&legacy).unwrap();
+        json.as_object_mut().unwrap().remove("version");
+        let decoded: TaggedCiphertext = serde_json::from_value(json).unwrap();
+        match secret.decrypt_with_sender(&decoded) {
+            Some((Sender::Anonymous, message)) => assert_eq!(message, b"Hello World".to_vec()),
+            _ => panic!("the legacy ciphertext should decrypt"),
+        }
+
+        let decoded = TaggedCiphertext::from_bytes(&ciphertext.to_bytes()).unwrap();
+        assert_eq!(secret.decrypt_bytes(&decoded).unwrap(), b"bound".to_vec());
+    }
+
+    #[test]
+    fn test_signatures() {
+        let key = PrivateKey::generate();
+        let signature = key.sign(b"hello");
+        assert!(key.public_key().verify(b"hello", &signature));
+        assert!(!key.public_key().verify(b"hellp", &signature));
+        assert!(!PrivateKey::generate()
+            .public_key()
+            .verify(b"hello", &signature));
+    }
 }
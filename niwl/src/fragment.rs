@@ -0,0 +1,238 @@
+//! Fragmentation of payloads too large to fit in a single niwl packet.
+//!
+//! A large payload is split into numbered fragments which are sent as separate messages, each
+//! with its own tag and ciphertext. Every fragment carries an identifier for the payload, the
+//! number of fragments and a hash over the whole payload, so the recipient can reassemble the
+//! fragments in whatever order they arrive and check the result.
+use crate::NiwlError;
+use chrono::{DateTime, Duration, Local};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::collections::{BTreeMap, HashMap};
+
+/// The maximum number of fragments a payload can be split into
+pub const MAX_FRAGMENTS: usize = 1024;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Fragment {
+    id: [u8; 16],
+    index: u16,
+    count: u16,
+    digest: [u8; 32],
+    data: Vec<u8>,
+}
+
+impl Fragment {
+    /// The identifier of the payload this fragment belongs to
+    pub fn id(&self) -> String {
+        hex::encode(self.id)
+    }
+
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+
+    pub fn count(&self) -> usize {
+        self.count as usize
+    }
+}
+
+fn digest(payload: &[u8]) -> [u8; 32] {
+    Sha3_256::digest(payload).into()
+}
+
+/// Split a payload into fragments carrying at most `fragment_size` bytes of the payload each.
+pub fn split(payload: &[u8], fragment_size: usize) -> Result<Vec<Fragment>, NiwlError> {
+    if fragment_size == 0 {
+        return Err(NiwlError::MessageTooLongError(String::from(
+            "there is no space left for a fragment",
+        )));
+    }
+    let chunks: Vec<&[u8]> = payload.chunks(fragment_size).collect();
+    if chunks.len() > MAX_FRAGMENTS {
+        return Err(NiwlError::MessageTooLongError(format!(
+            "message is {} bytes, the maximum is {} bytes",
+            payload.len(),
+            MAX_FRAGMENTS * fragment_size
+        )));
+    }
+
+    let mut id = [0u8; 16];
+    OsRng.fill_bytes(&mut id);
+    let digest = digest(payload);
+    Ok(chunks
+        .iter()
+        .enumerate()
+        .map(|(index, data)| Fragment {
+            id,
+            index: index as u16,
+            count: chunks.len() as u16,
+            digest,
+            data: data.to_vec(),
+        })
+        .collect())
+}
+
+/// The fragments we have received of a payload, and who sent them
+#[derive(Serialize, Deserialize)]
+struct PartialPayload {
+    sender: Option<String>,
+    count: u16,
+    digest: [u8; 32],
+    fragments: BTreeMap<u16, Vec<u8>>,
+    first_seen: DateTime<Local>,
+}
+
+/// Reassembles payloads from fragments arriving in any order.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Reassembler {
+    partial: HashMap<String, PartialPayload>,
+}
+
+impl Reassembler {
+    /// Add a fragment received from `sender` (or None if the sender is unknown). Returns the
+    /// payload and its sender once every fragment has arrived - if the fragments did not all
+    /// come from the same sender then the sender of the payload is unknown.
+    pub fn add(
+        &mut self,
+        sender: Option<String>,
+        fragment: Fragment,
+    ) -> Result<Option<(Option<String>, Vec<u8>)>, NiwlError> {
+        if fragment.count == 0
+            || fragment.count as usize > MAX_FRAGMENTS
+            || fragment.index >= fragment.count
+        {
+            return Err(NiwlError::InvalidFragmentError(format!(
+                "fragment {} of {} is out of range",
+                fragment.index, fragment.count
+            )));
+        }
+
+        let id = fragment.id();
+        let partial = self
+            .partial
+            .entry(id.clone())
+            .or_insert_with(|| PartialPayload {
+                sender: sender.clone(),
+                count: fragment.count,
+                digest: fragment.digest,
+                fragments: BTreeMap::new(),
+                first_seen: Local::now(),
+            });
+        if partial.count != fragment.count || partial.digest != fragment.digest {
+            return Err(NiwlError::InvalidFragmentError(format!(
+                "fragment does not match the other fragments of {}",
+                id
+            )));
+        }
+        if partial.sender != sender {
+            partial.sender = None;
+        }
+        partial
+            .fragments
+            .entry(fragment.index)
+            .or_insert(fragment.data);
+
+        if partial.fragments.len() < partial.count as usize {
+            return Ok(None);
+        }
+        let partial = self.partial.remove(&id).unwrap();
+        let payload: Vec<u8> = partial
+            .fragments
+            .into_iter()
+            .flat_map(|(_, data)| data)
+            .collect();
+        if digest(&payload) != partial.digest {
+            return Err(NiwlError::InvalidFragmentError(format!(
+                "reassembled message {} failed its integrity check",
+                id
+            )));
+        }
+        Ok(Some((partial.sender, payload)))
+    }
+
+    /// Give up on any payload whose first fragment arrived more than `timeout` ago. Returns the
+    /// id of each discarded payload, along with how many of its fragments had arrived.
+    pub fn expire(&mut self, timeout: Duration) -> Vec<(String, usize, usize)> {
+        let now = Local::now();
+        let expired: Vec<String> = self
+            .partial
+            .iter()
+            .filter(|(_, partial)| now - partial.first_seen > timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        expired
+            .into_iter()
+            .map(|id| {
+                let partial = self.partial.remove(&id).unwrap();
+                (id, partial.fragments.len(), partial.count as usize)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fragment::{split, Reassembler};
+    use chrono::Duration;
+    use rand::rngs::OsRng;
+    use rand::RngCore;
+
+    #[test]
+    fn test_reassemble_out_of_order() {
+        let mut payload = vec![0u8; 5000];
+        OsRng.fill_bytes(&mut payload);
+        let mut fragments = split(&payload, 700).unwrap();
+        assert_eq!(fragments.len(), 8);
+        fragments.reverse();
+
+        let mut reassembler = Reassembler::default();
+        let sender = Some(String::from("alice"));
+        let duplicate = fragments[3].clone();
+        let last = fragments.pop().unwrap();
+        for fragment in fragments.into_iter() {
+            assert!(reassembler.add(sender.clone(), fragment).unwrap().is_none());
+        }
+        assert!(reassembler
+            .add(sender.clone(), duplicate)
+            .unwrap()
+            .is_none());
+        let (from, reassembled) = reassembler.add(sender.clone(), last).unwrap().unwrap();
+        assert_eq!(from, sender);
+        assert_eq!(reassembled, payload);
+    }
+
+    #[test]
+    fn test_reassemble_integrity() {
+        let payload = vec![7u8; 2000];
+        let mut fragments = split(&payload, 700).unwrap();
+        fragments[1].data[0] ^= 1;
+
+        let mut reassembler = Reassembler::default();
+        assert!(reassembler
+            .add(None, fragments[0].clone())
+            .unwrap()
+            .is_none());
+        assert!(reassembler
+            .add(None, fragments[1].clone())
+            .unwrap()
+            .is_none());
+        assert!(reassembler.add(None, fragments[2].clone()).is_err());
+    }
+
+    #[test]
+    fn test_expire_missing_fragments() {
+        let fragments = split(&[1u8; 2000], 700).unwrap();
+        let mut reassembler = Reassembler::default();
+        assert!(reassembler
+            .add(None, fragments[0].clone())
+            .unwrap()
+            .is_none());
+        assert!(reassembler.expire(Duration::hours(1)).is_empty());
+        let expired = reassembler.expire(Duration::seconds(-1));
+        assert_eq!(expired, vec![(fragments[0].id(), 1, 3)]);
+        assert!(reassembler.partial.is_empty());
+    }
+}
@@ -1,20 +1,112 @@
 #![feature(into_future)]
-use crate::encrypt::{PrivateKey, PublicKey, TaggedCiphertext};
+use crate::backup::Seed;
+use crate::encrypt::{
+    PrivateKey, PublicKey, Sender, Signature, TaggedCiphertext, MAX_MESSAGE_LENGTH, PACKET_OVERHEAD,
+};
+use crate::fragment::{Fragment, Reassembler};
+use crate::ratchet::{RatchetMessage, Sessions};
+use crate::sphinx::{Surb, SurbSecrets};
+use crate::storage::{KdfParams, StorageKey};
+use crate::uri::{KeySetUri, SharedKeySet};
+use chrono::{DateTime, Duration, Local};
 use fuzzytags::{DetectionKey, RootSecret, Tag, TaggingKey};
 use rand::rngs::OsRng;
-use reqwest::{Error, Response};
 use serde::{Deserialize, Serialize};
+use sha3::Digest;
 use std::collections::HashMap;
+use std::fmt;
 use std::fs;
-use std::fs::File;
-use std::io::Write;
+use zeroize::Zeroizing;
 
+pub mod backup;
+pub mod client;
 pub mod encrypt;
+pub mod fragment;
+pub mod ratchet;
+pub mod sphinx;
+pub mod storage;
+pub mod transport;
+pub mod uri;
+pub mod wire;
 
 #[derive(Debug)]
 pub enum NiwlError {
     NoKnownContactError(String),
     RemoteServerError(String),
+    MessageTooLongError(String),
+    InvalidPacketError(String),
+    NoKnownReplyBlockError(String),
+    InvalidFragmentError(String),
+    SessionError(String),
+    StorageError(String),
+    BackupError(String),
+    VerificationError(String),
+    InvalidKeyError(String),
+    /// Reading or writing a file failed
+    IoError(std::io::Error),
+    /// Data could not be encoded or decoded
+    SerializationError(String),
+    /// A ciphertext or signature failed to authenticate
+    CryptoError(String),
+    /// A server answered a request with an unsuccessful status, and the body of its answer
+    HttpStatusError(u16, String),
+    /// A server answered a request with something we could not understand
+    ProtocolError(String),
+    /// A server no longer has all the messages after our sync cursor
+    CursorExpiredError(String),
+}
+
+impl fmt::Display for NiwlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NiwlError::NoKnownContactError(reason)
+            | NiwlError::RemoteServerError(reason)
+            | NiwlError::MessageTooLongError(reason)
+            | NiwlError::InvalidPacketError(reason)
+            | NiwlError::NoKnownReplyBlockError(reason)
+            | NiwlError::InvalidFragmentError(reason)
+            | NiwlError::SessionError(reason)
+            | NiwlError::StorageError(reason)
+            | NiwlError::BackupError(reason)
+            | NiwlError::VerificationError(reason)
+            | NiwlError::InvalidKeyError(reason)
+            | NiwlError::SerializationError(reason)
+            | NiwlError::CryptoError(reason)
+            | NiwlError::ProtocolError(reason)
+            | NiwlError::CursorExpiredError(reason) => f.write_str(reason),
+            NiwlError::IoError(err) => write!(f, "{}", err),
+            NiwlError::HttpStatusError(status, body) => {
+                write!(f, "server responded with status {}: {}", status, body)
+            }
+        }
+    }
+}
+
+impl std::error::Error for NiwlError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NiwlError::IoError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for NiwlError {
+    fn from(err: std::io::Error) -> NiwlError {
+        NiwlError::IoError(err)
+    }
+}
+
+impl From<bincode::Error> for NiwlError {
+    fn from(err: bincode::Error) -> NiwlError {
+        NiwlError::SerializationError(err.to_string())
+    }
+}
+
+impl From<reqwest::Error> for NiwlError {
+    fn from(err: reqwest::Error) -> NiwlError {
+        NiwlError::RemoteServerError(err.to_string())
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -22,9 +114,64 @@ pub struct Profile {
     profile_name: String,
     pub root_secret: RootSecret<24>,
     pub private_key: PrivateKey,
-    tagging_keys: HashMap<String, (TaggingKey<24>, PublicKey)>,
+    // A long-term key used to authenticate the messages we send. Profiles saved before identity
+    // keys were introduced derive theirs from `private_key` when they are opened, replacing
+    // this default.
+    #[serde(default = "PrivateKey::generate")]
+    identity_key: PrivateKey,
+    // Our contacts, indexed by the id of their tagging key
+    tagging_keys: HashMap<String, Contact>,
     detection_key_length: usize,
+    // Superseded by `cursors`, and only used to migrate to a cursor on the first sync
     last_seen_tag: Option<Tag<24>>,
+    // How far we have synced with each server, by the sequence number of the last message checked
+    #[serde(default)]
+    cursors: HashMap<String, u64>,
+    // SURBs we have given out, indexed by the nonce of the replies they will produce
+    #[serde(default)]
+    issued_surbs: HashMap<String, IssuedSurb>,
+    // SURBs we have received and not yet used, indexed by their id
+    #[serde(default)]
+    reply_blocks: HashMap<String, ReplyBlock>,
+    // Fragments of large messages that are still arriving
+    #[serde(default)]
+    fragments: Reassembler,
+    // Keys we have rotated away from, which we keep detecting and decrypting with until
+    // they expire
+    #[serde(default)]
+    retired_keys: Vec<RetiredKeys>,
+    // When our current keys were generated. Profiles saved before this was recorded count from
+    // when they were opened.
+    #[serde(default = "Local::now")]
+    keys_created: DateTime<Local>,
+    // A signature by the identity key we last rotated away from, handing over to our current
+    // keys. It is exported with our keys so that contacts holding the old ones can replace them.
+    #[serde(default)]
+    rotated_from: Option<Signature>,
+    // The seed every secret above was derived from. Profiles created before seeds were
+    // introduced have none, and cannot be backed up with a seed phrase.
+    #[serde(default)]
+    seed: Option<Seed>,
+    // The key this profile is encrypted under when saved, if it is protected by a passphrase
+    #[serde(skip)]
+    storage_key: Option<StorageKey>,
+    // A fingerprint of the file this profile was loaded from, to detect other processes saving
+    // it in the meantime
+    #[serde(skip)]
+    loaded: Option<[u8; 32]>,
+}
+
+// The fields that profiles saved by older versions may be missing
+#[derive(Deserialize)]
+struct SavedFields {
+    identity_key: Option<serde::de::IgnoredAny>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RetiredKeys {
+    root_secret: RootSecret<24>,
+    private_key: PrivateKey,
+    expires: DateTime<Local>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -32,20 +179,248 @@ pub struct KeySet {
     profile_name: String,
     tagging_key: TaggingKey<24>,
     public_key: PublicKey,
+    identity_key: PublicKey,
+}
+
+/// A KeySet signed by the identity key it contains, as exported for others to import. The
+/// signature only shows that the keys belong together - whether they belong to the person we
+/// think they do is checked by comparing safety numbers.
+#[derive(Serialize, Deserialize)]
+pub struct SignedKeySet {
+    keyset: KeySet,
+    signature: Signature,
+}
+
+impl KeySet {
+    fn signed_bytes(&self) -> Result<Vec<u8>, NiwlError> {
+        let mut bytes = b"niwl-keyset".to_vec();
+        bytes.extend_from_slice(&bincode::serialize(self)?);
+        Ok(bytes)
+    }
+
+    // What the identity key we rotate away from signs to hand over to these keys
+    fn rotation_bytes(&self) -> Result<Vec<u8>, NiwlError> {
+        let mut bytes = b"niwl-rotation".to_vec();
+        bytes.extend_from_slice(&self.signed_bytes()?);
+        Ok(bytes)
+    }
+}
+
+impl SignedKeySet {
+    /// The keyset, if it is signed by its own identity key
+    pub fn verify(self) -> Result<KeySet, NiwlError> {
+        match self
+            .keyset
+            .identity_key
+            .verify(&self.keyset.signed_bytes()?, &self.signature)
+        {
+            true => Ok(self.keyset),
+            false => Err(NiwlError::CryptoError(String::from(
+                "the key is not signed by its identity key",
+            ))),
+        }
+    }
+}
+
+/// The keys we hold for another party
+#[derive(Serialize, Deserialize)]
+pub struct Contact {
+    // The name we know the contact by. Petnames are chosen locally and are unique within a
+    // profile, unlike the names contacts advertise for themselves.
+    #[serde(default)]
+    petname: String,
+    tagging_key: TaggingKey<24>,
+    public_key: PublicKey,
+    // None for contacts imported from keysets exported before keysets were signed
+    #[serde(default)]
+    identity_key: Option<PublicKey>,
+    // Our double ratchet sessions with this contact
+    #[serde(default)]
+    sessions: Sessions,
+    // Set once we have compared safety numbers with the contact
+    #[serde(default)]
+    verified: bool,
+    // Set if the contact shared their keys as a mix
+    #[serde(default)]
+    mix: bool,
 }
 
+// Secret keys are never shown by Debug, so logging a profile cannot leak them
+impl fmt::Debug for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Profile")
+            .field("profile_name", &self.profile_name)
+            .field("root_secret", &format_args!("[REDACTED]"))
+            .field("private_key", &self.private_key)
+            .field("identity_key", &self.identity_key)
+            .field("seed", &self.seed)
+            .field("contacts", &self.tagging_keys)
+            .field("detection_key_length", &self.detection_key_length)
+            .field("issued_surbs", &self.issued_surbs.len())
+            .field("reply_blocks", &self.reply_blocks.len())
+            .field("retired_keys", &self.retired_keys.len())
+            .field("keys_created", &self.keys_created)
+            .field("encrypted", &self.storage_key.is_some())
+            .finish()
+    }
+}
+
+impl fmt::Debug for Contact {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Contact")
+            .field("petname", &self.petname)
+            .field(
+                "public_key",
+                &hex::encode(self.public_key.0.compress().as_bytes()),
+            )
+            .field("identity_key", &self.identity_key())
+            .field("sessions", &self.sessions)
+            .field("verified", &self.verified)
+            .field("mix", &self.mix)
+            .finish()
+    }
+}
+
+impl Contact {
+    /// The name we know this contact by
+    pub fn petname(&self) -> &String {
+        &self.petname
+    }
+
+    /// The id of this contact's tagging key, which their entry is filed under
+    pub fn id(&self) -> String {
+        self.tagging_key.id()
+    }
+
+    /// The identity key of this contact, hex encoded, or None if they shared keys without one
+    pub fn identity_key(&self) -> Option<String> {
+        self.identity_key
+            .as_ref()
+            .map(|key| hex::encode(key.0.compress().as_bytes()))
+    }
+
+    // The identity key of this contact, which they must have shared for `reason`
+    fn require_identity_key(&self, reason: &str) -> Result<&PublicKey, NiwlError> {
+        self.identity_key.as_ref().ok_or_else(|| {
+            NiwlError::VerificationError(format!(
+                "{} shared keys without an identity key, which is needed {}. {}",
+                self.petname, reason, "Ask them for a new key"
+            ))
+        })
+    }
+
+    /// Whether we have compared safety numbers with this contact
+    pub fn is_verified(&self) -> bool {
+        self.verified
+    }
+
+    /// Whether this contact shared their keys as a mix
+    pub fn is_mix(&self) -> bool {
+        self.mix
+    }
+}
+
+/// File contacts under the id of their tagging key. Contacts stored by older versions are
+/// indexed by the name they advertised and have no petname, so that name becomes their petname.
+/// Returns the contacts along with the new id of each contact whose index changed.
+fn index_by_id(
+    contacts: HashMap<String, Contact>,
+) -> (HashMap<String, Contact>, HashMap<String, String>) {
+    let mut indexed = HashMap::new();
+    let mut moved = HashMap::new();
+    for (name, mut contact) in contacts {
+        if contact.petname.is_empty() {
+            contact.petname = name.clone();
+        }
+        let id = contact.id();
+        if id != name {
+            moved.insert(name, id.clone());
+        }
+        indexed.insert(id, contact);
+    }
+    (indexed, moved)
+}
+
+#[derive(Serialize, Deserialize)]
+struct IssuedSurb {
+    contact: String,
+    secrets: SurbSecrets,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReplyBlock {
+    contact: Option<String>,
+    surb: Surb,
+}
+
+/// The contents of a message exchanged between two profiles
+#[derive(Serialize, Deserialize)]
+pub enum Payload {
+    Message(Vec<u8>),
+    /// A message along with a single-use reply block the recipient can use to answer it
+    Replyable(Surb, Vec<u8>),
+    /// Part of a payload too large to send in a single packet
+    Fragment(Fragment),
+    /// A payload encrypted within a double ratchet session with the sender
+    Ratchet(RatchetMessage),
+    /// Notice that the sender has replaced their keys with these
+    KeyRotation(KeySet),
+}
+
+impl Payload {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, NiwlError> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    /// Decode a payload. Messages from older clients are not framed, and are treated as a
+    /// plain Message.
+    pub fn from_bytes(data: &[u8]) -> Payload {
+        match bincode::deserialize(data) {
+            Ok(payload) => payload,
+            Err(_) => Payload::Message(data.to_vec()),
+        }
+    }
+}
+
+/// The most messages a server checks for a single fetch with a cursor. Larger limits are capped
+/// to this, so that neither the server nor the client holds more than a page of messages. A
+/// fetch without a cursor is sent at most this many matching messages.
+pub const MAX_PAGE_SIZE: u32 = 1000;
+
 #[derive(Deserialize)]
 pub struct DetectedTags {
     pub detected_tags: Vec<(Tag<24>, TaggedCiphertext)>,
+    // The sequence number of the last message the server checked, to be sent as the
+    // `after_sequence` of the next request. Only returned when `after_sequence` was given.
+    #[serde(default)]
+    pub cursor: Option<u64>,
+    // Whether the server stopped at the limit of the request, with more messages to check
+    #[serde(default)]
+    pub more: bool,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct FetchMessagesRequest {
     // The last tag this client downloaded to use as a reference when fetching new messages
     // If None, then the server will check *all* messages.
+    // When `after_sequence` is also given, this is only used to migrate to a cursor, and an
+    // unknown tag is reported as an expired cursor.
     pub reference_tag: Option<Tag<24>>,
     // The detection key to use to fetch new messages
     pub detection_key: DetectionKey<24>,
+    // Only check messages with a sequence number after this one (0 checks all those kept). If the
+    // server has since pruned messages after it, it responds that the cursor has expired.
+    #[serde(default)]
+    pub after_sequence: Option<u64>,
+    // The most messages to check, capped to `MAX_PAGE_SIZE` (the default). Requests without
+    // `after_sequence` cannot resume from a cursor, so instead they are sent at most
+    // `MAX_PAGE_SIZE` matching messages and resume from the last one as their `reference_tag`.
+    #[serde(default)]
+    pub limit: Option<u32>,
+    // Do not check messages with a sequence number after this one, so that the pages fetched
+    // for each of our detection keys cover the same messages
+    #[serde(default)]
+    pub until_sequence: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -55,184 +430,1521 @@ pub struct PostMessageRequest {
 }
 
 impl Profile {
-    pub fn get_profile(profile_filename: &String) -> Profile {
-        match fs::read_to_string(profile_filename) {
-            Ok(json) => serde_json::from_str(json.as_str()).unwrap(),
-            Err(why) => {
-                panic!("couldn't read orb.profile : {}", why);
+    /// Load a profile that is not protected by a passphrase
+    pub fn get_profile(profile_filename: &String) -> Result<Profile, NiwlError> {
+        Profile::open(profile_filename, None)
+    }
+
+    /// Whether the profile stored in `profile_filename` is protected by a passphrase
+    pub fn is_encrypted(profile_filename: &String) -> Result<bool, NiwlError> {
+        Ok(storage::is_encrypted(&fs::read(profile_filename)?))
+    }
+
+    /// Load a profile, decrypting it with `passphrase` if it is protected by one
+    pub fn open(
+        profile_filename: &String,
+        passphrase: Option<&String>,
+    ) -> Result<Profile, NiwlError> {
+        let data = Zeroizing::new(fs::read(profile_filename)?);
+        let loaded = storage::fingerprint(&data);
+        let (storage_key, json) = match (storage::is_encrypted(&data), passphrase) {
+            (false, _) => (None, data),
+            (true, Some(passphrase)) => {
+                let (storage_key, json) = storage::open(passphrase, &data)?;
+                (Some(storage_key), json)
+            }
+            (true, None) => {
+                return Err(NiwlError::StorageError(format!(
+                    "{} is protected by a passphrase",
+                    profile_filename
+                )))
             }
+        };
+        let mut profile: Profile = serde_json::from_slice(&json).map_err(|why| {
+            NiwlError::SerializationError(format!("couldn't parse {} : {}", profile_filename, why))
+        })?;
+        let saved: SavedFields = serde_json::from_slice(&json).map_err(|why| {
+            NiwlError::SerializationError(format!("couldn't parse {} : {}", profile_filename, why))
+        })?;
+        // The identity key must be the same every time the profile is opened, or safety numbers
+        // and signatures would change until it is next saved
+        if saved.identity_key.is_none() {
+            profile.identity_key = profile.private_key.derive(b"niwl-identity-key");
+        }
+        profile.storage_key = storage_key;
+        profile.loaded = Some(loaded);
+        let (contacts, moved) = index_by_id(std::mem::take(&mut profile.tagging_keys));
+        profile.tagging_keys = contacts;
+        for (old, new) in moved.iter() {
+            profile.refile_contact(old, new);
         }
+        Ok(profile)
+    }
+
+    /// Protect this profile with a passphrase the next time it is saved, replacing any existing
+    /// passphrase, or store it unencrypted if `passphrase` is None.
+    pub fn set_passphrase(&mut self, passphrase: Option<&String>) -> Result<(), NiwlError> {
+        self.storage_key = match passphrase {
+            Some(passphrase) => Some(StorageKey::derive(passphrase, KdfParams::default())?),
+            None => None,
+        };
+        Ok(())
     }
 
     pub fn new(profile_name: String, detection_key_length: usize) -> Profile {
-        let root_secret = RootSecret::<24>::generate(&mut OsRng);
-        let private_key = PrivateKey::generate();
+        Profile::from_seed(profile_name, detection_key_length, Seed::generate())
+    }
+
+    /// Recover a profile from its seed phrase. Contacts are not part of the seed, see
+    /// `import_contacts`.
+    pub fn restore(
+        profile_name: String,
+        detection_key_length: usize,
+        phrase: &str,
+    ) -> Result<Profile, NiwlError> {
+        Ok(Profile::from_seed(
+            profile_name,
+            detection_key_length,
+            Seed::from_phrase(phrase)?,
+        ))
+    }
+
+    fn from_seed(profile_name: String, detection_key_length: usize, seed: Seed) -> Profile {
+        // The order secrets are drawn in must never change, or seeds will stop recovering them
+        let mut rng = seed.rng();
+        let root_secret = RootSecret::<24>::generate(&mut rng);
+        let private_key = PrivateKey::from_rng(&mut rng);
+        let identity_key = PrivateKey::from_rng(&mut rng);
         Profile {
             profile_name,
             root_secret,
             private_key,
+            identity_key,
             tagging_keys: Default::default(),
             detection_key_length,
             last_seen_tag: None,
+            cursors: HashMap::new(),
+            issued_surbs: Default::default(),
+            reply_blocks: Default::default(),
+            fragments: Default::default(),
+            retired_keys: vec![],
+            keys_created: Local::now(),
+            rotated_from: None,
+            seed: Some(seed),
+            storage_key: None,
+            loaded: None,
+        }
+    }
+
+    fn seed(&self) -> Result<&Seed, NiwlError> {
+        self.seed
+            .as_ref()
+            .ok_or(NiwlError::BackupError(String::from(
+            "this profile was created before seed phrases were introduced, and cannot be backed up",
+        )))
+    }
+
+    /// The seed phrase that recovers the secrets of this profile
+    pub fn seed_phrase(&self) -> Result<Zeroizing<String>, NiwlError> {
+        Ok(self.seed()?.phrase())
+    }
+
+    /// Export the keys of our contacts, encrypted under a key derived from our seed
+    pub fn export_contacts(&self) -> Result<Vec<u8>, NiwlError> {
+        Ok(self.seed()?.export_contacts(&self.tagging_keys))
+    }
+
+    /// Import contacts exported from a profile with the same seed. Contacts we already know
+    /// are left alone, and contacts whose petname is taken are given a new one. Returns the
+    /// petnames of the contacts that were added.
+    pub fn import_contacts(&mut self, export: &[u8]) -> Result<Vec<String>, NiwlError> {
+        let (contacts, _) = index_by_id(self.seed()?.import_contacts(export)?);
+        let mut added = vec![];
+        for (id, mut contact) in contacts {
+            if !self.tagging_keys.contains_key(&id) {
+                contact.petname = self.free_petname(&contact.petname);
+                added.push(contact.petname.clone());
+                self.tagging_keys.insert(id, contact);
+            }
         }
+        added.sort();
+        Ok(added)
     }
 
-    pub fn keyset(&self) -> KeySet {
+    pub fn keyset(&self) -> Result<KeySet, NiwlError> {
         let tagging_key = self.root_secret.tagging_key();
         let public_key = self.private_key.public_key();
-        KeySet {
+        let identity_key = self.identity_key.public_key();
+        Ok(KeySet {
             profile_name: self.profile_name.clone(),
             tagging_key,
             public_key,
+            identity_key,
+        })
+    }
+
+    /// Our keys, signed by our identity key and encoded as a key URI for others to import.
+    /// Mixes should set `mix` so that clients know they can route through them. If we have
+    /// rotated our keys, the URI also carries the proof that replaces our old keys.
+    pub fn export_keyset(&self, mix: bool) -> Result<String, NiwlError> {
+        let keyset = self.keyset()?;
+        let signature = self.identity_key.sign(&keyset.signed_bytes()?);
+        KeySetUri {
+            name: Some(keyset.profile_name.clone()),
+            keyset: SharedKeySet::Signed(SignedKeySet { keyset, signature }),
+            mix,
+            previous: self.rotated_from.clone(),
         }
+        .encode()
     }
 
-    pub fn save(&self, profile_filename: &String) -> std::io::Result<()> {
-        let j = serde_json::to_string(&self);
-        let mut file = match File::create(profile_filename) {
-            Err(why) => panic!("couldn't create : {}", why),
-            Ok(file) => file,
+    /// Save this profile, replacing the file atomically and keeping the previous version in
+    /// `<profile_filename>.bak`. Fails if another process has saved the profile since it was
+    /// loaded - open it again to pick up their changes.
+    pub fn save(&mut self, profile_filename: &String) -> Result<(), NiwlError> {
+        let json = storage::to_json(self);
+        let data = match &self.storage_key {
+            Some(storage_key) => Zeroizing::new(storage_key.seal(&json)),
+            None => json,
         };
-        file.write_all(j.unwrap().as_bytes())
+        self.loaded = Some(storage::write_atomically(
+            profile_filename,
+            &data,
+            self.loaded.as_ref(),
+        )?);
+        Ok(())
     }
 
     pub fn generate_tag(&self, id: &String) -> Result<Tag<24>, NiwlError> {
-        if self.tagging_keys.contains_key(id) {
-            let tag = self.tagging_keys[id].0.generate_tag(&mut OsRng);
-            println!("Tag for {} {}", id, tag.to_string());
-            return Ok(tag);
-        }
-        Err(NiwlError::NoKnownContactError(format!(
-            "No known friend {}. Perhaps you need to import-tagging-key first?",
-            id
-        )))
+        match self.contact_id(id) {
+            Some(contact_id) => Ok(self.tagging_keys[&contact_id]
+                .tagging_key
+                .generate_tag(&mut OsRng)),
+            None => Err(NiwlError::NoKnownContactError(format!(
+                "No known friend {}. Perhaps you need to import-tagging-key first?",
+                id
+            ))),
+        }
     }
 
-    pub fn import_tagging_key(&mut self, key: &String) {
-        match base32::decode(base32::Alphabet::RFC4648 { padding: false }, key.as_str()) {
-            Some(data) => {
-                let tagging_key_result: Result<KeySet, bincode::Error> =
-                    bincode::deserialize(&data);
-                match tagging_key_result {
-                    Ok(hotk) => {
-                        println!("Got: {}: {}", hotk.profile_name, hotk.tagging_key.id());
-                        if self.tagging_keys.contains_key(&hotk.profile_name) == false {
-                            self.tagging_keys
-                                .insert(hotk.profile_name, (hotk.tagging_key, hotk.public_key));
-                        } else {
-                            println!("There is already an entry for {}", hotk.profile_name)
-                        }
-                        return;
-                    }
-                    Err(err) => {
-                        println!("Error: {}", err.to_string());
+    /// Import the keys of a friend from a key URI (or a bare base32 key), returning the petname
+    /// they were filed under. The petname defaults to the name they advertise. Keys exported
+    /// before keysets were signed are imported without an identity key, see
+    /// `Contact::identity_key`. Keys that were rotated from the keys of a contact replace them,
+    /// as if the contact had sent us a rotation notice, and keep their petname.
+    pub fn import_tagging_key(
+        &mut self,
+        key: &String,
+        petname: Option<&String>,
+    ) -> Result<String, NiwlError> {
+        let uri = KeySetUri::parse(key)?;
+        let (profile_name, tagging_key, public_key, identity_key) = match uri.keyset {
+            SharedKeySet::Signed(signed) => {
+                let keyset = signed.verify()?;
+                if let Some(proof) = &uri.previous {
+                    if let Some(contact) = self.rotated_contact(&keyset, proof)? {
+                        let petname = self.petname(&contact);
+                        self.apply_key_rotation(&contact, keyset)?;
+                        return Ok(petname);
                     }
                 }
+                (
+                    keyset.profile_name,
+                    keyset.tagging_key,
+                    keyset.public_key,
+                    Some(keyset.identity_key),
+                )
             }
-            _ => {}
+            SharedKeySet::Unsigned(unsigned) => (
+                unsigned.profile_name,
+                unsigned.tagging_key,
+                unsigned.public_key,
+                None,
+            ),
         };
-        println!("Error Reporting Tagging Key")
+        let id = tagging_key.id();
+        if let Some(contact) = self.tagging_keys.get(&id) {
+            return Err(NiwlError::InvalidKeyError(format!(
+                "There is already an entry for this key, named {}",
+                contact.petname
+            )));
+        }
+        let petname = petname.unwrap_or(&profile_name).clone();
+        self.check_petname(&petname)?;
+        self.tagging_keys.insert(
+            id,
+            Contact {
+                petname: petname.clone(),
+                tagging_key,
+                public_key,
+                identity_key,
+                sessions: Default::default(),
+                verified: false,
+                mix: uri.mix,
+            },
+        );
+        Ok(petname)
     }
 
-    pub async fn tag_and_mix(
+    // The id of the contact whose identity key handed over to `keyset`
+    fn rotated_contact(
         &self,
-        server: String,
-        mix: String,
-        contact: String,
-        message: &String,
-    ) -> Result<Response, NiwlError> {
-        match self.generate_tag(&contact) {
-            Ok(tag) => {
-                let ciphertext = self.tagging_keys[&contact].1.encrypt(&tag, message);
-                let ciphertext_json = serde_json::to_string(&ciphertext).unwrap();
-                return self.tag_and_send(&server, mix, &ciphertext_json).await;
+        keyset: &KeySet,
+        proof: &Signature,
+    ) -> Result<Option<String>, NiwlError> {
+        let rotation = keyset.rotation_bytes()?;
+        Ok(self
+            .tagging_keys
+            .iter()
+            .find(|(_, contact)| {
+                contact
+                    .identity_key
+                    .as_ref()
+                    .map_or(false, |identity_key| identity_key.verify(&rotation, proof))
+            })
+            .map(|(id, _)| id.clone()))
+    }
+
+    /// The id a contact is filed under, given either their petname or the id itself
+    fn contact_id(&self, contact: &String) -> Option<String> {
+        match self.tagging_keys.contains_key(contact) {
+            true => Some(contact.clone()),
+            false => self
+                .tagging_keys
+                .iter()
+                .find(|(_, c)| &c.petname == contact)
+                .map(|(id, _)| id.clone()),
+        }
+    }
+
+    // Petnames must be unique, and must not be mistaken for the id of another contact
+    fn check_petname(&self, petname: &String) -> Result<(), NiwlError> {
+        if petname.trim().is_empty() {
+            return Err(NiwlError::InvalidKeyError(String::from(
+                "petnames cannot be empty",
+            )));
+        }
+        if self.contact_id(petname).is_some() {
+            return Err(NiwlError::InvalidKeyError(format!(
+                "There is already a contact named {}, choose another petname",
+                petname
+            )));
+        }
+        Ok(())
+    }
+
+    // The first of `petname`, `petname-2`, `petname-3`... that is not taken
+    fn free_petname(&self, petname: &String) -> String {
+        let mut candidate = petname.clone();
+        let mut suffix = 2;
+        while self.check_petname(&candidate).is_err() {
+            candidate = format!("{}-{}", petname, suffix);
+            suffix += 1;
+        }
+        candidate
+    }
+
+    /// The name to show for a contact id, which is their petname if we still know them
+    fn petname(&self, id: &String) -> String {
+        self.tagging_keys
+            .get(id)
+            .map_or(id.clone(), |contact| contact.petname.clone())
+    }
+
+    // Point the SURBs we hold for a contact at the new id they are filed under
+    fn refile_contact(&mut self, old: &String, new: &String) {
+        for issued in self.issued_surbs.values_mut() {
+            if &issued.contact == old {
+                issued.contact = new.clone();
+            }
+        }
+        for reply_block in self.reply_blocks.values_mut() {
+            if reply_block.contact.as_ref() == Some(old) {
+                reply_block.contact = Some(new.clone());
             }
-            Err(err) => Err(err),
         }
     }
 
-    pub async fn send_to_self(
+    /// Our contacts, ordered by petname
+    pub fn contacts(&self) -> Result<Vec<&Contact>, NiwlError> {
+        let mut contacts: Vec<&Contact> = self.tagging_keys.values().collect();
+        contacts.sort_by(|a, b| a.petname.cmp(&b.petname));
+        Ok(contacts)
+    }
+
+    /// Change the name we know a contact by
+    pub fn rename_contact(&mut self, contact: &String, petname: &String) -> Result<(), NiwlError> {
+        let id = self.contact(contact)?.id();
+        self.check_petname(petname)?;
+        self.tagging_keys.get_mut(&id).unwrap().petname = petname.clone();
+        Ok(())
+    }
+
+    /// Forget a contact, along with our sessions with them. Reply blocks they gave us can still
+    /// be used, but are no longer attributed to them.
+    pub fn remove_contact(&mut self, contact: &String) -> Result<Contact, NiwlError> {
+        let id = self.contact(contact)?.id();
+        for reply_block in self.reply_blocks.values_mut() {
+            if reply_block.contact.as_ref() == Some(&id) {
+                reply_block.contact = None;
+            }
+        }
+        Ok(self.tagging_keys.remove(&id).unwrap())
+    }
+
+    /// Encrypt a message to a known contact without filler. If `authenticated` is set the
+    /// message is bound to our identity key so the contact can verify who sent it.
+    fn encrypt_for(
         &self,
-        server: &String,
-        message: &String,
-    ) -> Result<Response, NiwlError> {
-        let client = reqwest::Client::new();
-        let tag = self.root_secret.tagging_key().generate_tag(&mut OsRng);
-        let ciphertext = self.private_key.public_key().encrypt(&tag, message);
+        contact: &String,
+        message: &[u8],
+        authenticated: bool,
+    ) -> Result<TaggedCiphertext, NiwlError> {
+        let tag = self.generate_tag(contact)?;
+        let identity = match authenticated {
+            true => Some(&self.identity_key),
+            false => None,
+        };
+        self.contact(contact)?
+            .public_key
+            .encrypt_bytes_compact(&tag, message, identity)
+    }
+
+    /// Decrypt a message addressed to us, returning the petname of the contact that sent it
+    /// or None if the sender is unknown (either the message is anonymous, or it was
+    /// authenticated by an identity key we don't know).
+    pub fn decrypt(
+        &self,
+        ciphertext: &TaggedCiphertext,
+    ) -> Result<(Option<String>, Payload), NiwlError> {
+        let (sender, message) = self
+            .private_key
+            .decrypt_with_sender(ciphertext)
+            .or_else(|| {
+                self.retired_keys
+                    .iter()
+                    .find_map(|keys| keys.private_key.decrypt_with_sender(ciphertext))
+            })
+            .ok_or(NiwlError::CryptoError(String::from(
+                "the message could not be decrypted with any of our keys",
+            )))?;
+        let contact = match sender {
+            Sender::Authenticated(identity_key) => self
+                .tagging_keys
+                .values()
+                .find(|contact| contact.identity_key.as_ref() == Some(&identity_key))
+                .map(|contact| contact.petname.clone()),
+            Sender::Anonymous => None,
+        };
+        Ok((contact, Payload::from_bytes(&message)))
+    }
+
+    /// Create a single-use reply block for a contact, routed back to us through an ordered list
+    /// of mixes. The contact can reply using the SURB without learning our tagging key.
+    pub fn create_surb(&mut self, route: &[String], contact: &String) -> Result<Surb, NiwlError> {
+        let contact = self.contact(contact)?.id();
+        let mut hops = vec![];
+        for mix in route.iter() {
+            let tag = self.generate_tag(mix)?;
+            hops.push((&self.contact(mix)?.public_key, tag));
+        }
+        let reply_tag = self.root_secret.tagging_key().generate_tag(&mut OsRng);
+        let (surb, id, secrets) = sphinx::create_surb(&hops, &reply_tag)?;
+        self.issued_surbs.insert(
+            base32::encode(base32::Alphabet::RFC4648 { padding: false }, &id),
+            IssuedSurb { contact, secrets },
+        );
+        Ok(surb)
+    }
+
+    /// Decrypt a reply made with one of the SURBs we have issued, returning the petname of the
+    /// contact we gave the SURB to. Each SURB can only be used once.
+    pub fn decrypt_reply(
+        &mut self,
+        ciphertext: &TaggedCiphertext,
+    ) -> Result<(String, Payload), NiwlError> {
+        let id = base32::encode(
+            base32::Alphabet::RFC4648 { padding: false },
+            ciphertext.nonce.compress().as_bytes(),
+        );
+        let issued = self
+            .issued_surbs
+            .get(&id)
+            .ok_or(NiwlError::NoKnownReplyBlockError(String::from(
+                "the message is not a reply to any of our reply blocks",
+            )))?;
+        let message = issued.secrets.open(ciphertext)?;
+        let issued = self.issued_surbs.remove(&id).unwrap();
+        Ok((self.petname(&issued.contact), Payload::from_bytes(&message)))
+    }
+
+    /// Replace our keys with keys from a new seed, and build a notice of the new keys for every
+    /// contact, authenticated by our old identity key. The notices must be posted for contacts
+    /// to learn our new keys, and contacts we don't know (e.g. the clients of a mix) can import
+    /// our exported keys in place of the old ones. Messages sent to our old keys are still
+    /// detected and decrypted until `grace_period` has passed. The old seed phrase no longer
+    /// recovers this profile.
+    pub fn rotate_keys(
+        &mut self,
+        grace_period: Duration,
+    ) -> Result<Vec<(String, TaggedCiphertext)>, NiwlError> {
+        let rotated = Profile::from_seed(
+            self.profile_name.clone(),
+            self.detection_key_length,
+            Seed::generate(),
+        );
+        let keyset = rotated.keyset()?;
+        let proof = self.identity_key.sign(&keyset.rotation_bytes()?);
+        let notice = Payload::KeyRotation(keyset).to_bytes()?;
+        let mut notices = vec![];
+        // Mixes never send to us, so they don't need to know our new keys
+        for (id, contact) in self.tagging_keys.iter().filter(|(_, c)| !c.mix) {
+            let mut ciphertext = self.encrypt_for(id, &notice, true)?;
+            ciphertext.pad();
+            notices.push((contact.petname.clone(), ciphertext));
+        }
+
+        let Profile {
+            root_secret,
+            private_key,
+            identity_key,
+            seed,
+            ..
+        } = rotated;
+        self.retired_keys.push(RetiredKeys {
+            root_secret: std::mem::replace(&mut self.root_secret, root_secret),
+            private_key: std::mem::replace(&mut self.private_key, private_key),
+            expires: Local::now() + grace_period,
+        });
+        self.identity_key = identity_key;
+        self.seed = seed;
+        self.keys_created = Local::now();
+        self.rotated_from = Some(proof);
+        Ok(notices)
+    }
+
+    /// Replace the keys we hold for a contact with the keys from their rotation notice. The
+    /// notice must have been authenticated by the contact's current identity key. Our sessions
+    /// with the contact are discarded, and a new one started with their new keys. The contact
+    /// is filed under the id of their new tagging key, and keeps their petname.
+    pub fn apply_key_rotation(
+        &mut self,
+        contact: &String,
+        keyset: KeySet,
+    ) -> Result<(), NiwlError> {
+        let old = self.contact(contact)?.id();
+        let new = keyset.tagging_key.id();
+        if new != old && self.tagging_keys.contains_key(&new) {
+            return Err(NiwlError::InvalidKeyError(format!(
+                "the new keys of {} belong to another contact",
+                contact
+            )));
+        }
+        let contact = self.tagging_keys.remove(&old).unwrap();
+        // The notice was authenticated by the identity key we had for the contact, so if that
+        // key was verified then so are the keys it vouches for
+        self.tagging_keys.insert(
+            new.clone(),
+            Contact {
+                petname: contact.petname.clone(),
+                tagging_key: keyset.tagging_key,
+                public_key: keyset.public_key,
+                identity_key: Some(keyset.identity_key),
+                sessions: Default::default(),
+                verified: contact.verified,
+                mix: contact.mix,
+            },
+        );
+        self.refile_contact(&old, &new);
+        Ok(())
+    }
 
-        let result = client
-            .post(&format!("{}/new", server))
-            .json(&PostMessageRequest { tag, ciphertext })
-            .send()
-            .await;
-        match result {
-            Ok(response) => Ok(response),
-            Err(err) => Err(NiwlError::RemoteServerError(err.to_string())),
+    /// Look up a contact by petname or id
+    pub fn contact(&self, contact: &String) -> Result<&Contact, NiwlError> {
+        self.contact_id(contact)
+            .map(|id| &self.tagging_keys[&id])
+            .ok_or(NiwlError::NoKnownContactError(format!(
+                "No known friend {}",
+                contact
+            )))
+    }
+
+    fn contact_mut(&mut self, contact: &String) -> Result<&mut Contact, NiwlError> {
+        match self.contact_id(contact) {
+            Some(id) => Ok(self.tagging_keys.get_mut(&id).unwrap()),
+            None => Err(NiwlError::NoKnownContactError(format!(
+                "No known friend {}",
+                contact
+            ))),
         }
     }
 
-    pub async fn forward(
+    /// A fingerprint of our identity key and a contact's, which is the same for both of us.
+    /// Comparing it over a trusted channel shows that nobody swapped the keys we exchanged.
+    pub fn safety_number(&self, contact: &String) -> Result<String, NiwlError> {
+        let ours = self.identity_key.public_key().0.compress().to_bytes();
+        let theirs = self
+            .contact(contact)?
+            .require_identity_key("to compute a safety number")?
+            .0
+            .compress()
+            .to_bytes();
+        let (first, second) = match ours < theirs {
+            true => (ours, theirs),
+            false => (theirs, ours),
+        };
+        let mut hash = sha3::Sha3_256::new();
+        hash.update(b"niwl-safety-number");
+        hash.update(first);
+        hash.update(second);
+        let digest = hash.finalize();
+        // Twelve groups of five digits, each from 20 bits of the hash
+        let groups: Vec<String> = digest[0..30]
+            .chunks(5)
+            .flat_map(|chunk| {
+                let bits = chunk
+                    .iter()
+                    .fold(0u64, |bits, byte| bits << 8 | *byte as u64);
+                vec![(bits >> 20) % 100000, (bits & 0xfffff) % 100000]
+            })
+            .map(|group| format!("{:05}", group))
+            .collect();
+        Ok(groups.join(" "))
+    }
+
+    /// Mark a contact as verified if `safety_number` (as they see it) matches ours
+    pub fn verify_contact(
+        &mut self,
+        contact: &String,
+        safety_number: &str,
+    ) -> Result<(), NiwlError> {
+        let expected = self.safety_number(contact)?;
+        let digits: String = safety_number
+            .chars()
+            .filter(|c| c.is_ascii_digit())
+            .collect();
+        if digits != expected.replace(' ', "") {
+            return Err(NiwlError::VerificationError(format!(
+                "safety numbers do not match, the keys you hold for {} may not be theirs",
+                contact
+            )));
+        }
+        self.contact_mut(contact)?.verified = true;
+        Ok(())
+    }
+
+    /// Mark a contact as no longer verified
+    pub fn unverify_contact(&mut self, contact: &String) -> Result<(), NiwlError> {
+        self.contact_mut(contact)?.verified = false;
+        Ok(())
+    }
+
+    /// Whether a contact shared their keys as a mix
+    pub fn is_mix(&self, contact: &String) -> bool {
+        self.contact(contact).map_or(false, |contact| contact.mix)
+    }
+
+    /// Whether we have compared safety numbers with a contact
+    pub fn is_verified(&self, contact: &String) -> bool {
+        self.contact(contact)
+            .map_or(false, |contact| contact.verified)
+    }
+
+    /// When our current keys were generated, e.g. to decide when to rotate them
+    pub fn keys_created(&self) -> DateTime<Local> {
+        self.keys_created
+    }
+
+    /// Our private keys, the current key first and then those of retired keys
+    pub fn private_keys(&self) -> Vec<&PrivateKey> {
+        std::iter::once(&self.private_key)
+            .chain(self.retired_keys.iter().map(|keys| &keys.private_key))
+            .collect()
+    }
+
+    /// Whether a tag was generated for one of our tagging keys, current or retired. Unlike the
+    /// detection keys we give to servers this has no false positives.
+    pub fn owns_tag(&self, tag: &Tag<24>) -> bool {
+        std::iter::once(&self.root_secret)
+            .chain(self.retired_keys.iter().map(|keys| &keys.root_secret))
+            .any(|root_secret| root_secret.extract_detection_key(24).test_tag(tag))
+    }
+
+    /// Forget any retired keys whose grace period has passed, returning how many were removed
+    pub fn expire_retired_keys(&mut self) -> usize {
+        let now = Local::now();
+        let count = self.retired_keys.len();
+        self.retired_keys.retain(|keys| keys.expires > now);
+        count - self.retired_keys.len()
+    }
+
+    /// Encrypt a payload within our double ratchet session with a contact, starting a session
+    /// if we don't have one. The result must be sent authenticated, so that the contact knows
+    /// which session to decrypt it with.
+    pub fn ratchet_encrypt(
+        &mut self,
+        contact: &String,
+        payload: &Payload,
+    ) -> Result<Payload, NiwlError> {
+        let id = self.contact(contact)?.id();
+        let identity_key = &self.identity_key;
+        let contact = self.tagging_keys.get_mut(&id).unwrap();
+        let message =
+            contact
+                .sessions
+                .encrypt(identity_key, &contact.public_key, &payload.to_bytes()?)?;
+        Ok(Payload::Ratchet(message))
+    }
+
+    /// Decrypt a payload sent within a double ratchet session by a contact.
+    pub fn ratchet_decrypt(
+        &mut self,
+        contact: &String,
+        message: &RatchetMessage,
+    ) -> Result<Payload, NiwlError> {
+        let id = self.contact(contact)?.id();
+        let private_key = &self.private_key;
+        let contact = self.tagging_keys.get_mut(&id).unwrap();
+        let identity_key = contact.require_identity_key("for a session")?.clone();
+        let plaintext = contact
+            .sessions
+            .decrypt(private_key, &identity_key, message)?;
+        match Payload::from_bytes(&plaintext) {
+            Payload::Ratchet(_) => Err(NiwlError::SessionError(String::from(
+                "session messages cannot contain other session messages",
+            ))),
+            payload => Ok(payload),
+        }
+    }
+
+    /// Split a payload into fragments if it is too large to send through `hops` mixes in a
+    /// single packet (as a Sphinx packet if `sphinx` is set). Each fragment should be sent as a
+    /// separate message.
+    pub fn fragment(
         &self,
-        server: &String,
-        message: &TaggedCiphertext,
-    ) -> Result<Response, NiwlError> {
-        let client = reqwest::Client::new();
-        let tag = message.tag.clone();
-        let ciphertext = message.clone();
+        payload: Payload,
+        hops: usize,
+        sphinx: bool,
+    ) -> Result<Vec<Payload>, NiwlError> {
+        let available = match sphinx {
+            true => sphinx::MAX_PAYLOAD_LENGTH.saturating_sub(PACKET_OVERHEAD),
+            false => MAX_MESSAGE_LENGTH.saturating_sub(hops * PACKET_OVERHEAD),
+        };
+        let message = payload.to_bytes()?;
+        if message.len() <= available {
+            return Ok(vec![payload]);
+        }
+        let overhead = Payload::Fragment(fragment::split(&[0], 1)?.remove(0))
+            .to_bytes()?
+            .len()
+            - 1;
+        let fragments = fragment::split(&message, available.saturating_sub(overhead))?;
+        Ok(fragments.into_iter().map(Payload::Fragment).collect())
+    }
 
-        let result = client
-            .post(&format!("{}/new", server))
-            .json(&PostMessageRequest { tag, ciphertext })
-            .send()
-            .await;
-        match result {
-            Ok(response) => Ok(response),
-            Err(err) => Err(NiwlError::RemoteServerError(err.to_string())),
+    /// Add a fragment we have received to the message it belongs to. Returns the message and
+    /// its sender once all of its fragments have arrived.
+    pub fn reassemble(
+        &mut self,
+        sender: Option<String>,
+        fragment: Fragment,
+    ) -> Result<Option<(Option<String>, Payload)>, NiwlError> {
+        match self.fragments.add(sender, fragment)? {
+            Some((sender, message)) => match Payload::from_bytes(&message) {
+                Payload::Fragment(_) => Err(NiwlError::InvalidFragmentError(String::from(
+                    "fragments cannot contain other fragments",
+                ))),
+                payload => Ok(Some((sender, payload))),
+            },
+            None => Ok(None),
         }
     }
 
-    pub async fn tag_and_send(
+    /// Give up on messages that are still missing fragments `timeout` after the first fragment
+    /// arrived. Returns the id of each, and how many of its fragments were received.
+    pub fn expire_fragments(&mut self, timeout: Duration) -> Vec<(String, usize, usize)> {
+        self.fragments.expire(timeout)
+    }
+
+    /// Keep a SURB we have received so that we can reply with it later, returning its id.
+    pub fn store_reply_block(&mut self, contact: Option<String>, surb: Surb) -> String {
+        let contact = contact.and_then(|contact| self.contact_id(&contact));
+        let id = surb.id();
+        self.reply_blocks
+            .insert(id.clone(), ReplyBlock { contact, surb });
+        id
+    }
+
+    /// Reply using a SURB we have received. The SURB is forgotten once used.
+    pub fn reply(&mut self, id: &String, payload: &Payload) -> Result<TaggedCiphertext, NiwlError> {
+        let reply_block = self
+            .reply_blocks
+            .get(id)
+            .ok_or(NiwlError::NoKnownReplyBlockError(format!(
+                "No known reply block {}",
+                id
+            )))?;
+        let packet = reply_block.surb.reply(&payload.to_bytes()?)?;
+        self.reply_blocks.remove(id);
+        Ok(packet)
+    }
+
+    /// Onion encrypt a message to a contact through an ordered route of mixes. The packet for
+    /// each hop is embedded without filler inside the packet for the previous hop, and each mix
+    /// pads the inner packet before forwarding it, so every hop reduces the space available for
+    /// the message by PACKET_OVERHEAD bytes.
+    pub fn wrap_route(
         &self,
-        server: &String,
-        contact: String,
-        message: &String,
-    ) -> Result<Response, NiwlError> {
-        let client = reqwest::Client::new();
-        match self.generate_tag(&contact) {
-            Ok(tag) => {
-                let ciphertext = self.tagging_keys[&contact].1.encrypt(&tag, message);
-
-                let result = client
-                    .post(&format!("{}/new", server))
-                    .json(&PostMessageRequest { tag, ciphertext })
-                    .send()
-                    .await;
-                match result {
-                    Ok(response) => Ok(response),
-                    Err(err) => Err(NiwlError::RemoteServerError(err.to_string())),
+        route: &[String],
+        contact: &String,
+        payload: &Payload,
+        authenticated: bool,
+    ) -> Result<TaggedCiphertext, NiwlError> {
+        let message = payload.to_bytes()?;
+        let overhead = route.len() * PACKET_OVERHEAD;
+        if message.len() + overhead > MAX_MESSAGE_LENGTH {
+            return Err(NiwlError::MessageTooLongError(format!(
+                "message is {} bytes, the maximum for a route of {} mixes is {} bytes",
+                message.len(),
+                route.len(),
+                MAX_MESSAGE_LENGTH.saturating_sub(overhead)
+            )));
+        }
+
+        let mut packet = self.encrypt_for(contact, &message, authenticated)?;
+        for mix in route.iter().rev() {
+            packet = self.encrypt_for(mix, &packet.to_bytes(), false)?;
+        }
+        packet.pad();
+        Ok(packet)
+    }
+
+    /// Encrypt a message to a contact and wrap it in a Sphinx packet routed through an ordered
+    /// list of mixes. Unlike `wrap_route` the packet is the same size at every hop and does not
+    /// reveal a mix's position on the route.
+    pub fn sphinx_route(
+        &self,
+        route: &[String],
+        contact: &String,
+        payload: &Payload,
+        authenticated: bool,
+    ) -> Result<TaggedCiphertext, NiwlError> {
+        let payload = self.encrypt_for(contact, &payload.to_bytes()?, authenticated)?;
+        let mut hops = vec![];
+        for mix in route.iter() {
+            let tag = self.generate_tag(mix)?;
+            hops.push((&self.contact(mix)?.public_key, tag));
+        }
+        sphinx::create_packet(&hops, &payload)
+    }
+
+    /// Encrypt a message to ourselves, e.g. the heartbeats and cover traffic of a mix
+    pub fn encrypt_to_self(&self, message: &[u8]) -> Result<TaggedCiphertext, NiwlError> {
+        let tag = self.root_secret.tagging_key().generate_tag(&mut OsRng);
+        self.private_key.public_key().encrypt_bytes(&tag, message)
+    }
+
+    /// Our detection keys, those of retired keys first and our current key last
+    pub(crate) fn detection_keys(&self) -> Vec<DetectionKey<24>> {
+        self.retired_keys
+            .iter()
+            .map(|keys| &keys.root_secret)
+            .chain(std::iter::once(&self.root_secret))
+            .map(|root_secret| root_secret.extract_detection_key(self.detection_key_length))
+            .collect()
+    }
+
+    pub fn update_previously_seen_tag(&mut self, tag: &Tag<24>) {
+        self.last_seen_tag = Some(tag.clone());
+    }
+
+    /// The sequence number of the last message checked on a server, if we have synced with it
+    pub fn cursor(&self, server: &str) -> Option<u64> {
+        self.cursors.get(server).copied()
+    }
+
+    /// The request to detect the messages sent to a detection key since we last synced with a
+    /// server. Until we have a cursor for the server, the last tag seen is used to migrate to one.
+    pub(crate) fn fetch_request(
+        &self,
+        server: &str,
+        detection_key: DetectionKey<24>,
+    ) -> FetchMessagesRequest {
+        let cursor = self.cursor(server);
+        FetchMessagesRequest {
+            reference_tag: match cursor {
+                Some(_) => None,
+                None => self.last_seen_tag.clone(),
+            },
+            detection_key,
+            after_sequence: Some(cursor.unwrap_or(0)),
+            limit: None,
+            until_sequence: None,
+        }
+    }
+
+    /// Record that every message up to the cursor of `detected` has been handled
+    pub fn update_cursor(&mut self, server: &str, detected: &DetectedTags) {
+        if let Some(cursor) = detected.cursor {
+            self.cursors.insert(String::from(server), cursor);
+        }
+    }
+
+    /// Forget how far we have synced with a server, so that every message it still has is
+    /// checked again. Used to recover from an expired cursor.
+    pub fn reset_cursor(&mut self, server: &str) {
+        self.cursors.remove(server);
+        self.last_seen_tag = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encrypt::TaggedCiphertext;
+    use crate::sphinx::{self, ProcessedPacket};
+    use crate::storage::{KdfParams, StorageKey};
+    use crate::uri::UnsignedKeySet;
+    use crate::{Contact, NiwlError, Payload, Profile, SignedKeySet};
+    use chrono::{Duration, Local};
+    use rand::rngs::OsRng;
+
+    fn add_contact(profile: &mut Profile, petname: &str, other: &Profile) {
+        let keyset = other.keyset().unwrap();
+        let contact = Contact {
+            petname: String::from(petname),
+            tagging_key: keyset.tagging_key,
+            public_key: keyset.public_key,
+            identity_key: Some(keyset.identity_key),
+            sessions: Default::default(),
+            verified: false,
+            mix: false,
+        };
+        profile.tagging_keys.insert(contact.id(), contact);
+    }
+
+    fn message(text: &str) -> Payload {
+        Payload::Message(text.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_wrap_route() {
+        let mut alice = Profile::new(String::from("alice"), 2);
+        let bob = Profile::new(String::from("bob"), 2);
+        let mix1 = Profile::new(String::from("mix1"), 0);
+        let mix2 = Profile::new(String::from("mix2"), 0);
+        add_contact(&mut alice, "bob", &bob);
+        add_contact(&mut alice, "mix1", &mix1);
+        add_contact(&mut alice, "mix2", &mix2);
+
+        let route = vec![String::from("mix1"), String::from("mix2")];
+        let packet = alice
+            .wrap_route(&route, &String::from("bob"), &message("hello bob"), false)
+            .unwrap();
+        assert!(packet.is_fixed_size());
+
+        // Each mix peels off one layer and pads the inner packet before forwarding it
+        let mut packet = packet;
+        for mix in [&mix1, &mix2].iter() {
+            let inner = mix.private_key.decrypt_bytes(&packet).unwrap();
+            packet = TaggedCiphertext::from_bytes(&inner).unwrap();
+            packet.pad();
+            assert!(packet.is_fixed_size());
+        }
+        let (_, payload) = bob.decrypt(&packet).unwrap();
+        assert!(matches!(payload, Payload::Message(m) if m == b"hello bob".to_vec()));
+    }
+
+    #[test]
+    fn test_sphinx_route() {
+        let mut alice = Profile::new(String::from("alice"), 2);
+        let bob = Profile::new(String::from("bob"), 2);
+        let mix1 = Profile::new(String::from("mix1"), 0);
+        let mix2 = Profile::new(String::from("mix2"), 0);
+        add_contact(&mut alice, "bob", &bob);
+        add_contact(&mut alice, "mix1", &mix1);
+        add_contact(&mut alice, "mix2", &mix2);
+
+        let route = vec![String::from("mix1"), String::from("mix2")];
+        let mut packet = alice
+            .sphinx_route(&route, &String::from("bob"), &message("hello bob"), true)
+            .unwrap();
+        for mix in [&mix1, &mix2].iter() {
+            assert!(packet.is_fixed_size());
+            packet = match sphinx::process_packet(&mix.private_key, &packet).unwrap().1 {
+                ProcessedPacket::Relay(next) => next,
+                ProcessedPacket::Deliver(delivered) => delivered,
+            };
+        }
+        let (_, payload) = bob.decrypt(&packet).unwrap();
+        assert!(matches!(payload, Payload::Message(m) if m == b"hello bob".to_vec()));
+    }
+
+    #[test]
+    fn test_reply_block() {
+        let mut alice = Profile::new(String::from("alice"), 2);
+        let mut bob = Profile::new(String::from("bob"), 2);
+        let mix = Profile::new(String::from("mix"), 0);
+        add_contact(&mut alice, "bob", &bob);
+        add_contact(&mut alice, "mix", &mix);
+        add_contact(&mut bob, "mix", &mix);
+
+        // Alice sends bob a message via the mix, with a SURB routed back through the mix
+        let route = vec![String::from("mix")];
+        let surb = alice.create_surb(&route, &String::from("bob")).unwrap();
+        let payload = Payload::Replyable(surb, b"hello bob".to_vec());
+        let packet = alice
+            .sphinx_route(&route, &String::from("bob"), &payload, false)
+            .unwrap();
+        let packet = match sphinx::process_packet(&mix.private_key, &packet).unwrap().1 {
+            ProcessedPacket::Deliver(delivered) => delivered,
+            ProcessedPacket::Relay(_) => panic!("expected the packet to be delivered"),
+        };
+
+        // Bob doesn't know alice, but can still reply using the SURB
+        let id = match bob.decrypt(&packet).unwrap() {
+            (None, Payload::Replyable(surb, _)) => bob.store_reply_block(None, surb),
+            _ => panic!("expected a replyable message"),
+        };
+        let reply = bob.reply(&id, &message("hello alice")).unwrap();
+        assert!(bob.reply(&id, &message("hello again")).is_err());
+
+        let reply = match sphinx::process_packet(&mix.private_key, &reply).unwrap().1 {
+            ProcessedPacket::Deliver(delivered) => delivered,
+            ProcessedPacket::Relay(_) => panic!("expected the reply to be delivered"),
+        };
+        assert!(reply.is_fixed_size());
+        assert!(alice.private_key.decrypt_bytes(&reply).is_none());
+        let (contact, payload) = alice.decrypt_reply(&reply).unwrap();
+        assert_eq!(contact, String::from("bob"));
+        assert!(matches!(payload, Payload::Message(m) if m == b"hello alice".to_vec()));
+        assert!(alice.decrypt_reply(&reply).is_err());
+    }
+
+    #[test]
+    fn test_fragmented_message() {
+        let mut alice = Profile::new(String::from("alice"), 2);
+        let mut bob = Profile::new(String::from("bob"), 2);
+        add_contact(&mut alice, "bob", &bob);
+
+        let long = vec![b'a'; 4000];
+        let payloads = alice
+            .fragment(Payload::Message(long.clone()), 0, false)
+            .unwrap();
+        assert!(payloads.len() > 1);
+
+        // Each fragment is sent as its own packet, and they may arrive in any order
+        let mut packets = vec![];
+        for payload in payloads.iter() {
+            let packet = alice
+                .wrap_route(&[], &String::from("bob"), payload, true)
+                .unwrap();
+            assert!(packet.is_fixed_size());
+            packets.push(packet);
+        }
+        packets.reverse();
+
+        let mut received = None;
+        for packet in packets.iter() {
+            assert!(received.is_none());
+            match bob.decrypt(packet).unwrap() {
+                (sender, Payload::Fragment(fragment)) => {
+                    received = bob.reassemble(sender, fragment).unwrap();
+                }
+                _ => panic!("expected a fragment"),
+            }
+        }
+        let (_, payload) = received.unwrap();
+        assert!(matches!(payload, Payload::Message(m) if m == long));
+    }
+
+    #[test]
+    fn test_ratchet_session() {
+        let mut alice = Profile::new(String::from("alice"), 2);
+        let mut bob = Profile::new(String::from("bob"), 2);
+        add_contact(&mut alice, "bob", &bob);
+        add_contact(&mut bob, "alice", &alice);
+
+        let payload = alice
+            .ratchet_encrypt(&String::from("bob"), &message("hello bob"))
+            .unwrap();
+        let packet = alice
+            .wrap_route(&[], &String::from("bob"), &payload, true)
+            .unwrap();
+        match bob.decrypt(&packet).unwrap() {
+            (Some(sender), Payload::Ratchet(message)) => {
+                let payload = bob.ratchet_decrypt(&sender, &message).unwrap();
+                assert!(matches!(payload, Payload::Message(m) if m == b"hello bob".to_vec()));
+            }
+            _ => panic!("expected a session message from alice"),
+        }
+    }
+
+    #[test]
+    fn test_encrypted_profile() {
+        let filename = std::env::temp_dir()
+            .join(format!("niwl-test-{}.profile", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let passphrase = String::from("correct horse");
+        let mut profile = Profile::new(String::from("alice"), 2);
+        let params = KdfParams {
+            memory_cost: 8,
+            time_cost: 1,
+            parallelism: 1,
+        };
+        profile.storage_key = Some(StorageKey::derive(&passphrase, params).unwrap());
+        profile.save(&filename).unwrap();
+
+        let data = std::fs::read(&filename).unwrap();
+        assert!(Profile::is_encrypted(&filename).unwrap());
+        assert!(!String::from_utf8_lossy(&data).contains("alice"));
+        assert!(Profile::open(&filename, None).is_err());
+        assert!(Profile::open(&filename, Some(&String::from("battery staple"))).is_err());
+
+        let mut opened = Profile::open(&filename, Some(&passphrase)).unwrap();
+        assert!(opened.keyset().unwrap().public_key == profile.keyset().unwrap().public_key);
+        opened.set_passphrase(None).unwrap();
+        opened.save(&filename).unwrap();
+        assert!(!Profile::is_encrypted(&filename).unwrap());
+        assert!(Profile::open(&filename, None).is_ok());
+        for suffix in ["", ".bak", ".lock"].iter() {
+            std::fs::remove_file(format!("{}{}", filename, suffix)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_debug_is_redacted() {
+        let mut alice = Profile::new(String::from("alice"), 2);
+        let bob = Profile::new(String::from("bob"), 2);
+        add_contact(&mut alice, "bob", &bob);
+        alice
+            .ratchet_encrypt(&String::from("bob"), &message("hello"))
+            .unwrap();
+
+        let debug = format!("{:?}", alice);
+        assert!(debug.contains("alice") && debug.contains("bob"));
+        assert!(debug.contains("[REDACTED]"));
+        for secret in [&alice.private_key, &alice.identity_key].iter() {
+            assert!(!debug.contains(&format!("{:?}", secret.0)));
+            assert!(!debug.contains(&format!("{:?}", secret.0.as_bytes())));
+        }
+    }
+
+    #[test]
+    fn test_restore_from_seed_phrase() {
+        let mut alice = Profile::new(String::from("alice"), 2);
+        let bob = Profile::new(String::from("bob"), 2);
+        let mix = Profile::new(String::from("mix"), 0);
+        add_contact(&mut alice, "bob", &bob);
+        add_contact(&mut alice, "mix", &mix);
+        let mix_name = String::from("mix");
+        alice.contact_mut(&mix_name).unwrap().mix = true;
+        alice
+            .ratchet_encrypt(&String::from("bob"), &message("hello"))
+            .unwrap();
+        let export = alice.export_contacts().unwrap();
+
+        let phrase = alice.seed_phrase().unwrap();
+        let mut restored = Profile::restore(String::from("alice"), 2, &phrase).unwrap();
+        assert!(restored.keyset().unwrap().public_key == alice.keyset().unwrap().public_key);
+        assert!(restored.keyset().unwrap().identity_key == alice.keyset().unwrap().identity_key);
+        assert_eq!(
+            bincode::serialize(&restored.root_secret.tagging_key()).unwrap(),
+            bincode::serialize(&alice.root_secret.tagging_key()).unwrap()
+        );
+
+        assert!(Profile::new(String::from("eve"), 2)
+            .import_contacts(&export)
+            .is_err());
+        assert_eq!(
+            restored.import_contacts(&export).unwrap(),
+            vec![String::from("bob"), String::from("mix")]
+        );
+        // Sessions are not exported, so a restored profile starts a new one
+        let bob_name = String::from("bob");
+        assert!(
+            restored.contact(&bob_name).unwrap().public_key
+                == alice.contact(&bob_name).unwrap().public_key
+        );
+        assert_eq!(
+            format!("{:?}", restored.contact(&bob_name).unwrap().sessions),
+            "Sessions(0 [REDACTED])"
+        );
+        // Mixes are still known as mixes, so we can route through them
+        assert!(restored.contact(&mix_name).unwrap().is_mix());
+        assert!(!restored.contact(&bob_name).unwrap().is_mix());
+        assert!(restored.import_contacts(&export).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let mut alice = Profile::new(String::from("alice"), 2);
+        let mut bob = Profile::new(String::from("bob"), 2);
+        add_contact(&mut alice, "bob", &bob);
+        add_contact(&mut bob, "alice", &alice);
+        let before = bob
+            .encrypt_for(
+                &String::from("alice"),
+                &message("before").to_bytes().unwrap(),
+                true,
+            )
+            .unwrap();
+
+        let old_phrase = alice.seed_phrase().unwrap();
+        let mut notices = alice.rotate_keys(Duration::days(7)).unwrap();
+        assert_eq!(notices.len(), 1);
+        assert_ne!(*alice.seed_phrase().unwrap(), *old_phrase);
+        let (to, notice) = notices.remove(0);
+        assert_eq!(to, "bob");
+        assert!(notice.is_fixed_size());
+
+        // Bob only accepts the notice because it is authenticated by alice's old identity key
+        match bob.decrypt(&notice) {
+            Ok((Some(sender), Payload::KeyRotation(keyset))) => {
+                assert_eq!(sender, "alice");
+                bob.apply_key_rotation(&sender, keyset).unwrap();
+            }
+            _ => panic!("expected a key rotation notice from alice"),
+        }
+        assert!(
+            bob.contact(&String::from("alice")).unwrap().public_key
+                == alice.keyset().unwrap().public_key
+        );
+
+        let after = bob
+            .encrypt_for(
+                &String::from("alice"),
+                &message("after").to_bytes().unwrap(),
+                true,
+            )
+            .unwrap();
+        for (ciphertext, text) in [(&before, "before"), (&after, "after")].iter() {
+            match alice.decrypt(ciphertext) {
+                Ok((Some(sender), Payload::Message(m))) => {
+                    assert_eq!(sender, "bob");
+                    assert_eq!(m, text.as_bytes());
                 }
+                _ => panic!("alice should decrypt messages to her old and new keys"),
             }
-            Err(err) => Err(err),
         }
+
+        assert_eq!(alice.expire_retired_keys(), 0);
+        alice.retired_keys[0].expires = Local::now() - Duration::seconds(1);
+        assert_eq!(alice.expire_retired_keys(), 1);
+        assert!(alice.decrypt(&before).is_err());
+        assert!(alice.decrypt(&after).is_ok());
+    }
+
+    #[test]
+    fn test_rotated_keys_replace_imported_keys() {
+        let mut mix = Profile::new(String::from("mix"), 0);
+        let mut alice = Profile::new(String::from("alice"), 2);
+        let petname = String::from("my mix");
+        alice
+            .import_tagging_key(&mix.export_keyset(true).unwrap(), Some(&petname))
+            .unwrap();
+        alice.contact_mut(&petname).unwrap().verified = true;
+        let old_tag = mix.root_secret.tagging_key().generate_tag(&mut OsRng);
+
+        assert!(mix.rotate_keys(Duration::days(7)).unwrap().is_empty());
+        let private_keys = mix.private_keys();
+        assert_eq!(private_keys.len(), 2);
+        assert!(private_keys[0].public_key() == mix.private_key.public_key());
+        let new_tag = mix.root_secret.tagging_key().generate_tag(&mut OsRng);
+        let stranger = Profile::new(String::from("stranger"), 0);
+        assert!(mix.owns_tag(&old_tag) && mix.owns_tag(&new_tag));
+        assert!(!mix.owns_tag(&stranger.root_secret.tagging_key().generate_tag(&mut OsRng)));
+
+        // The new keys replace the old ones, keeping the petname and the verification
+        let exported = mix.export_keyset(true).unwrap();
+        assert!(exported.contains("&previous="));
+        assert_eq!(alice.import_tagging_key(&exported, None).unwrap(), petname);
+        assert_eq!(alice.tagging_keys.len(), 1);
+        let contact = alice.contact(&petname).unwrap();
+        assert_eq!(contact.id(), mix.root_secret.tagging_key().id());
+        assert!(contact.is_verified() && contact.mix);
+        assert!(alice.import_tagging_key(&exported, None).is_err());
+
+        // Someone who never had the old keys just imports the new ones
+        let mut bob = Profile::new(String::from("bob"), 2);
+        assert_eq!(bob.import_tagging_key(&exported, None).unwrap(), "mix");
+
+        // The proof is only good for the keys it was made for
+        let mut mallory = Profile::new(String::from("mallory"), 0);
+        mallory.rotated_from = mix.rotated_from.clone();
+        let forged = mallory.export_keyset(true).unwrap();
+        assert_eq!(alice.import_tagging_key(&forged, None).unwrap(), "mallory");
+        assert_eq!(alice.tagging_keys.len(), 2);
+        assert!(!alice.is_verified(&String::from("mallory")));
+    }
+
+    #[test]
+    fn test_signed_keysets_and_safety_numbers() {
+        let mut alice = Profile::new(String::from("alice"), 2);
+        let mut bob = Profile::new(String::from("bob"), 2);
+        alice
+            .import_tagging_key(&bob.export_keyset(false).unwrap(), None)
+            .unwrap();
+        bob.import_tagging_key(&alice.export_keyset(false).unwrap(), None)
+            .unwrap();
+        let (alice_id, bob_id) = (String::from("alice"), String::from("bob"));
+        assert!(alice.contact(&bob_id).is_ok());
+
+        // A relay that swaps in its own public key breaks the signature
+        let mallory = Profile::new(String::from("bob"), 2);
+        let data = base32::decode(
+            base32::Alphabet::RFC4648 { padding: false },
+            bob.export_keyset(false).unwrap().split(':').nth(2).unwrap(),
+        )
+        .unwrap();
+        let mut signed: SignedKeySet = bincode::deserialize(&data).unwrap();
+        signed.keyset.public_key = mallory.keyset().unwrap().public_key;
+        assert!(signed.verify().is_err());
+
+        let number = alice.safety_number(&bob_id).unwrap();
+        assert_eq!(number, bob.safety_number(&alice_id).unwrap());
+        assert_eq!(number.split(' ').count(), 12);
+        assert!(!alice.is_verified(&bob_id));
+        assert!(alice.verify_contact(&bob_id, "12345").is_err());
+        alice
+            .verify_contact(&bob_id, &number.replace(' ', ""))
+            .unwrap();
+        assert!(alice.is_verified(&bob_id));
+        alice.unverify_contact(&bob_id).unwrap();
+        assert!(!alice.is_verified(&bob_id));
     }
 
-    pub async fn detect_tags(&mut self, server: &String) -> Result<DetectedTags, Error> {
-        let client = reqwest::Client::new();
-        let detection_key = self
-            .root_secret
-            .extract_detection_key(self.detection_key_length);
-        let result = client
-            .post(&format!("{}/tags", server))
-            .json(&FetchMessagesRequest {
-                reference_tag: self.last_seen_tag.clone(),
-                detection_key,
+    #[test]
+    fn test_unsigned_keysets_are_imported_unverified() {
+        let mut alice = Profile::new(String::from("alice"), 2);
+        let mut bob = Profile::new(String::from("bob"), 2);
+        bob.import_tagging_key(&alice.export_keyset(false).unwrap(), None)
+            .unwrap();
+
+        // Before keysets were signed they were exported as the bare base32 of these keys
+        let keyset = bob.keyset().unwrap();
+        let unsigned = base32::encode(
+            base32::Alphabet::RFC4648 { padding: false },
+            &bincode::serialize(&UnsignedKeySet {
+                profile_name: keyset.profile_name,
+                tagging_key: keyset.tagging_key,
+                public_key: keyset.public_key,
             })
-            .send()
-            .await;
-        result.unwrap().json().await
+            .unwrap(),
+        );
+        let bob_name = alice.import_tagging_key(&unsigned, None).unwrap();
+        assert_eq!(bob_name, "bob");
+        let contact = alice.contact(&bob_name).unwrap();
+        assert_eq!(contact.id(), bob.root_secret.tagging_key().id());
+        assert!(contact.identity_key().is_none());
+        assert!(!contact.is_verified());
+
+        // Without an identity key there is no safety number to compare
+        assert!(matches!(
+            alice.safety_number(&bob_name),
+            Err(NiwlError::VerificationError(_))
+        ));
+        assert!(alice.verify_contact(&bob_name, "").is_err());
+        assert!(!alice.is_verified(&bob_name));
+
+        // Messages can still be exchanged, but bob's can't be attributed to him
+        let to_bob = alice
+            .encrypt_for(&bob_name, &message("hi bob").to_bytes().unwrap(), true)
+            .unwrap();
+        match bob.decrypt(&to_bob) {
+            Ok((Some(sender), Payload::Message(m))) => {
+                assert_eq!(sender, "alice");
+                assert_eq!(m, b"hi bob".to_vec());
+            }
+            _ => panic!("bob should decrypt a message from alice"),
+        }
+        let to_alice = bob
+            .encrypt_for(
+                &String::from("alice"),
+                &message("hi alice").to_bytes().unwrap(),
+                true,
+            )
+            .unwrap();
+        assert!(matches!(
+            alice.decrypt(&to_alice),
+            Ok((None, Payload::Message(_)))
+        ));
     }
 
-    pub fn update_previously_seen_tag(&mut self, tag: &Tag<24>) {
-        self.last_seen_tag = Some(tag.clone());
+    #[test]
+    fn test_contact_management() {
+        let mut alice = Profile::new(String::from("alice"), 2);
+        let bob = Profile::new(String::from("bob"), 2);
+        let other_bob = Profile::new(String::from("bob"), 2);
+        let (bob_name, builder) = (String::from("bob"), String::from("bob the builder"));
+        assert_eq!(
+            alice
+                .import_tagging_key(&bob.export_keyset(false).unwrap(), None)
+                .unwrap(),
+            bob_name
+        );
+        // A second "bob" needs a petname of its own, and the same key can't be imported twice
+        assert!(alice
+            .import_tagging_key(&other_bob.export_keyset(false).unwrap(), None)
+            .is_err());
+        assert!(alice
+            .import_tagging_key(&bob.export_keyset(false).unwrap(), Some(&builder))
+            .is_err());
+        alice
+            .import_tagging_key(&other_bob.export_keyset(false).unwrap(), Some(&builder))
+            .unwrap();
+        let petnames: Vec<&String> = alice
+            .contacts()
+            .unwrap()
+            .iter()
+            .map(|c| c.petname())
+            .collect();
+        assert_eq!(petnames, vec![&bob_name, &builder]);
+
+        // Contacts are filed under their tagging key id, and can be found by either
+        let id = bob.root_secret.tagging_key().id();
+        assert_eq!(alice.contact(&bob_name).unwrap().id(), id);
+        assert_eq!(alice.contact(&id).unwrap().petname(), &bob_name);
+
+        assert!(alice.rename_contact(&builder, &bob_name).is_err());
+        alice
+            .rename_contact(&bob_name, &String::from("robert"))
+            .unwrap();
+        assert!(alice.contact(&bob_name).is_err());
+        assert_eq!(alice.contact(&id).unwrap().petname(), "robert");
+        alice.remove_contact(&String::from("robert")).unwrap();
+        assert!(alice.contact(&id).is_err());
+        assert_eq!(alice.contacts().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_contacts_are_migrated() {
+        let filename = std::env::temp_dir()
+            .join(format!("niwl-test-migrate-{}.profile", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let mut alice = Profile::new(String::from("alice"), 2);
+        let bob = Profile::new(String::from("bob"), 2);
+        let bob_name = String::from("bob");
+        let mix = Profile::new(String::from("mix"), 0);
+        add_contact(&mut alice, "bob", &bob);
+        add_contact(&mut alice, "mix", &mix);
+        alice
+            .create_surb(&[String::from("mix")], &bob_name)
+            .unwrap();
+
+        // Older versions filed contacts under the name they advertised, without a petname
+        let id = alice.contact(&bob_name).unwrap().id();
+        let mut contact = alice.tagging_keys.remove(&id).unwrap();
+        contact.petname = String::new();
+        alice.tagging_keys.insert(bob_name.clone(), contact);
+        for issued in alice.issued_surbs.values_mut() {
+            issued.contact = bob_name.clone();
+        }
+        alice.save(&filename).unwrap();
+
+        let opened = Profile::open(&filename, None).unwrap();
+        for suffix in ["", ".lock"].iter() {
+            std::fs::remove_file(format!("{}{}", filename, suffix)).unwrap();
+        }
+        assert!(opened.tagging_keys.contains_key(&id));
+        assert_eq!(opened.contact(&bob_name).unwrap().petname(), &bob_name);
+        assert!(opened
+            .issued_surbs
+            .values()
+            .all(|issued| issued.contact == id));
+    }
+
+    #[test]
+    fn test_io_errors_are_returned() {
+        let missing = std::env::temp_dir()
+            .join(format!("niwl-test-missing-{}", std::process::id()))
+            .join("niwl.profile")
+            .to_string_lossy()
+            .to_string();
+        let error = Profile::get_profile(&missing).unwrap_err();
+        assert!(matches!(&error, NiwlError::IoError(_)));
+        assert!(std::error::Error::source(&error).is_some());
+        assert!(matches!(
+            Profile::is_encrypted(&missing),
+            Err(NiwlError::IoError(_))
+        ));
+        assert!(matches!(
+            Profile::new(String::from("alice"), 2).save(&missing),
+            Err(NiwlError::IoError(_))
+        ));
+        assert_eq!(
+            NiwlError::HttpStatusError(404, String::from("not found")).to_string(),
+            "server responded with status 404: not found"
+        );
+    }
+
+    #[test]
+    fn test_identity_key_is_derived_for_older_profiles() {
+        let filename = std::env::temp_dir()
+            .join(format!("niwl-test-identity-{}.profile", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let mut alice = Profile::new(String::from("alice"), 2);
+        let bob = Profile::new(String::from("bob"), 2);
+        add_contact(&mut alice, "bob", &bob);
+
+        // Profiles saved before identity keys were introduced have none
+        let mut json: serde_json::Value =
+            serde_json::from_slice(&crate::storage::to_json(&alice)).unwrap();
+        json.as_object_mut().unwrap().remove("identity_key");
+        std::fs::write(&filename, serde_json::to_vec(&json).unwrap()).unwrap();
+
+        let bob_name = String::from("bob");
+        let first = Profile::open(&filename, None).unwrap();
+        let second = Profile::open(&filename, None).unwrap();
+        std::fs::remove_file(&filename).unwrap();
+        assert_eq!(
+            first.safety_number(&bob_name).unwrap(),
+            second.safety_number(&bob_name).unwrap()
+        );
+        assert!(first.keyset().unwrap().identity_key == second.keyset().unwrap().identity_key);
+        assert!(first.keyset().unwrap().identity_key != first.keyset().unwrap().public_key);
+    }
+
+    #[test]
+    fn test_concurrent_saves_are_detected() {
+        let filename = std::env::temp_dir()
+            .join(format!("niwl-test-save-{}.profile", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        Profile::new(String::from("alice"), 2)
+            .save(&filename)
+            .unwrap();
+        let mut first = Profile::get_profile(&filename).unwrap();
+        let mut second = Profile::get_profile(&filename).unwrap();
+        let bob = Profile::new(String::from("bob"), 2);
+        add_contact(&mut first, "bob", &bob);
+        first.save(&filename).unwrap();
+        // Saving twice from the same process is fine, the fingerprint follows our own saves
+        first.save(&filename).unwrap();
+
+        // The second process would discard bob, so its save is refused
+        assert!(matches!(
+            second.save(&filename),
+            Err(NiwlError::StorageError(_))
+        ));
+        let mut reopened = Profile::get_profile(&filename).unwrap();
+        assert!(reopened.contact(&String::from("bob")).is_ok());
+        reopened.save(&filename).unwrap();
+
+        // The previous version is kept as a backup, and no temporary file is left behind
+        let backup = Profile::get_profile(&format!("{}.bak", filename)).unwrap();
+        assert!(backup.contact(&String::from("bob")).is_ok());
+        assert!(!std::path::Path::new(&format!("{}.tmp", filename)).exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&filename).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        for suffix in ["", ".bak", ".lock"].iter() {
+            std::fs::remove_file(format!("{}{}", filename, suffix)).unwrap();
+        }
     }
 }
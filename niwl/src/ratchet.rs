@@ -0,0 +1,447 @@
+//! An optional session layer running a double ratchet between two contacts.
+//!
+//! Every niwl ciphertext uses a fresh ephemeral key, but is always encrypted to the static
+//! public key of the recipient - anyone who later compromises that key can decrypt every message
+//! ever sent to it. Messages sent within a session are additionally encrypted with keys from a
+//! double ratchet: a hash ratchet derives a new key for every message, and a diffie-hellman
+//! ratchet mixes in new ephemeral keys whenever the direction of the conversation changes. Keys
+//! are deleted once used, giving forward secrecy, and the diffie-hellman ratchet lets a session
+//! recover from the compromise of its state (post-compromise security).
+//!
+//! A session is started by the first message sent to a contact. The initial root key is derived
+//! from our identity key and the contact's public key, and the first messages are flagged so the
+//! contact can set up the other side of the session. Until the contact replies these messages
+//! are only protected by the contact's static key like any other ciphertext: they have no
+//! forward secrecy, and anyone who compromises that key can decrypt them. If both parties start a
+//! session at the same time each ends up using the session the other started, so each party
+//! keeps a handful of recent sessions per contact. Session messages must be sent authenticated,
+//! so that the recipient knows which contact's sessions to use.
+use crate::encrypt::{PrivateKey, PublicKey};
+use crate::NiwlError;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::digest::Digest;
+use curve25519_dalek::ristretto::RistrettoPoint;
+use curve25519_dalek::scalar::Scalar;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha3::Sha3_256;
+use std::convert::TryInto;
+use std::fmt;
+use std::ops::Mul;
+use zeroize::Zeroize;
+
+/// The maximum number of message keys that will be derived and stored for messages that have
+/// not yet arrived
+pub const MAX_SKIP: u32 = 1000;
+
+/// The number of sessions kept per contact
+pub const MAX_SESSIONS: usize = 4;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Header {
+    session: [u8; 16],
+    // Set on messages sent before the other party has set up the session
+    initiate: bool,
+    ratchet_key: RistrettoPoint,
+    previous_chain_length: u32,
+    message_number: u32,
+}
+
+/// A message encrypted within a session
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RatchetMessage {
+    header: Header,
+    ciphertext: Vec<u8>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SkippedKey {
+    ratchet_key: RistrettoPoint,
+    message_number: u32,
+    key: [u8; 32],
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Session {
+    id: [u8; 16],
+    initiating: bool,
+    root_key: [u8; 32],
+    sending_key: Scalar,
+    receiving_key: Option<RistrettoPoint>,
+    sending_chain: Option<[u8; 32]>,
+    receiving_chain: Option<[u8; 32]>,
+    sent: u32,
+    received: u32,
+    previous_chain_length: u32,
+    skipped: Vec<SkippedKey>,
+}
+
+// Keys are wiped as soon as they are dropped, including the copies made when a session is
+// cloned to try decrypting a message
+impl Drop for SkippedKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.root_key.zeroize();
+        self.sending_key.zeroize();
+        if let Some(chain_key) = self.sending_chain.as_mut() {
+            chain_key.zeroize();
+        }
+        if let Some(chain_key) = self.receiving_chain.as_mut() {
+            chain_key.zeroize();
+        }
+    }
+}
+
+/// The sessions we have with a contact, most recently used first
+#[derive(Default, Serialize, Deserialize)]
+pub struct Sessions {
+    sessions: Vec<Session>,
+}
+
+impl fmt::Debug for Sessions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Sessions({} [REDACTED])", self.sessions.len())
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hash = Sha3_256::new();
+    for part in parts {
+        hash.update(part);
+    }
+    hash.finalize().into()
+}
+
+// Derive a new root key and chain key from a diffie-hellman output
+fn kdf_root(root_key: &[u8; 32], shared: &RistrettoPoint) -> ([u8; 32], [u8; 32]) {
+    let shared = shared.compress();
+    (
+        hash(&[b"niwl-ratchet-root", root_key, shared.as_bytes()]),
+        hash(&[b"niwl-ratchet-chain", root_key, shared.as_bytes()]),
+    )
+}
+
+// Derive the next chain key and a message key from a chain key
+fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    (
+        hash(&[b"niwl-ratchet-next", chain_key]),
+        hash(&[b"niwl-ratchet-message", chain_key]),
+    )
+}
+
+fn session_id(ratchet_key: &RistrettoPoint) -> [u8; 16] {
+    hash(&[b"niwl-ratchet-session", ratchet_key.compress().as_bytes()])[0..16]
+        .try_into()
+        .unwrap()
+}
+
+// Each message key is only used once, so the nonce can be derived from it
+fn cipher(message_key: &[u8; 32]) -> (XChaCha20Poly1305, [u8; 24]) {
+    let nonce = hash(&[b"niwl-ratchet-nonce", message_key]);
+    (
+        XChaCha20Poly1305::new(Key::from_slice(message_key)),
+        nonce[0..24].try_into().unwrap(),
+    )
+}
+
+fn session_error(reason: &str) -> NiwlError {
+    NiwlError::SessionError(String::from(reason))
+}
+
+impl Session {
+    fn initiate(identity: &PrivateKey, their_public_key: &PublicKey) -> Session {
+        let shared_secret = hash(&[
+            b"niwl-ratchet-init",
+            their_public_key.0.mul(identity.0).compress().as_bytes(),
+        ]);
+        let sending_key = Scalar::random(&mut OsRng);
+        let ratchet_key = RISTRETTO_BASEPOINT_POINT.mul(sending_key);
+        let (root_key, sending_chain) =
+            kdf_root(&shared_secret, &their_public_key.0.mul(sending_key));
+        Session {
+            id: session_id(&ratchet_key),
+            initiating: true,
+            root_key,
+            sending_key,
+            receiving_key: Some(their_public_key.0),
+            sending_chain: Some(sending_chain),
+            receiving_chain: None,
+            sent: 0,
+            received: 0,
+            previous_chain_length: 0,
+            skipped: vec![],
+        }
+    }
+
+    fn respond(private_key: &PrivateKey, their_identity: &PublicKey, header: &Header) -> Session {
+        let shared_secret = hash(&[
+            b"niwl-ratchet-init",
+            their_identity.0.mul(private_key.0).compress().as_bytes(),
+        ]);
+        // The initiator's first chain is derived from our static key. Take the first
+        // diffie-hellman step now so that only fresh ratchet keys are kept in the session.
+        let (root_key, receiving_chain) =
+            kdf_root(&shared_secret, &header.ratchet_key.mul(private_key.0));
+        let sending_key = Scalar::random(&mut OsRng);
+        let (root_key, sending_chain) = kdf_root(&root_key, &header.ratchet_key.mul(sending_key));
+        Session {
+            id: header.session,
+            initiating: false,
+            root_key,
+            sending_key,
+            receiving_key: Some(header.ratchet_key),
+            sending_chain: Some(sending_chain),
+            receiving_chain: Some(receiving_chain),
+            sent: 0,
+            received: 0,
+            previous_chain_length: 0,
+            skipped: vec![],
+        }
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8]) -> Result<RatchetMessage, NiwlError> {
+        let chain_key = self
+            .sending_chain
+            .ok_or(session_error("session has no sending chain"))?;
+        let (chain_key, mut message_key) = kdf_chain(&chain_key);
+        self.sending_chain = Some(chain_key);
+        let header = Header {
+            session: self.id,
+            initiate: self.initiating,
+            ratchet_key: RISTRETTO_BASEPOINT_POINT.mul(self.sending_key),
+            previous_chain_length: self.previous_chain_length,
+            message_number: self.sent,
+        };
+        self.sent += 1;
+
+        let (cipher, nonce) = cipher(&message_key);
+        message_key.zeroize();
+        let aad = bincode::serialize(&header)?;
+        let ciphertext = cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| session_error("could not encrypt message"))?;
+        Ok(RatchetMessage { header, ciphertext })
+    }
+
+    // Store the keys for messages in the current receiving chain up to `until`
+    fn skip(&mut self, until: u32) -> Result<(), NiwlError> {
+        if self.received.saturating_add(MAX_SKIP) < until {
+            return Err(session_error("too many skipped messages"));
+        }
+        if let (Some(mut chain_key), Some(ratchet_key)) = (self.receiving_chain, self.receiving_key)
+        {
+            while self.received < until {
+                let (next, key) = kdf_chain(&chain_key);
+                self.skipped.push(SkippedKey {
+                    ratchet_key,
+                    message_number: self.received,
+                    key,
+                });
+                chain_key = next;
+                self.received += 1;
+            }
+            self.receiving_chain = Some(chain_key);
+        }
+        let excess = self.skipped.len().saturating_sub(MAX_SKIP as usize);
+        self.skipped.drain(0..excess);
+        Ok(())
+    }
+
+    fn ratchet(&mut self, ratchet_key: &RistrettoPoint) {
+        self.previous_chain_length = self.sent;
+        self.sent = 0;
+        self.received = 0;
+        self.receiving_key = Some(*ratchet_key);
+        let (root_key, receiving_chain) =
+            kdf_root(&self.root_key, &ratchet_key.mul(self.sending_key));
+        self.sending_key = Scalar::random(&mut OsRng);
+        let (root_key, sending_chain) = kdf_root(&root_key, &ratchet_key.mul(self.sending_key));
+        self.root_key = root_key;
+        self.receiving_chain = Some(receiving_chain);
+        self.sending_chain = Some(sending_chain);
+    }
+
+    fn message_key(&mut self, header: &Header) -> Result<[u8; 32], NiwlError> {
+        let skipped = self.skipped.iter().position(|skipped| {
+            skipped.ratchet_key == header.ratchet_key
+                && skipped.message_number == header.message_number
+        });
+        if let Some(index) = skipped {
+            return Ok(self.skipped.remove(index).key);
+        }
+
+        if self.receiving_key != Some(header.ratchet_key) {
+            self.skip(header.previous_chain_length)?;
+            self.ratchet(&header.ratchet_key);
+        }
+        self.skip(header.message_number)?;
+        let chain_key = self
+            .receiving_chain
+            .ok_or(session_error("session has no receiving chain"))?;
+        let (chain_key, message_key) = kdf_chain(&chain_key);
+        self.receiving_chain = Some(chain_key);
+        self.received += 1;
+        Ok(message_key)
+    }
+}
+
+impl Sessions {
+    /// Encrypt a message to a contact within our current session, starting a new session if
+    /// we don't have one.
+    pub fn encrypt(
+        &mut self,
+        identity: &PrivateKey,
+        their_public_key: &PublicKey,
+        plaintext: &[u8],
+    ) -> Result<RatchetMessage, NiwlError> {
+        if self.sessions.is_empty() {
+            self.sessions
+                .push(Session::initiate(identity, their_public_key));
+        }
+        self.sessions[0].encrypt(plaintext)
+    }
+
+    /// Decrypt a message from a contact. The session state is only updated if the message
+    /// decrypts successfully.
+    pub fn decrypt(
+        &mut self,
+        private_key: &PrivateKey,
+        their_identity: &PublicKey,
+        message: &RatchetMessage,
+    ) -> Result<Vec<u8>, NiwlError> {
+        let header = &message.header;
+        let existing = self
+            .sessions
+            .iter()
+            .position(|session| session.id == header.session);
+        let mut session = match existing {
+            Some(index) => self.sessions[index].clone(),
+            None if header.initiate => Session::respond(private_key, their_identity, header),
+            None => return Err(session_error("unknown session")),
+        };
+
+        let mut message_key = session.message_key(header)?;
+        let (cipher, nonce) = cipher(&message_key);
+        message_key.zeroize();
+        let aad = bincode::serialize(header)?;
+        let plaintext = cipher
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &message.ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| session_error("message failed to decrypt"))?;
+
+        // The other party has set up this session, so it no longer needs to be flagged. Make it
+        // the session we use to send.
+        session.initiating = false;
+        if let Some(index) = existing {
+            self.sessions.remove(index);
+        }
+        self.sessions.insert(0, session);
+        self.sessions.truncate(MAX_SESSIONS);
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encrypt::PrivateKey;
+    use crate::ratchet::Sessions;
+
+    struct Party {
+        private_key: PrivateKey,
+        identity: PrivateKey,
+        sessions: Sessions,
+    }
+
+    fn party() -> Party {
+        Party {
+            private_key: PrivateKey::generate(),
+            identity: PrivateKey::generate(),
+            sessions: Sessions::default(),
+        }
+    }
+
+    fn send(from: &mut Party, to: &Party, message: &[u8]) -> crate::ratchet::RatchetMessage {
+        from.sessions
+            .encrypt(&from.identity, &to.private_key.public_key(), message)
+            .unwrap()
+    }
+
+    fn receive(to: &mut Party, from: &Party, message: &crate::ratchet::RatchetMessage) -> Vec<u8> {
+        to.sessions
+            .decrypt(&to.private_key, &from.identity.public_key(), message)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_ratchet_conversation() {
+        let mut alice = party();
+        let mut bob = party();
+
+        let a1 = send(&mut alice, &bob, b"a1");
+        let a2 = send(&mut alice, &bob, b"a2");
+        let a3 = send(&mut alice, &bob, b"a3");
+        // Messages can arrive out of order
+        assert_eq!(receive(&mut bob, &alice, &a2), b"a2".to_vec());
+        assert_eq!(receive(&mut bob, &alice, &a1), b"a1".to_vec());
+        // ...but can only be decrypted once, the key is deleted after use
+        assert!(bob
+            .sessions
+            .decrypt(&bob.private_key, &alice.identity.public_key(), &a1)
+            .is_err());
+
+        let b1 = send(&mut bob, &alice, b"b1");
+        // The responder replies with a fresh ratchet key, never its static key
+        assert!(b1.header.ratchet_key != bob.private_key.public_key().0);
+        assert_eq!(receive(&mut alice, &bob, &b1), b"b1".to_vec());
+        let a4 = send(&mut alice, &bob, b"a4");
+        assert!(a4.header.initiate == false);
+        assert_eq!(receive(&mut bob, &alice, &a4), b"a4".to_vec());
+        // A message from a previous chain can still be decrypted
+        assert_eq!(receive(&mut bob, &alice, &a3), b"a3".to_vec());
+
+        // A tampered message is rejected without affecting the session
+        let mut b2 = send(&mut bob, &alice, b"b2");
+        b2.ciphertext[0] ^= 1;
+        assert!(alice
+            .sessions
+            .decrypt(&alice.private_key, &bob.identity.public_key(), &b2)
+            .is_err());
+        b2.ciphertext[0] ^= 1;
+        assert_eq!(receive(&mut alice, &bob, &b2), b"b2".to_vec());
+    }
+
+    #[test]
+    fn test_ratchet_simultaneous_initiation() {
+        let mut alice = party();
+        let mut bob = party();
+
+        let a1 = send(&mut alice, &bob, b"a1");
+        let b1 = send(&mut bob, &alice, b"b1");
+        assert_eq!(receive(&mut bob, &alice, &a1), b"a1".to_vec());
+        assert_eq!(receive(&mut alice, &bob, &b1), b"b1".to_vec());
+
+        // Each now sends in the session the other started
+        let a2 = send(&mut alice, &bob, b"a2");
+        let b2 = send(&mut bob, &alice, b"b2");
+        assert_eq!(receive(&mut bob, &alice, &a2), b"a2".to_vec());
+        assert_eq!(receive(&mut alice, &bob, &b2), b"b2".to_vec());
+    }
+}
@@ -0,0 +1,713 @@
+//! A Sphinx-style packet format for routing messages through a sequence of mixes.
+//!
+//! Nested `TaggedCiphertext`s (see `Profile::wrap_route`) shrink the available payload at every
+//! hop and, because each layer is a complete ciphertext, a mix can infer something about its
+//! position from the size of the payload it unwraps. A Sphinx packet instead has a fixed size
+//! header containing per-hop routing information, a group element that is re-blinded at every
+//! hop, and a payload that is encrypted with a wide-block cipher (LIONESS) so that every hop sees
+//! a packet of the same shape.
+//!
+//! Sphinx packets are carried as the ciphertext of a `TaggedCiphertext` with version
+//! `SPHINX_VERSION`: the nonce holds the blinded group element, and the ciphertext holds the
+//! routing information, its MAC and the payload. The routing information for each mix contains
+//! the fuzzytag for the next hop, so the server sees ordinary fixed-size tagged packets.
+//!
+//! A sender can also embed a single-use reply block (`Surb`) in a message: a header that routes
+//! a reply back through mixes of the sender's choosing, and is posted by the last mix under a
+//! tag for the sender.
+//!
+//! Every packet is created for an epoch (see `EPOCH_SECONDS`), which is mixed into the keys each
+//! hop derives. A mix only processes packets created for the previous, current or next epoch,
+//! so it only needs to remember the replay tags of recent epochs to refuse every replay.
+use crate::encrypt::{
+    PrivateKey, PublicKey, TaggedCiphertext, CIPHERTEXT_SIZE, CURRENT_VERSION, SPHINX_VERSION,
+    TAG_SIZE,
+};
+use crate::NiwlError;
+use chrono::Local;
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::ristretto::RistrettoPoint;
+use curve25519_dalek::scalar::Scalar;
+use fuzzytags::Tag;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha3::digest::{ExtendableOutput, Update, XofReader};
+use sha3::{Sha3_256, Shake256};
+use std::convert::TryInto;
+use std::fmt;
+use std::ops::Mul;
+use zeroize::Zeroize;
+
+/// The maximum number of mixes a Sphinx packet can be routed through
+pub const MAX_HOPS: usize = 4;
+
+/// Size of the MAC over the routing information
+const GAMMA_SIZE: usize = 16;
+
+/// Size of the routing instructions for a single hop: a flag and (for relays) the next tag
+const ROUTING_SIZE: usize = 1 + TAG_SIZE;
+
+/// Size of the routing information for a single hop, including the MAC for the next hop
+const HOP_SIZE: usize = ROUTING_SIZE + GAMMA_SIZE;
+
+/// Size of the routing information in the header
+const BETA_SIZE: usize = MAX_HOPS * HOP_SIZE;
+
+/// Size of the payload, the rest of a fixed size niwl ciphertext
+const DELTA_SIZE: usize = CIPHERTEXT_SIZE - BETA_SIZE - GAMMA_SIZE;
+
+/// The final mix checks for these zero bytes to detect a tampered payload
+const PAYLOAD_CHECK_SIZE: usize = 16;
+
+/// Size of the length prefix in front of the packet carried in the payload
+const PAYLOAD_LENGTH_SIZE: usize = 2;
+
+/// The largest (compact) TaggedCiphertext that can be delivered via a Sphinx packet
+pub const MAX_PAYLOAD_LENGTH: usize = DELTA_SIZE - PAYLOAD_CHECK_SIZE - PAYLOAD_LENGTH_SIZE;
+
+/// Routing flag: forward the processed packet to the next hop
+const RELAY: u8 = 1;
+
+/// Routing flag: this is the last mix, post the packet carried in the payload
+const DELIVER: u8 = 2;
+
+/// Routing flag: this is the last mix of a reply block, post the payload under the given tag
+const REPLY: u8 = 3;
+
+/// How long an epoch lasts. Packets (including those sent with a SURB) are only processed until
+/// the end of the epoch after the one they were created for.
+pub const EPOCH_SECONDS: i64 = 7 * 24 * 60 * 60;
+
+/// The epoch packets created now are created for
+pub fn current_epoch() -> u64 {
+    (Local::now().timestamp() / EPOCH_SECONDS) as u64
+}
+
+/// Identifies a packet a mix has processed. Mixes must refuse to process a packet with a replay
+/// tag they have seen before, and can forget the tags of epochs they no longer accept packets
+/// for.
+pub struct ReplayTag {
+    pub epoch: u64,
+    pub tag: [u8; 32],
+}
+
+/// The result of a mix processing a Sphinx packet. In both cases the mix should add the
+/// resulting (fixed size) packet to its store rather than post it immediately.
+pub enum ProcessedPacket {
+    /// A Sphinx packet for the next mix on the route
+    Relay(TaggedCiphertext),
+    /// The packet carried in the payload (or a reply), for the final recipient
+    Deliver(TaggedCiphertext),
+}
+
+// The per-hop keys derived from the diffie-hellman shared secret of a hop and the epoch
+struct HopKeys {
+    rho: [u8; 32],
+    mac: [u8; 32],
+    lioness: [u8; 32],
+    blinding: Scalar,
+    replay: [u8; 32],
+}
+
+impl HopKeys {
+    fn derive(alpha: &RistrettoPoint, shared: &RistrettoPoint, epoch: u64) -> HopKeys {
+        let shared = shared.compress();
+        let epoch = epoch.to_le_bytes();
+        let mut blinding = [0u8; 64];
+        xof(
+            &[
+                b"niwl-sphinx-blind",
+                &epoch,
+                alpha.compress().as_bytes(),
+                shared.as_bytes(),
+            ],
+            &mut blinding,
+        );
+        let keys = HopKeys {
+            rho: hash(&[b"niwl-sphinx-rho", &epoch, shared.as_bytes()]),
+            mac: hash(&[b"niwl-sphinx-mac", &epoch, shared.as_bytes()]),
+            lioness: hash(&[b"niwl-sphinx-lioness", &epoch, shared.as_bytes()]),
+            blinding: Scalar::from_bytes_mod_order_wide(&blinding),
+            replay: hash(&[b"niwl-sphinx-replay", &epoch, shared.as_bytes()]),
+        };
+        blinding.zeroize();
+        keys
+    }
+}
+
+impl Drop for HopKeys {
+    fn drop(&mut self) {
+        self.rho.zeroize();
+        self.mac.zeroize();
+        self.lioness.zeroize();
+        self.blinding.zeroize();
+        self.replay.zeroize();
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hash = Sha3_256::default();
+    for part in parts {
+        Update::update(&mut hash, part);
+    }
+    sha3::Digest::finalize(hash).into()
+}
+
+fn xof(parts: &[&[u8]], output: &mut [u8]) {
+    let mut shake = Shake256::default();
+    for part in parts {
+        shake.update(part);
+    }
+    shake.finalize_xof().read(output);
+}
+
+fn xor(data: &mut [u8], stream: &[u8]) {
+    for (byte, key) in data.iter_mut().zip(stream.iter()) {
+        *byte ^= key;
+    }
+}
+
+fn stream(key: &[u8], length: usize) -> Vec<u8> {
+    let mut output = vec![0u8; length];
+    xof(&[b"niwl-sphinx-stream", key], &mut output);
+    output
+}
+
+fn mac(key: &[u8; 32], data: &[u8]) -> [u8; GAMMA_SIZE] {
+    hash(&[key, data])[0..GAMMA_SIZE].try_into().unwrap()
+}
+
+// Compare two MACs without short circuiting
+fn verify_mac(expected: &[u8], actual: &[u8]) -> bool {
+    expected.len() == actual.len()
+        && expected
+            .iter()
+            .zip(actual.iter())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+// LIONESS wide-block cipher built from the shake stream and sha3: every bit of the output
+// depends on every bit of the input, so a tampered payload is garbled entirely.
+fn lioness_subkeys(key: &[u8; 32]) -> [[u8; 32]; 4] {
+    [
+        hash(&[b"niwl-lioness-1", key]),
+        hash(&[b"niwl-lioness-2", key]),
+        hash(&[b"niwl-lioness-3", key]),
+        hash(&[b"niwl-lioness-4", key]),
+    ]
+}
+
+fn lioness_stream_round(key: &[u8; 32], left: &[u8], right: &mut [u8]) {
+    let mut round_key = *key;
+    xor(&mut round_key, left);
+    let keystream = stream(&round_key, right.len());
+    xor(right, &keystream);
+}
+
+fn lioness_hash_round(key: &[u8; 32], left: &mut [u8], right: &[u8]) {
+    xor(left, &hash(&[key, right]));
+}
+
+fn lioness_encrypt(key: &[u8; 32], block: &mut [u8]) {
+    let keys = lioness_subkeys(key);
+    let (left, right) = block.split_at_mut(32);
+    lioness_stream_round(&keys[0], left, right);
+    lioness_hash_round(&keys[1], left, right);
+    lioness_stream_round(&keys[2], left, right);
+    lioness_hash_round(&keys[3], left, right);
+}
+
+fn lioness_decrypt(key: &[u8; 32], block: &mut [u8]) {
+    let keys = lioness_subkeys(key);
+    let (left, right) = block.split_at_mut(32);
+    lioness_hash_round(&keys[3], left, right);
+    lioness_stream_round(&keys[2], left, right);
+    lioness_hash_round(&keys[1], left, right);
+    lioness_stream_round(&keys[0], left, right);
+}
+
+// The header of a Sphinx packet, along with the keys the sender shares with each hop
+struct Header {
+    alpha: RistrettoPoint,
+    beta: Vec<u8>,
+    gamma: [u8; GAMMA_SIZE],
+    hop_keys: Vec<HopKeys>,
+    // The group element the final hop will see after blinding, used to identify replies
+    final_alpha: RistrettoPoint,
+}
+
+fn check_route(route: &[(&PublicKey, Tag<24>)]) -> Result<(), NiwlError> {
+    if route.is_empty() || route.len() > MAX_HOPS {
+        return Err(NiwlError::InvalidPacketError(format!(
+            "a sphinx route must contain between 1 and {} mixes",
+            MAX_HOPS
+        )));
+    }
+    Ok(())
+}
+
+// Build the header for a route in `epoch`, with `instructions` as the routing instructions for
+// the final hop
+fn create_header(
+    route: &[(&PublicKey, Tag<24>)],
+    instructions: &[u8; ROUTING_SIZE],
+    epoch: u64,
+) -> Header {
+    // Derive the shared secrets for every hop, blinding our secret as each mix will blind alpha
+    let mut rng = OsRng::default();
+    let mut x = Scalar::random(&mut rng);
+    let alpha = RISTRETTO_BASEPOINT_POINT.mul(x);
+    let mut hop_keys = vec![];
+    for (public_key, _) in route.iter() {
+        let keys = HopKeys::derive(
+            &RISTRETTO_BASEPOINT_POINT.mul(x),
+            &public_key.0.mul(x),
+            epoch,
+        );
+        x *= keys.blinding;
+        hop_keys.push(keys);
+    }
+
+    // The filler is the tail of the routing information that each hop appends when it shifts
+    // the header, computed in advance so that the MAC of every hop can be calculated.
+    let mut filler: Vec<u8> = vec![];
+    for keys in hop_keys.iter().take(route.len() - 1) {
+        filler.extend_from_slice(&[0u8; HOP_SIZE]);
+        let keystream = stream(&keys.rho, BETA_SIZE + HOP_SIZE);
+        let offset = BETA_SIZE + HOP_SIZE - filler.len();
+        xor(&mut filler, &keystream[offset..]);
+    }
+
+    // Build the routing information from the last hop backwards
+    let last = route.len() - 1;
+    let mut beta = vec![0u8; BETA_SIZE - filler.len()];
+    rng.fill_bytes(&mut beta);
+    beta[0..ROUTING_SIZE].copy_from_slice(instructions);
+    beta[ROUTING_SIZE..HOP_SIZE].copy_from_slice(&[0u8; GAMMA_SIZE]);
+    xor(
+        &mut beta,
+        &stream(&hop_keys[last].rho, BETA_SIZE + HOP_SIZE),
+    );
+    beta.extend_from_slice(&filler);
+    let mut gamma = mac(&hop_keys[last].mac, &beta);
+
+    for hop in (0..last).rev() {
+        let mut next_beta = Vec::with_capacity(BETA_SIZE);
+        next_beta.push(RELAY);
+        next_beta.extend_from_slice(&route[hop + 1].1.compress());
+        next_beta.extend_from_slice(&gamma);
+        next_beta.extend_from_slice(&beta[0..BETA_SIZE - HOP_SIZE]);
+        xor(&mut next_beta, &stream(&hop_keys[hop].rho, BETA_SIZE));
+        beta = next_beta;
+        gamma = mac(&hop_keys[hop].mac, &beta);
+    }
+
+    Header {
+        alpha,
+        beta,
+        gamma,
+        hop_keys,
+        final_alpha: RISTRETTO_BASEPOINT_POINT.mul(x),
+    }
+}
+
+// Lay out a message in a payload block: zero bytes to detect tampering, the length of the
+// message, the message and random filler.
+fn encode_payload(message: &[u8]) -> Vec<u8> {
+    let mut delta = vec![0u8; DELTA_SIZE];
+    let length = (message.len() as u16).to_le_bytes();
+    delta[PAYLOAD_CHECK_SIZE..PAYLOAD_CHECK_SIZE + PAYLOAD_LENGTH_SIZE].copy_from_slice(&length);
+    let start = PAYLOAD_CHECK_SIZE + PAYLOAD_LENGTH_SIZE;
+    delta[start..start + message.len()].copy_from_slice(message);
+    OsRng.fill_bytes(&mut delta[start + message.len()..]);
+    delta
+}
+
+fn decode_payload(delta: &[u8]) -> Result<&[u8], NiwlError> {
+    if delta[0..PAYLOAD_CHECK_SIZE].iter().any(|byte| *byte != 0) {
+        return Err(NiwlError::CryptoError(String::from(
+            "sphinx payload has been tampered with",
+        )));
+    }
+    let start = PAYLOAD_CHECK_SIZE + PAYLOAD_LENGTH_SIZE;
+    let length = u16::from_le_bytes(delta[PAYLOAD_CHECK_SIZE..start].try_into().unwrap()) as usize;
+    if length > MAX_PAYLOAD_LENGTH {
+        return Err(NiwlError::InvalidPacketError(String::from(
+            "invalid sphinx payload length",
+        )));
+    }
+    Ok(&delta[start..start + length])
+}
+
+fn check_payload_length(length: usize) -> Result<(), NiwlError> {
+    if length > MAX_PAYLOAD_LENGTH {
+        return Err(NiwlError::MessageTooLongError(format!(
+            "sphinx payload is {} bytes, the maximum is {} bytes",
+            length, MAX_PAYLOAD_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+/// Create a Sphinx packet that will be processed by each of the mixes in `route` (in order),
+/// with the last mix posting `payload` to the server. Each hop is given as the public key of
+/// the mix and a tag generated from the tagging key of the mix. `payload` should be a compact
+/// TaggedCiphertext for the final recipient.
+pub fn create_packet(
+    route: &[(&PublicKey, Tag<24>)],
+    payload: &TaggedCiphertext,
+) -> Result<TaggedCiphertext, NiwlError> {
+    create_packet_in(route, payload, current_epoch())
+}
+
+fn create_packet_in(
+    route: &[(&PublicKey, Tag<24>)],
+    payload: &TaggedCiphertext,
+    epoch: u64,
+) -> Result<TaggedCiphertext, NiwlError> {
+    check_route(route)?;
+    let payload = payload.to_bytes();
+    check_payload_length(payload.len())?;
+
+    let mut instructions = [0u8; ROUTING_SIZE];
+    instructions[0] = DELIVER;
+    let header = create_header(route, &instructions, epoch);
+
+    // The payload is layered with LIONESS, each mix removes one layer
+    let mut delta = encode_payload(&payload);
+    for keys in header.hop_keys.iter().rev() {
+        lioness_encrypt(&keys.lioness, &mut delta);
+    }
+
+    let mut ciphertext = header.beta;
+    ciphertext.extend_from_slice(&header.gamma);
+    ciphertext.extend_from_slice(&delta);
+    Ok(TaggedCiphertext {
+        version: SPHINX_VERSION,
+        tag: route[0].1.clone(),
+        nonce: header.alpha,
+        ciphertext,
+    })
+}
+
+/// A single-use reply block. A SURB contains the header of a Sphinx packet routed through a
+/// series of mixes chosen by its creator, with the last mix posting the reply under a tag for
+/// the creator. Anyone holding the SURB can use it to send one reply, without learning
+/// anything about who created it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Surb {
+    tag: Tag<24>,
+    alpha: RistrettoPoint,
+    header: Vec<u8>,
+    key: [u8; 32],
+}
+
+/// The secrets the creator of a SURB keeps in order to read the reply.
+#[derive(Serialize, Deserialize)]
+pub struct SurbSecrets {
+    hop_keys: Vec<[u8; 32]>,
+    key: [u8; 32],
+}
+
+impl Drop for SurbSecrets {
+    fn drop(&mut self) {
+        for key in self.hop_keys.iter_mut() {
+            key.zeroize();
+        }
+        self.key.zeroize();
+    }
+}
+
+impl fmt::Debug for SurbSecrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SurbSecrets([REDACTED])")
+    }
+}
+
+impl fmt::Debug for Surb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Surb")
+            .field("id", &self.id())
+            .field("key", &format_args!("[REDACTED]"))
+            .finish()
+    }
+}
+
+/// Create a single-use reply block that will be routed through the mixes in `route` (in order),
+/// with the last mix posting the reply under `reply_tag`. Returns the SURB, an identifier for
+/// replies made with it (the nonce of the packet posted by the last mix) and the secrets needed
+/// to read the reply.
+pub fn create_surb(
+    route: &[(&PublicKey, Tag<24>)],
+    reply_tag: &Tag<24>,
+) -> Result<(Surb, [u8; 32], SurbSecrets), NiwlError> {
+    check_route(route)?;
+    let mut instructions = [0u8; ROUTING_SIZE];
+    instructions[0] = REPLY;
+    instructions[1..].copy_from_slice(&reply_tag.compress());
+    let header = create_header(route, &instructions, current_epoch());
+
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    let mut ciphertext = header.beta;
+    ciphertext.extend_from_slice(&header.gamma);
+    let surb = Surb {
+        tag: route[0].1.clone(),
+        alpha: header.alpha,
+        header: ciphertext,
+        key,
+    };
+    let secrets = SurbSecrets {
+        hop_keys: header.hop_keys.iter().map(|keys| keys.lioness).collect(),
+        key,
+    };
+    Ok((surb, header.final_alpha.compress().to_bytes(), secrets))
+}
+
+impl Surb {
+    /// An identifier for this SURB that can be shown to a user
+    pub fn id(&self) -> String {
+        let digest = hash(&[b"niwl-surb-id", &self.header]);
+        base32::encode(base32::Alphabet::RFC4648 { padding: false }, &digest[0..10])
+            .to_ascii_lowercase()
+    }
+
+    /// Create a Sphinx packet carrying `message` along the route of this SURB. The packet must
+    /// be posted under the tag of the first hop.
+    pub fn reply(&self, message: &[u8]) -> Result<TaggedCiphertext, NiwlError> {
+        check_payload_length(message.len())?;
+        let mut delta = encode_payload(message);
+        lioness_encrypt(&self.key, &mut delta);
+        let mut ciphertext = self.header.clone();
+        ciphertext.extend_from_slice(&delta);
+        Ok(TaggedCiphertext {
+            version: SPHINX_VERSION,
+            tag: self.tag.clone(),
+            nonce: self.alpha,
+            ciphertext,
+        })
+    }
+}
+
+impl SurbSecrets {
+    /// Read a reply made with the SURB these secrets belong to.
+    pub fn open(&self, packet: &TaggedCiphertext) -> Result<Vec<u8>, NiwlError> {
+        if packet.ciphertext.len() < DELTA_SIZE {
+            return Err(NiwlError::InvalidPacketError(String::from(
+                "reply is too short",
+            )));
+        }
+        // Every mix decrypted the payload with its key, so undo each of those in turn
+        let mut delta = packet.ciphertext[0..DELTA_SIZE].to_vec();
+        for key in self.hop_keys.iter().rev() {
+            lioness_encrypt(key, &mut delta);
+        }
+        lioness_decrypt(&self.key, &mut delta);
+        Ok(decode_payload(&delta)?.to_vec())
+    }
+}
+
+/// Process a Sphinx packet addressed to this mix. Returns a replay tag along with the processed
+/// packet - mixes must refuse to process a packet with a replay tag they have seen before.
+/// Packets created for an epoch other than the previous, current or next one are refused.
+pub fn process_packet(
+    private_key: &PrivateKey,
+    packet: &TaggedCiphertext,
+) -> Result<(ReplayTag, ProcessedPacket), NiwlError> {
+    process_packet_in(private_key, packet, current_epoch())
+}
+
+fn process_packet_in(
+    private_key: &PrivateKey,
+    packet: &TaggedCiphertext,
+    current_epoch: u64,
+) -> Result<(ReplayTag, ProcessedPacket), NiwlError> {
+    if packet.version != SPHINX_VERSION || packet.ciphertext.len() != CIPHERTEXT_SIZE {
+        return Err(NiwlError::InvalidPacketError(String::from(
+            "not a sphinx packet",
+        )));
+    }
+    let alpha = packet.nonce;
+    let shared = alpha.mul(private_key.0);
+
+    // The epoch is not sent, so find the one the header authenticates under. Clocks are allowed
+    // to differ by up to an epoch.
+    let beta = &packet.ciphertext[0..BETA_SIZE];
+    let gamma = &packet.ciphertext[BETA_SIZE..BETA_SIZE + GAMMA_SIZE];
+    let (epoch, keys) = (current_epoch.saturating_sub(1)..=current_epoch + 1)
+        .map(|epoch| (epoch, HopKeys::derive(&alpha, &shared, epoch)))
+        .find(|(_, keys)| verify_mac(&mac(&keys.mac, beta), gamma))
+        .ok_or(NiwlError::CryptoError(String::from(
+            "sphinx header failed to authenticate, or the packet has expired",
+        )))?;
+    let replay_tag = ReplayTag {
+        epoch,
+        tag: keys.replay,
+    };
+
+    let mut routing = beta.to_vec();
+    routing.extend_from_slice(&[0u8; HOP_SIZE]);
+    xor(&mut routing, &stream(&keys.rho, BETA_SIZE + HOP_SIZE));
+
+    let mut delta = packet.ciphertext[BETA_SIZE + GAMMA_SIZE..].to_vec();
+    lioness_decrypt(&keys.lioness, &mut delta);
+
+    let tag = || {
+        Tag::<24>::decompress(&routing[1..ROUTING_SIZE]).ok_or(NiwlError::InvalidPacketError(
+            String::from("invalid tag in routing information"),
+        ))
+    };
+    match routing[0] {
+        RELAY => {
+            let mut ciphertext = routing[HOP_SIZE..].to_vec();
+            ciphertext.extend_from_slice(&routing[ROUTING_SIZE..HOP_SIZE]);
+            ciphertext.extend_from_slice(&delta);
+            let next = TaggedCiphertext {
+                version: SPHINX_VERSION,
+                tag: tag()?,
+                nonce: alpha.mul(keys.blinding),
+                ciphertext,
+            };
+            Ok((replay_tag, ProcessedPacket::Relay(next)))
+        }
+        DELIVER => {
+            let mut payload = TaggedCiphertext::from_bytes(decode_payload(&delta)?).ok_or(
+                NiwlError::InvalidPacketError(String::from("invalid sphinx payload")),
+            )?;
+            payload.pad();
+            Ok((replay_tag, ProcessedPacket::Deliver(payload)))
+        }
+        REPLY => {
+            // The payload of a reply is still encrypted, only the creator of the SURB can check
+            // it. It is posted as an ordinary packet, identified by the blinded group element.
+            let mut ciphertext = delta;
+            let mut filler = vec![0u8; CIPHERTEXT_SIZE - DELTA_SIZE];
+            OsRng.fill_bytes(&mut filler);
+            ciphertext.extend_from_slice(&filler);
+            let reply = TaggedCiphertext {
+                version: CURRENT_VERSION,
+                tag: tag()?,
+                nonce: alpha.mul(keys.blinding),
+                ciphertext,
+            };
+            Ok((replay_tag, ProcessedPacket::Deliver(reply)))
+        }
+        _ => Err(NiwlError::InvalidPacketError(String::from(
+            "unknown sphinx routing flag",
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encrypt::PrivateKey;
+    use crate::sphinx::{
+        create_packet, create_packet_in, current_epoch, process_packet, process_packet_in,
+        ProcessedPacket, MAX_HOPS,
+    };
+    use fuzzytags::RootSecret;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_sphinx_route() {
+        let recipient = PrivateKey::generate();
+        let recipient_tag = RootSecret::<24>::generate(&mut OsRng)
+            .tagging_key()
+            .generate_tag(&mut OsRng);
+        let payload = recipient
+            .public_key()
+            .encrypt_bytes_compact(&recipient_tag, b"hello via sphinx", None)
+            .unwrap();
+
+        for hops in 1..=MAX_HOPS {
+            let mixes: Vec<PrivateKey> = (0..hops).map(|_| PrivateKey::generate()).collect();
+            let public_keys: Vec<_> = mixes.iter().map(|mix| mix.public_key()).collect();
+            let route: Vec<_> = public_keys
+                .iter()
+                .map(|public_key| {
+                    let tag = RootSecret::<24>::generate(&mut OsRng)
+                        .tagging_key()
+                        .generate_tag(&mut OsRng);
+                    (public_key, tag)
+                })
+                .collect();
+
+            let mut packet = create_packet(&route, &payload).unwrap();
+            for (hop, mix) in mixes.iter().enumerate() {
+                assert!(packet.is_fixed_size());
+                assert!(packet.tag == route[hop].1);
+                packet = match process_packet(mix, &packet).unwrap().1 {
+                    ProcessedPacket::Relay(next) => next,
+                    ProcessedPacket::Deliver(delivered) => {
+                        assert_eq!(hop, hops - 1);
+                        delivered
+                    }
+                };
+            }
+            assert!(packet.is_fixed_size());
+            assert_eq!(
+                recipient.decrypt_bytes(&packet).unwrap(),
+                b"hello via sphinx".to_vec()
+            );
+        }
+    }
+
+    #[test]
+    fn test_sphinx_rejects_tampering() {
+        let mix = PrivateKey::generate();
+        let public_key = mix.public_key();
+        let tag = RootSecret::<24>::generate(&mut OsRng)
+            .tagging_key()
+            .generate_tag(&mut OsRng);
+        let payload = PrivateKey::generate()
+            .public_key()
+            .encrypt_bytes_compact(&tag, b"payload", None)
+            .unwrap();
+
+        let packet = create_packet(&[(&public_key, tag.clone())], &payload).unwrap();
+        let mut tampered_header = packet.clone();
+        tampered_header.ciphertext[0] ^= 1;
+        assert!(process_packet(&mix, &tampered_header).is_err());
+
+        let mut tampered_payload = packet.clone();
+        tampered_payload.ciphertext[super::BETA_SIZE + super::GAMMA_SIZE + 40] ^= 1;
+        assert!(process_packet(&mix, &tampered_payload).is_err());
+
+        assert!(process_packet(&PrivateKey::generate(), &packet).is_err());
+    }
+
+    #[test]
+    fn test_sphinx_packets_expire() {
+        let mix = PrivateKey::generate();
+        let public_key = mix.public_key();
+        let tag = RootSecret::<24>::generate(&mut OsRng)
+            .tagging_key()
+            .generate_tag(&mut OsRng);
+        let payload = PrivateKey::generate()
+            .public_key()
+            .encrypt_bytes_compact(&tag, b"payload", None)
+            .unwrap();
+        let route = [(&public_key, tag.clone())];
+
+        // Packets are accepted within an epoch either side of the one they were created for,
+        // with a replay tag that depends on the epoch
+        let epoch = current_epoch();
+        let packet = create_packet_in(&route, &payload, epoch).unwrap();
+        let mut replay_tags = vec![];
+        for current in epoch - 1..=epoch + 1 {
+            let (replay_tag, _) = process_packet_in(&mix, &packet, current).unwrap();
+            assert_eq!(replay_tag.epoch, epoch);
+            replay_tags.push(replay_tag.tag);
+        }
+        assert!(replay_tags.iter().all(|tag| tag == &replay_tags[0]));
+        assert!(process_packet_in(&mix, &packet, epoch + 2).is_err());
+        assert!(process_packet_in(&mix, &packet, epoch - 2).is_err());
+
+        let next = create_packet_in(&route, &payload, epoch + 1).unwrap();
+        let (replay_tag, _) = process_packet(&mix, &next).unwrap();
+        assert_eq!(replay_tag.epoch, epoch + 1);
+        assert!(process_packet_in(&mix, &next, epoch + 3).is_err());
+    }
+}
@@ -0,0 +1,318 @@
+//! Passphrase-encrypted containers for profiles at rest.
+//!
+//! A profile holds every secret we have, so it may be stored encrypted under a key derived from
+//! a passphrase with Argon2id. An encrypted profile starts with a header recording the
+//! parameters needed to derive the key again (the KDF, its costs and the salt) and the nonce,
+//! followed by the profile encrypted with XChaCha20-Poly1305. The header is authenticated as
+//! associated data, so tampering with the KDF parameters is detected.
+//!
+//! Profiles are saved by writing a temporary file and renaming it over the old profile, so a
+//! crash never leaves a truncated profile behind, and the previous profile is kept as a backup.
+//! Saves take an advisory lock, and are refused if another process has changed the profile since
+//! it was loaded, rather than silently discarding the other process's changes.
+use crate::NiwlError;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use fs2::FileExt;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::Serialize;
+use sha3::{Digest, Sha3_256};
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use zeroize::{Zeroize, Zeroizing};
+
+/// The environment variable read for the passphrase of a profile
+pub const PASSPHRASE_ENV_VAR: &str = "NIWL_PASSPHRASE";
+
+const MAGIC: &[u8; 4] = b"NIWL";
+const CONTAINER_VERSION: u8 = 1;
+const KDF_ARGON2ID: u8 = 1;
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
+const HEADER_SIZE: usize = MAGIC.len() + 2 + 12 + SALT_SIZE + NONCE_SIZE;
+
+// Refuse to derive keys with parameters that would exhaust memory (memory cost is in KiB)
+const MAX_MEMORY_COST: u32 = 4 * 1024 * 1024;
+const MAX_TIME_COST: u32 = 64;
+const MAX_PARALLELISM: u32 = 16;
+
+/// The cost parameters of Argon2id
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KdfParams {
+    /// Memory in KiB
+    pub memory_cost: u32,
+    pub time_cost: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> KdfParams {
+        KdfParams {
+            memory_cost: 64 * 1024,
+            time_cost: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+/// A key derived from a passphrase, along with the parameters used to derive it
+#[derive(Clone)]
+pub struct StorageKey {
+    params: KdfParams,
+    salt: [u8; SALT_SIZE],
+    key: [u8; 32],
+}
+
+impl Drop for StorageKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+impl fmt::Debug for StorageKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StorageKey")
+            .field("params", &self.params)
+            .field("key", &format_args!("[REDACTED]"))
+            .finish()
+    }
+}
+
+fn storage_error(reason: &str) -> NiwlError {
+    NiwlError::StorageError(String::from(reason))
+}
+
+impl StorageKey {
+    /// Derive a new key from a passphrase, with a fresh salt
+    pub fn derive(passphrase: &str, params: KdfParams) -> Result<StorageKey, NiwlError> {
+        let mut salt = [0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        StorageKey::derive_with_salt(passphrase, params, salt)
+    }
+
+    fn derive_with_salt(
+        passphrase: &str,
+        params: KdfParams,
+        salt: [u8; SALT_SIZE],
+    ) -> Result<StorageKey, NiwlError> {
+        if params.memory_cost > MAX_MEMORY_COST
+            || params.time_cost > MAX_TIME_COST
+            || params.parallelism > MAX_PARALLELISM
+        {
+            return Err(storage_error("key derivation parameters are too large"));
+        }
+        let argon2 = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(
+                params.memory_cost,
+                params.time_cost,
+                params.parallelism,
+                Some(32),
+            )
+            .map_err(|_| storage_error("invalid key derivation parameters"))?,
+        );
+        let mut key = [0u8; 32];
+        argon2
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|_| storage_error("key derivation failed"))?;
+        Ok(StorageKey { params, salt, key })
+    }
+
+    fn header(&self, nonce: &[u8; NONCE_SIZE]) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.push(CONTAINER_VERSION);
+        header.push(KDF_ARGON2ID);
+        header.extend_from_slice(&self.params.memory_cost.to_le_bytes());
+        header.extend_from_slice(&self.params.time_cost.to_le_bytes());
+        header.extend_from_slice(&self.params.parallelism.to_le_bytes());
+        header.extend_from_slice(&self.salt);
+        header.extend_from_slice(nonce);
+        header
+    }
+
+    /// Encrypt `plaintext` into a container, under a fresh nonce
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let mut container = self.header(&nonce);
+        let ciphertext = XChaCha20Poly1305::new(Key::from_slice(&self.key))
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &container,
+                },
+            )
+            .unwrap();
+        container.extend_from_slice(&ciphertext);
+        container
+    }
+}
+
+// Counts the bytes written to it, so a buffer of exactly the right size can be allocated
+struct Counter(usize);
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Serialize `value` to JSON that is wiped when dropped. The buffer is allocated at its final
+/// size up front, so that growing it does not leave partial copies behind in freed memory.
+pub(crate) fn to_json<T: Serialize>(value: &T) -> Zeroizing<Vec<u8>> {
+    let mut counter = Counter(0);
+    serde_json::to_writer(&mut counter, value).unwrap();
+    let mut json = Zeroizing::new(Vec::with_capacity(counter.0));
+    serde_json::to_writer(&mut *json, value).unwrap();
+    json
+}
+
+/// A hash of the contents of a profile file, used to tell whether it has changed
+pub(crate) fn fingerprint(data: &[u8]) -> [u8; 32] {
+    Sha3_256::digest(data).into()
+}
+
+// Profiles hold every secret we have, so only their owner may read them
+fn create_private(filename: &str) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(filename)
+}
+
+/// Replace the contents of `filename` with `data`, keeping the previous contents in
+/// `<filename>.bak`. `loaded` is the fingerprint of the contents we last read, if any - if the
+/// file no longer matches it then another process has saved it since, and the write is refused.
+/// Returns the fingerprint of the new contents.
+pub(crate) fn write_atomically(
+    filename: &str,
+    data: &[u8],
+    loaded: Option<&[u8; 32]>,
+) -> Result<[u8; 32], NiwlError> {
+    // The lock is released when the file is closed, even if we crash while holding it
+    let lock = create_private(&format!("{}.lock", filename))?;
+    lock.lock_exclusive()?;
+
+    let current = match fs::read(filename) {
+        Ok(current) => Some(Zeroizing::new(current)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+    if let (Some(current), Some(loaded)) = (&current, loaded) {
+        if &fingerprint(current) != loaded {
+            return Err(storage_error(
+                "the profile was changed by another process since it was loaded",
+            ));
+        }
+    }
+
+    let temporary = format!("{}.tmp", filename);
+    let mut file = create_private(&temporary)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    if current.is_some() {
+        fs::copy(filename, format!("{}.bak", filename))?;
+    }
+    fs::rename(&temporary, filename)?;
+    Ok(fingerprint(data))
+}
+
+/// Whether `data` is an encrypted container, rather than a plaintext profile
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Decrypt a container with a passphrase, returning the contents and the key, so that the
+/// contents can be sealed again without repeating the key derivation.
+pub fn open(
+    passphrase: &str,
+    container: &[u8],
+) -> Result<(StorageKey, Zeroizing<Vec<u8>>), NiwlError> {
+    if !is_encrypted(container) || container.len() < HEADER_SIZE {
+        return Err(storage_error("not an encrypted profile"));
+    }
+    let (header, ciphertext) = container.split_at(HEADER_SIZE);
+    if header[4] != CONTAINER_VERSION || header[5] != KDF_ARGON2ID {
+        return Err(storage_error("unsupported encrypted profile version"));
+    }
+    let u32_at = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+    let params = KdfParams {
+        memory_cost: u32_at(6),
+        time_cost: u32_at(10),
+        parallelism: u32_at(14),
+    };
+    let salt: [u8; SALT_SIZE] = header[18..18 + SALT_SIZE].try_into().unwrap();
+    let nonce = &header[18 + SALT_SIZE..];
+
+    let key = StorageKey::derive_with_salt(passphrase, params, salt)?;
+    let plaintext = XChaCha20Poly1305::new(Key::from_slice(&key.key))
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| storage_error("incorrect passphrase, or the profile is corrupted"))?;
+    Ok((key, Zeroizing::new(plaintext)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{is_encrypted, open, KdfParams, StorageKey};
+
+    // Cheap parameters so the tests run quickly
+    const TEST_PARAMS: KdfParams = KdfParams {
+        memory_cost: 8,
+        time_cost: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn test_storage_round_trip() {
+        let key = StorageKey::derive("correct horse", TEST_PARAMS).unwrap();
+        let container = key.seal(b"{\"profile_name\":\"alice\"}");
+        assert!(is_encrypted(&container));
+        assert!(!is_encrypted(b"{\"profile_name\":\"alice\"}"));
+
+        let (reopened, plaintext) = open("correct horse", &container).unwrap();
+        assert_eq!(*plaintext, b"{\"profile_name\":\"alice\"}".to_vec());
+        assert_eq!(reopened.params, TEST_PARAMS);
+        // Sealing again reuses the key and salt, but never the nonce
+        let resealed = reopened.seal(&plaintext);
+        assert_eq!(resealed[..34], container[..34]);
+        assert_ne!(resealed, container);
+
+        assert!(open("battery staple", &container).is_err());
+    }
+
+    #[test]
+    fn test_storage_rejects_tampering() {
+        let key = StorageKey::derive("correct horse", TEST_PARAMS).unwrap();
+        let container = key.seal(b"secret");
+
+        let mut params = container.clone();
+        params[10] += 1;
+        assert!(open("correct horse", &params).is_err());
+        let mut body = container.clone();
+        *body.last_mut().unwrap() ^= 1;
+        assert!(open("correct horse", &body).is_err());
+        let mut expensive = container.clone();
+        expensive[6..10].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(open("correct horse", &expensive).is_err());
+        assert!(open("correct horse", &container[..40]).is_err());
+    }
+}
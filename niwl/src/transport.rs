@@ -0,0 +1,410 @@
+//! The transports messages are posted and detected over.
+//!
+//! A `Transport` posts tagged messages to a bulletin board and fetches the messages whose tags
+//! match a detection key. `HttpTransport` talks to a niwl server, and `BulletinBoard` keeps
+//! messages in memory with the same detection semantics as the server, so that clients and
+//! mixes can be tested or simulated without one.
+use crate::encrypt::TaggedCiphertext;
+use crate::{DetectedTags, FetchMessagesRequest, NiwlError, PostMessageRequest, MAX_PAGE_SIZE};
+use fuzzytags::Tag;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The future returned by the methods of a `Transport`
+pub type TransportFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, NiwlError>> + Send + 'a>>;
+
+/// A way of posting messages to a bulletin board and detecting the messages sent to us
+pub trait Transport: Send + Sync {
+    /// Identifies the bulletin board, so that we can keep track of how far we have synced with it
+    fn server(&self) -> &str;
+
+    /// Post a tagged message
+    fn post(&self, request: PostMessageRequest) -> TransportFuture<'_, ()>;
+
+    /// Fetch the messages whose tags match the detection key of the request, posted after the
+    /// sequence number (or reference tag) of the request, checking at most a page of messages.
+    /// Fails with a `CursorExpiredError` if some of the messages after it are no longer kept.
+    fn fetch(&self, request: FetchMessagesRequest) -> TransportFuture<'_, DetectedTags>;
+}
+
+// Every board gets its own name, so that profiles keep a separate cursor for each
+static BOARDS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Default)]
+struct Messages {
+    // Messages with the sequence number they were given, in the order they were posted
+    messages: Vec<(u64, Tag<24>, TaggedCiphertext)>,
+    // The last sequence number given out. Like the ids of the server, these are never reused.
+    last_sequence: u64,
+}
+
+/// A bulletin board kept in memory. Clones share the same messages, so a clone can be given to
+/// every client and mix in a test.
+#[derive(Clone)]
+pub struct BulletinBoard {
+    name: String,
+    messages: Arc<Mutex<Messages>>,
+    page_size: u32,
+}
+
+impl Default for BulletinBoard {
+    fn default() -> BulletinBoard {
+        BulletinBoard {
+            name: format!("memory:{}", BOARDS.fetch_add(1, Ordering::Relaxed)),
+            messages: Default::default(),
+            page_size: MAX_PAGE_SIZE,
+        }
+    }
+}
+
+impl BulletinBoard {
+    pub fn new() -> BulletinBoard {
+        Default::default()
+    }
+
+    /// A board that checks at most `page_size` messages for each fetch, rather than
+    /// `MAX_PAGE_SIZE`
+    pub fn with_page_size(page_size: u32) -> BulletinBoard {
+        BulletinBoard {
+            page_size,
+            ..Default::default()
+        }
+    }
+
+    /// How many messages are kept
+    pub fn len(&self) -> usize {
+        self.messages.lock().unwrap().messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Discard the oldest messages, as a server does once it has kept them long enough
+    pub fn prune(&self, count: usize) {
+        let mut messages = self.messages.lock().unwrap();
+        let count = count.min(messages.messages.len());
+        messages.messages.drain(..count);
+    }
+
+    fn store(&self, request: PostMessageRequest) -> Result<(), NiwlError> {
+        // As with the server, packets that are not a fixed size would leak their contents
+        if !request.ciphertext.is_fixed_size() {
+            return Err(NiwlError::HttpStatusError(
+                400,
+                String::from("ciphertext is not a fixed size packet"),
+            ));
+        }
+        let mut messages = self.messages.lock().unwrap();
+        messages.last_sequence += 1;
+        let sequence = messages.last_sequence;
+        messages
+            .messages
+            .push((sequence, request.tag, request.ciphertext));
+        Ok(())
+    }
+
+    fn detect(&self, request: &FetchMessagesRequest) -> Result<DetectedTags, NiwlError> {
+        let Messages {
+            messages,
+            last_sequence,
+        } = &*self.messages.lock().unwrap();
+        // Like the server, start after the first message with the reference tag
+        let reference = request.reference_tag.as_ref().and_then(|reference| {
+            let reference = reference.compress();
+            messages
+                .iter()
+                .find(|(_, tag, _)| tag.compress() == reference)
+                .map(|(sequence, _, _)| *sequence)
+        });
+        let after = match (request.after_sequence, &request.reference_tag, reference) {
+            // Without a cursor, an unknown reference tag means checking everything
+            (None, _, reference) => reference.unwrap_or(0),
+            (Some(_), Some(_), None) => {
+                return Err(NiwlError::CursorExpiredError(String::from(
+                    "the reference tag is no longer kept",
+                )))
+            }
+            (Some(_), Some(_), Some(reference)) => reference,
+            (Some(after), None, _) => {
+                let first = messages
+                    .first()
+                    .map_or(*last_sequence + 1, |(sequence, _, _)| *sequence);
+                // A cursor of 0 asks for every message still kept
+                if after > *last_sequence || (after > 0 && first > after + 1) {
+                    return Err(NiwlError::CursorExpiredError(String::from(
+                        "messages after the cursor are no longer kept",
+                    )));
+                }
+                after
+            }
+        };
+        // Like the server, only requests that can resume from a cursor are paged
+        let limit = match request.after_sequence {
+            Some(_) => request
+                .limit
+                .filter(|limit| *limit != 0)
+                .map_or(self.page_size, |limit| limit.min(self.page_size)),
+            None => u32::MAX,
+        };
+        let until = request.until_sequence.unwrap_or(u64::MAX);
+        let start = messages.partition_point(|(sequence, _, _)| *sequence <= after);
+        let end = messages.partition_point(|(sequence, _, _)| *sequence <= until);
+        let checked = &messages[start..end.max(start)];
+        let more = checked.len() > limit as usize;
+        let checked = &checked[..checked.len().min(limit as usize)];
+        let mut detected_tags: Vec<(Tag<24>, TaggedCiphertext)> = checked
+            .iter()
+            .filter(|(_, tag, _)| request.detection_key.test_tag(tag))
+            .map(|(_, tag, ciphertext)| (tag.clone(), ciphertext.clone()))
+            .collect();
+        // Requests without a cursor are still sent at most a page of messages, and resume after
+        // the last one as their reference tag
+        let more = match request.after_sequence {
+            None if detected_tags.len() > self.page_size as usize => {
+                detected_tags.truncate(self.page_size as usize);
+                true
+            }
+            _ => more,
+        };
+        let cursor = request
+            .after_sequence
+            .map(|_| checked.last().map_or(after, |(sequence, _, _)| *sequence));
+        Ok(DetectedTags {
+            detected_tags,
+            cursor,
+            more,
+        })
+    }
+}
+
+impl Transport for BulletinBoard {
+    fn server(&self) -> &str {
+        &self.name
+    }
+
+    fn post(&self, request: PostMessageRequest) -> TransportFuture<'_, ()> {
+        Box::pin(async move { self.store(request) })
+    }
+
+    fn fetch(&self, request: FetchMessagesRequest) -> TransportFuture<'_, DetectedTags> {
+        Box::pin(async move { self.detect(&request) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::NiwlClient;
+    use crate::transport::{BulletinBoard, Transport};
+    use crate::{FetchMessagesRequest, NiwlError, Payload, Profile};
+    use chrono::Duration;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn test_bulletin_board() {
+        let board = BulletinBoard::new();
+        let client = NiwlClient::with_transport(board.clone());
+        let mut alice = Profile::new(String::from("alice"), 2);
+        let mut bob = Profile::new(String::from("bob"), 2);
+        let carol = Profile::new(String::from("carol"), 2);
+        alice
+            .import_tagging_key(&bob.export_keyset(false).unwrap(), None)
+            .unwrap();
+        alice
+            .import_tagging_key(&carol.export_keyset(false).unwrap(), None)
+            .unwrap();
+
+        let send = |text: &str, to: &str| {
+            let payload = Payload::Message(text.as_bytes().to_vec());
+            block_on(client.tag_and_send(&alice, String::from(to), &payload, true)).unwrap()
+        };
+        send("first", "bob");
+        send("for carol", "carol");
+        send("second", "bob");
+        assert_eq!(board.len(), 3);
+
+        // Bob only detects the messages tagged for him, and only those after the last one seen
+        let detected = block_on(client.detect_tags(&bob)).unwrap().detected_tags;
+        assert_eq!(detected.len(), 2);
+        let (_, payload) = bob.decrypt(&detected[0].1).unwrap();
+        assert!(matches!(payload, Payload::Message(m) if m == b"first".to_vec()));
+        bob.update_previously_seen_tag(&detected[0].0);
+        let detected = block_on(client.detect_tags(&bob)).unwrap().detected_tags;
+        assert_eq!(detected.len(), 1);
+        let (_, payload) = bob.decrypt(&detected[0].1).unwrap();
+        assert!(matches!(payload, Payload::Message(m) if m == b"second".to_vec()));
+
+        // Only fixed size packets are accepted
+        let mut ciphertext = alice.encrypt_to_self(b"hello").unwrap();
+        ciphertext.ciphertext.truncate(10);
+        assert!(matches!(
+            block_on(client.forward(&ciphertext)),
+            Err(NiwlError::HttpStatusError(400, _))
+        ));
+        assert_eq!(board.len(), 3);
+    }
+
+    #[test]
+    fn test_sequence_cursor() {
+        let board = BulletinBoard::new();
+        let client = NiwlClient::with_transport(board.clone());
+        let mut alice = Profile::new(String::from("alice"), 2);
+        let mut bob = Profile::new(String::from("bob"), 2);
+        alice
+            .import_tagging_key(&bob.export_keyset(false).unwrap(), None)
+            .unwrap();
+        let send = |text: &str| {
+            let payload = Payload::Message(text.as_bytes().to_vec());
+            block_on(client.tag_and_send(&alice, String::from("bob"), &payload, false)).unwrap()
+        };
+
+        // Syncing records how far we got, even when none of the messages were for us
+        block_on(client.forward(&alice.encrypt_to_self(b"noise").unwrap())).unwrap();
+        send("first");
+        let detected = block_on(client.detect_tags(&bob)).unwrap();
+        assert_eq!(detected.detected_tags.len(), 1);
+        assert_eq!(detected.cursor, Some(2));
+        bob.update_cursor(client.server(), &detected);
+        assert_eq!(bob.cursor(client.server()), Some(2));
+        let detected = block_on(client.detect_tags(&bob)).unwrap();
+        assert!(detected.detected_tags.is_empty());
+        assert_eq!(detected.cursor, Some(2));
+
+        // Pruning messages we have already synced does not matter
+        send("second");
+        board.prune(2);
+        let detected = block_on(client.detect_tags(&bob)).unwrap();
+        assert_eq!(detected.detected_tags.len(), 1);
+        bob.update_cursor(client.server(), &detected);
+
+        // Pruning messages we have not synced expires our cursor instead of silently skipping them
+        send("third");
+        send("fourth");
+        board.prune(2);
+        assert!(matches!(
+            block_on(client.detect_tags(&bob)),
+            Err(NiwlError::CursorExpiredError(_))
+        ));
+        bob.reset_cursor(client.server());
+        let detected = block_on(client.detect_tags(&bob)).unwrap();
+        assert_eq!(detected.detected_tags.len(), 1);
+        let (_, payload) = bob.decrypt(&detected.detected_tags[0].1).unwrap();
+        assert!(matches!(payload, Payload::Message(m) if m == b"fourth".to_vec()));
+        assert_eq!(detected.cursor, Some(5));
+
+        // Cursors are kept per server, and a cursor from the future has expired too
+        let other = NiwlClient::with_transport(BulletinBoard::new());
+        bob.update_cursor(client.server(), &detected);
+        assert_eq!(bob.cursor(other.server()), None);
+        let mut confused = Profile::new(String::from("confused"), 2);
+        confused.update_cursor(other.server(), &detected);
+        assert!(matches!(
+            block_on(other.detect_tags(&confused)),
+            Err(NiwlError::CursorExpiredError(_))
+        ));
+    }
+
+    #[test]
+    fn test_requests_without_a_cursor_are_capped() {
+        let board = BulletinBoard::with_page_size(2);
+        let client = NiwlClient::with_transport(board.clone());
+        let mut alice = Profile::new(String::from("alice"), 2);
+        let bob = Profile::new(String::from("bob"), 2);
+        alice
+            .import_tagging_key(&bob.export_keyset(false).unwrap(), None)
+            .unwrap();
+        for text in ["one", "two", "three", "four", "five"].iter() {
+            let payload = Payload::Message(text.as_bytes().to_vec());
+            block_on(client.tag_and_send(&alice, String::from("bob"), &payload, false)).unwrap();
+        }
+
+        // Older clients resume after the last message they received
+        let mut reference_tag = None;
+        let mut received = vec![];
+        let mut pages = vec![];
+        loop {
+            let request = FetchMessagesRequest {
+                reference_tag: reference_tag.clone(),
+                detection_key: bob.root_secret.extract_detection_key(24),
+                after_sequence: None,
+                limit: None,
+                until_sequence: None,
+            };
+            let detected = block_on(board.fetch(request)).unwrap();
+            for (tag, ciphertext) in detected.detected_tags.iter() {
+                if let Ok((_, Payload::Message(message))) = bob.decrypt(ciphertext) {
+                    received.push(String::from_utf8(message).unwrap());
+                }
+                reference_tag = Some(tag.clone());
+            }
+            pages.push((detected.detected_tags.len(), detected.more));
+            if !detected.more {
+                break;
+            }
+        }
+        assert_eq!(received, vec!["one", "two", "three", "four", "five"]);
+        assert_eq!(pages, vec![(2, true), (2, true), (1, false)]);
+    }
+
+    #[test]
+    fn test_paging() {
+        let board = BulletinBoard::with_page_size(2);
+        let client = NiwlClient::with_transport(board.clone());
+        let mut alice = Profile::new(String::from("alice"), 2);
+        let mut bob = Profile::new(String::from("bob"), 2);
+        // The new keys would replace the old ones if alice held them already
+        let old_keys = bob.export_keyset(false).unwrap();
+        bob.rotate_keys(Duration::days(7)).unwrap();
+        alice
+            .import_tagging_key(
+                &bob.export_keyset(false).unwrap(),
+                Some(&String::from("new bob")),
+            )
+            .unwrap();
+        alice
+            .import_tagging_key(&old_keys, Some(&String::from("old bob")))
+            .unwrap();
+
+        let send = |text: &str, to: &str| {
+            let payload = Payload::Message(text.as_bytes().to_vec());
+            block_on(client.tag_and_send(&alice, String::from(to), &payload, false)).unwrap()
+        };
+        send("one", "old bob");
+        send("two", "new bob");
+        block_on(client.forward(&alice.encrypt_to_self(b"noise").unwrap())).unwrap();
+        send("three", "old bob");
+        send("four", "new bob");
+
+        // Every page checks the same messages for the retired and current keys, so each message
+        // is received exactly once
+        let mut received = vec![];
+        let mut pages = vec![];
+        loop {
+            let detected = block_on(client.detect_tags(&bob)).unwrap();
+            for (_, ciphertext) in detected.detected_tags.iter() {
+                if let Ok((_, Payload::Message(message))) = bob.decrypt(ciphertext) {
+                    received.push(String::from_utf8(message).unwrap());
+                }
+            }
+            pages.push((detected.cursor, detected.more));
+            bob.update_cursor(client.server(), &detected);
+            if !detected.more {
+                break;
+            }
+        }
+        received.sort();
+        assert_eq!(received, vec!["four", "one", "three", "two"]);
+        assert_eq!(
+            pages,
+            vec![(Some(2), true), (Some(4), true), (Some(5), false)]
+        );
+    }
+}
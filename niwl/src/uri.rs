@@ -0,0 +1,320 @@
+//! A versioned text format for sharing keys.
+//!
+//! Keys are shared as `niwl:<version>:<key>:<checksum>`, optionally followed by `?name=<name>`,
+//! `&mix` for keys belonging to a mix and `&previous=<proof>` for keys that replace rotated
+//! ones. The key is the base32 encoding of a signed keyset,
+//! and the checksum is a hash over everything else in the URI, so a mistyped key is reported
+//! as such rather than failing to decode. Bare base32 keysets, as exported before this format,
+//! are still accepted - including those exported before keysets were signed, which carry no
+//! identity key.
+use crate::encrypt::{PublicKey, Signature};
+use crate::{NiwlError, SignedKeySet};
+use fuzzytags::TaggingKey;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+/// The scheme every niwl key URI starts with
+pub const URI_SCHEME: &str = "niwl:";
+
+/// The current version of the key URI format
+pub const URI_VERSION: u8 = 1;
+
+const CHECKSUM_SIZE: usize = 4;
+
+/// A keyset as exported before keysets were signed. It has no identity key, so a contact
+/// imported from it can neither be verified nor authenticate the messages they send.
+#[derive(Serialize, Deserialize)]
+pub struct UnsignedKeySet {
+    pub(crate) profile_name: String,
+    pub(crate) tagging_key: TaggingKey<24>,
+    pub(crate) public_key: PublicKey,
+}
+
+/// The keyset shared in a key URI
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum SharedKeySet {
+    Signed(SignedKeySet),
+    Unsigned(UnsignedKeySet),
+}
+
+impl SharedKeySet {
+    /// The name the owner of the keys gave when exporting them
+    pub fn profile_name(&self) -> &String {
+        match self {
+            SharedKeySet::Signed(signed) => &signed.keyset.profile_name,
+            SharedKeySet::Unsigned(unsigned) => &unsigned.profile_name,
+        }
+    }
+}
+
+/// A keyset along with the options shared with it
+pub struct KeySetUri {
+    pub keyset: SharedKeySet,
+    /// The name of the owner of the keys, which must match the name in the signed keyset
+    pub name: Option<String>,
+    /// Whether the keys belong to a mix
+    pub mix: bool,
+    /// A signature by the identity key these keys were rotated from, handing over to them
+    pub previous: Option<Signature>,
+}
+
+fn invalid_key(reason: String) -> NiwlError {
+    NiwlError::InvalidKeyError(reason)
+}
+
+fn checksum(unchecked: &str) -> String {
+    let mut hash = Sha3_256::new();
+    hash.update(b"niwl-uri-checksum");
+    hash.update(unchecked.as_bytes());
+    hex::encode(&hash.finalize()[0..CHECKSUM_SIZE])
+}
+
+// Names are percent-encoded, leaving only unreserved characters as they are
+fn encode_name(name: &str) -> String {
+    name.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn decode_name(encoded: &str) -> Result<String, NiwlError> {
+    let mut bytes = vec![];
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match byte {
+            b'%' if tail.len() >= 2 => {
+                let hex = std::str::from_utf8(&tail[0..2]).unwrap_or("");
+                bytes
+                    .push(u8::from_str_radix(hex, 16).map_err(|_| {
+                        invalid_key(format!("invalid escape %{} in the name", hex))
+                    })?);
+                rest = &tail[2..];
+            }
+            b'%' => return Err(invalid_key(String::from("truncated escape in the name"))),
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid_key(String::from("the name is not valid utf-8")))
+}
+
+fn decode_proof(encoded: &str) -> Result<Signature, NiwlError> {
+    hex::decode(encoded)
+        .ok()
+        .and_then(|proof| bincode::deserialize(&proof).ok())
+        .ok_or(invalid_key(String::from(
+            "the proof of the previous key could not be decoded",
+        )))
+}
+
+fn decode_keyset(encoded: &str) -> Result<SharedKeySet, NiwlError> {
+    let data = base32::decode(
+        base32::Alphabet::RFC4648 { padding: false },
+        &encoded.to_ascii_uppercase(),
+    )
+    .ok_or(invalid_key(String::from("the key is not valid base32")))?;
+    if let Ok(keyset) = bincode::deserialize(&data) {
+        return Ok(SharedKeySet::Signed(keyset));
+    }
+    match bincode::deserialize(&data) {
+        Ok(unsigned) => Ok(SharedKeySet::Unsigned(unsigned)),
+        Err(_) => Err(invalid_key(String::from(
+            "the key could not be decoded, it may be incomplete",
+        ))),
+    }
+}
+
+impl KeySetUri {
+    /// Parse a key URI, or a bare base32 keyset
+    pub fn parse(text: &str) -> Result<KeySetUri, NiwlError> {
+        let text = text.trim();
+        if text.len() < URI_SCHEME.len()
+            || !text[..URI_SCHEME.len()].eq_ignore_ascii_case(URI_SCHEME)
+        {
+            return Ok(KeySetUri {
+                keyset: decode_keyset(text)?,
+                name: None,
+                mix: false,
+                previous: None,
+            });
+        }
+
+        let (path, query) = match text.find('?') {
+            Some(index) => (&text[..index], Some(&text[index + 1..])),
+            None => (text, None),
+        };
+        let parts: Vec<&str> = path[URI_SCHEME.len()..].split(':').collect();
+        if parts.len() != 3 {
+            return Err(invalid_key(String::from(
+                "expected a key of the form niwl:<version>:<key>:<checksum>",
+            )));
+        }
+        let version: u8 = parts[0]
+            .parse()
+            .map_err(|_| invalid_key(format!("invalid version {}", parts[0])))?;
+        if version != URI_VERSION {
+            return Err(invalid_key(format!(
+                "unsupported key version {}, this version of niwl reads version {}",
+                version, URI_VERSION
+            )));
+        }
+        let unchecked = match query {
+            Some(query) => format!("{}{}:{}?{}", URI_SCHEME, parts[0], parts[1], query),
+            None => format!("{}{}:{}", URI_SCHEME, parts[0], parts[1]),
+        };
+        if !parts[2].eq_ignore_ascii_case(&checksum(&unchecked)) {
+            return Err(invalid_key(String::from(
+                "the checksum does not match, the key was probably copied incorrectly",
+            )));
+        }
+
+        let mut uri = KeySetUri {
+            keyset: decode_keyset(parts[1])?,
+            name: None,
+            mix: false,
+            previous: None,
+        };
+        for option in query.unwrap_or("").split('&').filter(|o| !o.is_empty()) {
+            match option.find('=') {
+                Some(index) if &option[..index] == "name" => {
+                    uri.name = Some(decode_name(&option[index + 1..])?)
+                }
+                Some(index) if &option[..index] == "previous" => {
+                    uri.previous = Some(decode_proof(&option[index + 1..])?)
+                }
+                None if option == "mix" => uri.mix = true,
+                _ => return Err(invalid_key(format!("unknown option {}", option))),
+            }
+        }
+        if let Some(name) = &uri.name {
+            if name != uri.keyset.profile_name() {
+                return Err(invalid_key(format!(
+                    "the key is named {} but was exported as {}",
+                    name,
+                    uri.keyset.profile_name()
+                )));
+            }
+        }
+        Ok(uri)
+    }
+
+    /// Encode the keyset and its options as a key URI
+    pub fn encode(&self) -> Result<String, NiwlError> {
+        let keyset = base32::encode(
+            base32::Alphabet::RFC4648 { padding: false },
+            &bincode::serialize(&self.keyset)?,
+        )
+        .to_ascii_lowercase();
+        let mut options = vec![];
+        if let Some(name) = &self.name {
+            options.push(format!("name={}", encode_name(name)));
+        }
+        if self.mix {
+            options.push(String::from("mix"));
+        }
+        if let Some(previous) = &self.previous {
+            options.push(format!(
+                "previous={}",
+                hex::encode(bincode::serialize(previous)?)
+            ));
+        }
+        let unchecked = match options.is_empty() {
+            true => format!("{}{}:{}", URI_SCHEME, URI_VERSION, keyset),
+            false => format!(
+                "{}{}:{}?{}",
+                URI_SCHEME,
+                URI_VERSION,
+                keyset,
+                options.join("&")
+            ),
+        };
+        let checksum = checksum(&unchecked);
+        Ok(match unchecked.find('?') {
+            Some(index) => format!(
+                "{}:{}{}",
+                &unchecked[..index],
+                checksum,
+                &unchecked[index..]
+            ),
+            None => format!("{}:{}", unchecked, checksum),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::uri::{decode_name, encode_name, KeySetUri, SharedKeySet, UnsignedKeySet};
+    use crate::Profile;
+
+    fn is_signed(keyset: SharedKeySet) -> bool {
+        match keyset {
+            SharedKeySet::Signed(signed) => signed.verify().is_ok(),
+            SharedKeySet::Unsigned(_) => false,
+        }
+    }
+
+    #[test]
+    fn test_uri_round_trip() {
+        let profile = Profile::new(String::from("zoë's mix"), 0);
+        let uri = profile.export_keyset(true).unwrap();
+        assert!(uri.starts_with("niwl:1:"));
+        assert!(uri.ends_with("?name=zo%C3%AB%27s%20mix&mix"));
+
+        let parsed = KeySetUri::parse(&format!("  {}\n", uri)).unwrap();
+        assert_eq!(parsed.name, Some(String::from("zoë's mix")));
+        assert!(parsed.mix);
+        assert!(is_signed(parsed.keyset));
+        assert_eq!(decode_name(&encode_name("a&b=c")).unwrap(), "a&b=c");
+    }
+
+    #[test]
+    fn test_uri_errors() {
+        let profile = Profile::new(String::from("alice"), 2);
+        let uri = profile.export_keyset(false).unwrap();
+        let error = |text: &str| match KeySetUri::parse(text) {
+            Err(crate::NiwlError::InvalidKeyError(reason)) => reason,
+            _ => panic!("{} should not parse", text),
+        };
+
+        // A single mistyped character is caught by the checksum
+        let typo: String = uri
+            .char_indices()
+            .map(|(i, c)| match (i, c) {
+                (20, 'a') => 'b',
+                (20, _) => 'a',
+                (_, c) => c,
+            })
+            .collect();
+        assert!(error(&typo).contains("checksum"));
+        assert!(error(&uri.replacen("niwl:1:", "niwl:2:", 1)).contains("version 2"));
+        assert!(error("niwl:1:abc").contains("niwl:<version>:<key>:<checksum>"));
+        assert!(error(&format!("{}?name=bob", uri)).contains("checksum"));
+        assert!(error("not a key!").contains("base32"));
+
+        // Bare base32 signed keysets are still accepted
+        let bare = uri.split(':').nth(2).unwrap();
+        assert!(is_signed(KeySetUri::parse(bare).unwrap().keyset));
+
+        // ...as are keysets from before keys were signed, which have no identity key
+        let keyset = profile.keyset().unwrap();
+        let unsigned = base32::encode(
+            base32::Alphabet::RFC4648 { padding: false },
+            &bincode::serialize(&UnsignedKeySet {
+                profile_name: keyset.profile_name,
+                tagging_key: keyset.tagging_key,
+                public_key: keyset.public_key,
+            })
+            .unwrap(),
+        );
+        let parsed = KeySetUri::parse(&unsigned).unwrap();
+        assert!(matches!(&parsed.keyset, SharedKeySet::Unsigned(_)));
+        assert_eq!(parsed.keyset.profile_name(), "alice");
+    }
+}
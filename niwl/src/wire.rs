@@ -0,0 +1,340 @@
+//! A compact binary encoding for the packets and requests exchanged with a niwl server.
+//!
+//! JSON encodes Ristretto points and byte arrays as lists of numbers, inflating every packet
+//! several times over. The wire encoding instead writes each message as a version byte, a byte
+//! identifying the type of the message, and then the fields of the message: fixed size fields
+//! (tags) as-is, and variable length fields prefixed with their length as a little-endian u32.
+//!
+//! Servers accept both encodings, distinguished by the content type of the request.
+//!
+//! Fields added after the first version (the sequence cursor and page of a fetch, and the cursor
+//! of its response) are optional and written at the end of a message, so that messages without
+//! them keep their original encoding.
+use crate::encrypt::{TaggedCiphertext, TAG_SIZE};
+use crate::{DetectedTags, FetchMessagesRequest, PostMessageRequest};
+use fuzzytags::Tag;
+use std::convert::TryInto;
+
+/// The content type of a request or response in the wire encoding
+pub const CONTENT_TYPE: &str = "application/x-niwl";
+
+/// The current version of the wire encoding
+pub const WIRE_VERSION: u8 = 1;
+
+const TAGGED_CIPHERTEXT: u8 = 1;
+const POST_MESSAGE_REQUEST: u8 = 2;
+const FETCH_MESSAGES_REQUEST: u8 = 3;
+const DETECTED_TAGS: u8 = 4;
+
+/// Types that can be sent in the wire encoding
+pub trait Wire: Sized {
+    fn to_wire(&self) -> Vec<u8>;
+    fn from_wire(bytes: &[u8]) -> Option<Self>;
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn new(message_type: u8) -> Writer {
+        Writer {
+            bytes: vec![WIRE_VERSION, message_type],
+        }
+    }
+
+    fn tag(&mut self, tag: &Tag<24>) {
+        self.bytes.extend_from_slice(&tag.compress());
+    }
+
+    fn data(&mut self, data: &[u8]) {
+        self.bytes
+            .extend_from_slice(&(data.len() as u32).to_le_bytes());
+        self.bytes.extend_from_slice(data);
+    }
+
+    fn ciphertext(&mut self, ciphertext: &TaggedCiphertext) {
+        self.data(&ciphertext.to_bytes());
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8], message_type: u8) -> Option<Reader<'a>> {
+        let mut reader = Reader { bytes };
+        match reader.take(2)? {
+            [WIRE_VERSION, t] if *t == message_type => Some(reader),
+            _ => None,
+        }
+    }
+
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < length {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn tag(&mut self) -> Option<Tag<24>> {
+        Tag::<24>::decompress(self.take(TAG_SIZE)?)
+    }
+
+    fn data(&mut self) -> Option<&'a [u8]> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+
+    fn ciphertext(&mut self) -> Option<TaggedCiphertext> {
+        TaggedCiphertext::from_bytes(self.data()?)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    // Whether the optional fields at the end of a message are absent
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    // Every message must be consumed entirely
+    fn finish<T>(self, value: T) -> Option<T> {
+        match self.bytes.is_empty() {
+            true => Some(value),
+            false => None,
+        }
+    }
+}
+
+impl Wire for TaggedCiphertext {
+    fn to_wire(&self) -> Vec<u8> {
+        let mut writer = Writer::new(TAGGED_CIPHERTEXT);
+        writer.ciphertext(self);
+        writer.bytes
+    }
+
+    fn from_wire(bytes: &[u8]) -> Option<TaggedCiphertext> {
+        let mut reader = Reader::new(bytes, TAGGED_CIPHERTEXT)?;
+        let ciphertext = reader.ciphertext()?;
+        reader.finish(ciphertext)
+    }
+}
+
+impl Wire for PostMessageRequest {
+    fn to_wire(&self) -> Vec<u8> {
+        let mut writer = Writer::new(POST_MESSAGE_REQUEST);
+        writer.tag(&self.tag);
+        writer.ciphertext(&self.ciphertext);
+        writer.bytes
+    }
+
+    fn from_wire(bytes: &[u8]) -> Option<PostMessageRequest> {
+        let mut reader = Reader::new(bytes, POST_MESSAGE_REQUEST)?;
+        let tag = reader.tag()?;
+        let ciphertext = reader.ciphertext()?;
+        reader.finish(PostMessageRequest { tag, ciphertext })
+    }
+}
+
+impl Wire for FetchMessagesRequest {
+    fn to_wire(&self) -> Vec<u8> {
+        let mut writer = Writer::new(FETCH_MESSAGES_REQUEST);
+        match &self.reference_tag {
+            Some(tag) => {
+                writer.bytes.push(1);
+                writer.tag(tag);
+            }
+            None => writer.bytes.push(0),
+        }
+        writer.data(&bincode::serialize(&self.detection_key).unwrap());
+        // A limit or bound of 0 is sent for none
+        if let Some(after_sequence) = self.after_sequence {
+            writer.u64(after_sequence);
+            writer.u32(self.limit.unwrap_or(0));
+            writer.u64(self.until_sequence.unwrap_or(0));
+        }
+        writer.bytes
+    }
+
+    fn from_wire(bytes: &[u8]) -> Option<FetchMessagesRequest> {
+        let mut reader = Reader::new(bytes, FETCH_MESSAGES_REQUEST)?;
+        let reference_tag = match reader.u8()? {
+            0 => None,
+            1 => Some(reader.tag()?),
+            _ => return None,
+        };
+        let detection_key = bincode::deserialize(reader.data()?).ok()?;
+        let (after_sequence, limit, until_sequence) = match reader.is_empty() {
+            true => (None, None, None),
+            false => (
+                Some(reader.u64()?),
+                Some(reader.u32()?).filter(|limit| *limit != 0),
+                Some(reader.u64()?).filter(|until| *until != 0),
+            ),
+        };
+        reader.finish(FetchMessagesRequest {
+            reference_tag,
+            detection_key,
+            after_sequence,
+            limit,
+            until_sequence,
+        })
+    }
+}
+
+impl Wire for DetectedTags {
+    fn to_wire(&self) -> Vec<u8> {
+        let mut writer = Writer::new(DETECTED_TAGS);
+        writer
+            .bytes
+            .extend_from_slice(&(self.detected_tags.len() as u32).to_le_bytes());
+        for (tag, ciphertext) in self.detected_tags.iter() {
+            writer.tag(tag);
+            writer.ciphertext(ciphertext);
+        }
+        if let Some(cursor) = self.cursor {
+            writer.u64(cursor);
+            writer.u8(self.more as u8);
+        }
+        writer.bytes
+    }
+
+    fn from_wire(bytes: &[u8]) -> Option<DetectedTags> {
+        let mut reader = Reader::new(bytes, DETECTED_TAGS)?;
+        let count = reader.u32()? as usize;
+        let mut detected_tags = vec![];
+        for _ in 0..count {
+            let tag = reader.tag()?;
+            let ciphertext = reader.ciphertext()?;
+            detected_tags.push((tag, ciphertext));
+        }
+        let (cursor, more) = match reader.is_empty() {
+            true => (None, false),
+            false => (Some(reader.u64()?), reader.u8()? != 0),
+        };
+        reader.finish(DetectedTags {
+            detected_tags,
+            cursor,
+            more,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encrypt::{PrivateKey, TaggedCiphertext};
+    use crate::wire::Wire;
+    use crate::{DetectedTags, FetchMessagesRequest, PostMessageRequest};
+    use fuzzytags::RootSecret;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_wire_round_trip() {
+        let secret = RootSecret::<24>::generate(&mut OsRng);
+        let tag = secret.tagging_key().generate_tag(&mut OsRng);
+        let key = PrivateKey::generate();
+        let ciphertext = key.public_key().encrypt_bytes(&tag, b"hello").unwrap();
+
+        let bytes = ciphertext.to_wire();
+        assert!(bytes.len() < serde_json::to_vec(&ciphertext).unwrap().len() / 2);
+        let decoded = TaggedCiphertext::from_wire(&bytes).unwrap();
+        assert_eq!(key.decrypt_bytes(&decoded).unwrap(), b"hello".to_vec());
+
+        let post = PostMessageRequest {
+            tag: tag.clone(),
+            ciphertext: ciphertext.clone(),
+        };
+        let decoded = PostMessageRequest::from_wire(&post.to_wire()).unwrap();
+        assert!(decoded.tag == tag);
+        assert_eq!(decoded.ciphertext.to_bytes(), ciphertext.to_bytes());
+
+        let fetch = FetchMessagesRequest {
+            reference_tag: Some(tag.clone()),
+            detection_key: secret.extract_detection_key(2),
+            after_sequence: None,
+            limit: None,
+            until_sequence: None,
+        };
+        let bytes = fetch.to_wire();
+        let decoded = FetchMessagesRequest::from_wire(&bytes).unwrap();
+        assert!(decoded.reference_tag == Some(tag.clone()));
+        assert!(decoded.detection_key.test_tag(&tag));
+        assert_eq!(decoded.after_sequence, None);
+
+        // The sequence cursor and page are appended to the original encoding
+        let fetch = FetchMessagesRequest {
+            after_sequence: Some(42),
+            limit: Some(100),
+            ..fetch
+        };
+        let with_cursor = fetch.to_wire();
+        assert_eq!(with_cursor[..bytes.len()], bytes[..]);
+        let decoded = FetchMessagesRequest::from_wire(&with_cursor).unwrap();
+        assert_eq!(decoded.after_sequence, Some(42));
+        assert_eq!(decoded.limit, Some(100));
+        assert_eq!(decoded.until_sequence, None);
+        assert!(FetchMessagesRequest::from_wire(&with_cursor[..with_cursor.len() - 1]).is_none());
+
+        let detected = DetectedTags {
+            detected_tags: vec![(tag.clone(), ciphertext.clone()); 3],
+            cursor: Some(7),
+            more: true,
+        };
+        let decoded = DetectedTags::from_wire(&detected.to_wire()).unwrap();
+        assert_eq!(decoded.detected_tags.len(), 3);
+        assert_eq!(decoded.cursor, Some(7));
+        assert!(decoded.more);
+        assert_eq!(
+            key.decrypt_bytes(&decoded.detected_tags[2].1).unwrap(),
+            b"hello".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_wire_rejects_malformed() {
+        let tag = RootSecret::<24>::generate(&mut OsRng)
+            .tagging_key()
+            .generate_tag(&mut OsRng);
+        let post = PostMessageRequest {
+            ciphertext: PrivateKey::generate()
+                .public_key()
+                .encrypt_bytes(&tag, b"hello")
+                .unwrap(),
+            tag,
+        };
+        let bytes = post.to_wire();
+        assert!(PostMessageRequest::from_wire(&bytes[..bytes.len() - 1]).is_none());
+        assert!(TaggedCiphertext::from_wire(&bytes).is_none());
+        let mut extended = bytes.clone();
+        extended.push(0);
+        assert!(PostMessageRequest::from_wire(&extended).is_none());
+        let mut future = bytes.clone();
+        future[0] += 1;
+        assert!(PostMessageRequest::from_wire(&future).is_none());
+    }
+}